@@ -1,12 +1,32 @@
-use crate::program::{Object, ProgramState, VarFieldId, VariableAccessError};
+use regex::Regex;
 
-use super::{expr::StringExpr, process::ProcessInfo};
+use crate::program::{Object, ProgramState, VarFieldId, VarNames, VariableAccessError};
+
+use super::{
+    expr::{Condition, StringExpr},
+    process::ProcessInfo,
+};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum OutputMap<T> {
     Print,
     Create(T),
     Append(T),
+    /// Writes to the file while still showing the output on the progress bar
+    Tee(T),
+    /// Writes to the file until it would grow past `max_bytes`, then renames it to `base.1.ext`
+    /// and starts a new one. Older files move up by one, only `keep` of them are kept if set.
+    Rotate {
+        path: T,
+        max_bytes: u64,
+        keep: Option<usize>,
+    },
+    /// Writes to the file until it holds `max_bytes`, the rest of the output is read and
+    /// discarded so the process doesn't block on a full pipe
+    Limited {
+        path: T,
+        max_bytes: u64,
+    },
 }
 
 impl<T> OutputMap<T> {
@@ -16,6 +36,20 @@ impl<T> OutputMap<T> {
             OutputMap::Print => OutputMap::Print,
             OutputMap::Create(value) => OutputMap::Create(f(value)),
             OutputMap::Append(value) => OutputMap::Append(f(value)),
+            OutputMap::Tee(value) => OutputMap::Tee(f(value)),
+            OutputMap::Rotate {
+                path,
+                max_bytes,
+                keep,
+            } => OutputMap::Rotate {
+                path: f(path),
+                max_bytes,
+                keep,
+            },
+            OutputMap::Limited { path, max_bytes } => OutputMap::Limited {
+                path: f(path),
+                max_bytes,
+            },
         }
     }
 
@@ -25,6 +59,20 @@ impl<T> OutputMap<T> {
             OutputMap::Print => OutputMap::Print,
             OutputMap::Create(value) => OutputMap::Create(f(value)),
             OutputMap::Append(value) => OutputMap::Append(f(value)),
+            OutputMap::Tee(value) => OutputMap::Tee(f(value)),
+            OutputMap::Rotate {
+                path,
+                max_bytes,
+                keep,
+            } => OutputMap::Rotate {
+                path: f(path),
+                max_bytes: *max_bytes,
+                keep: *keep,
+            },
+            OutputMap::Limited { path, max_bytes } => OutputMap::Limited {
+                path: f(path),
+                max_bytes: *max_bytes,
+            },
         }
     }
 
@@ -36,6 +84,36 @@ impl<T> OutputMap<T> {
             OutputMap::Print => Ok(OutputMap::Print),
             OutputMap::Create(value) => Ok(OutputMap::Create(f(value)?)),
             OutputMap::Append(value) => Ok(OutputMap::Append(f(value)?)),
+            OutputMap::Tee(value) => Ok(OutputMap::Tee(f(value)?)),
+            OutputMap::Rotate {
+                path,
+                max_bytes,
+                keep,
+            } => Ok(OutputMap::Rotate {
+                path: f(path)?,
+                max_bytes: *max_bytes,
+                keep: *keep,
+            }),
+            OutputMap::Limited { path, max_bytes } => Ok(OutputMap::Limited {
+                path: f(path)?,
+                max_bytes: *max_bytes,
+            }),
+        }
+    }
+
+    /// Whether the output is shown on the progress bar
+    pub fn shows_on_bar(&self) -> bool {
+        matches!(self, OutputMap::Print | OutputMap::Tee(_))
+    }
+
+    /// The file that is truncated when the output is opened, if any
+    pub fn truncated(&self) -> Option<&T> {
+        match self {
+            OutputMap::Create(value)
+            | OutputMap::Tee(value)
+            | OutputMap::Rotate { path: value, .. }
+            | OutputMap::Limited { path: value, .. } => Some(value),
+            OutputMap::Print | OutputMap::Append(_) => None,
         }
     }
 }
@@ -44,18 +122,71 @@ impl<T> OutputMap<T> {
 pub enum ArgBuilder {
     String(StringExpr),
     Set(VarFieldId),
+    /// Passes the whole object serialized to JSON as a single argument
+    Json(VarFieldId),
+    /// Written as `combo()`, the variables of the enclosing loops and their current values as a
+    /// single JSON object, see `TestBed::combo`
+    Combo,
+    /// Written as `@path`, each non-empty line of the file not starting with `#` is an argument
+    File(StringExpr),
+    /// Written as `...list`, each element of the list is a separate argument, nested lists
+    /// are flattened
+    Spread(VarFieldId),
 }
 
 impl ArgBuilder {
     pub fn evaluate<'a>(
         &'a self,
         state: &'a ProgramState,
+        names: &VarNames,
+        combo: Option<&str>,
     ) -> Result<ObjectIter<'a>, VariableAccessError> {
         match self {
             ArgBuilder::String(value) => Ok(ObjectIter::once(value.evaluate(state)?)),
             ArgBuilder::Set(value) => {
+                if let Some(slice) = state.get_slice(value)? {
+                    return ObjectIter::from_slice(state, slice);
+                }
+
                 let object = state.get_object(value)?;
-                Ok(ObjectIter::object_iter(state, object))
+                ObjectIter::from_object(state, object)
+            }
+            ArgBuilder::Json(value) => {
+                let object = state.get_object(value)?;
+                let json = serde_json::to_string(&object.to_serialize(state, names))
+                    .map_err(|e| VariableAccessError::SerializeError(e.to_string()))?;
+
+                Ok(ObjectIter::once(json))
+            }
+            ArgBuilder::Combo => Ok(ObjectIter::once(combo.unwrap_or("{}").to_string())),
+            ArgBuilder::File(path) => {
+                let path = path.evaluate(state)?;
+                let Ok(contents) = std::fs::read_to_string(&path) else {
+                    return Err(VariableAccessError::MissingFile(path));
+                };
+
+                let args: Vec<String> = contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from)
+                    .collect();
+
+                Ok(ObjectIter::Many(args.into_iter()))
+            }
+            ArgBuilder::Spread(value) => {
+                if let Some(slice) = state.get_slice(value)? {
+                    return ObjectIter::from_slice(state, slice);
+                }
+
+                let list = match state.get_object(value)? {
+                    Object::Ref(variable_ref) => state.evaluate_ref(*variable_ref)?,
+                    object => object,
+                };
+                match list {
+                    Object::List(_) => ObjectIter::from_object(state, list),
+                    _ => Err(VariableAccessError::NotAList),
+                }
             }
         }
     }
@@ -63,6 +194,7 @@ impl ArgBuilder {
 
 pub enum ObjectIter<'a> {
     Once(Option<String>),
+    Many(std::vec::IntoIter<String>),
     Iter { object: &'a Object, idx: usize },
 }
 
@@ -71,29 +203,75 @@ impl<'a> ObjectIter<'a> {
         Self::Once(Some(value))
     }
 
-    pub fn object_iter(state: &'a ProgramState, object: &'a Object) -> Self {
+    /// Flattens each element like a list
+    pub fn from_slice(
+        state: &'a ProgramState,
+        slice: &'a [Object],
+    ) -> Result<Self, VariableAccessError> {
+        let mut args = vec![];
+        for object in slice {
+            flatten_into(state, object, &mut args)?;
+        }
+
+        Ok(Self::Many(args.into_iter()))
+    }
+
+    /// Counters and single structs are iterated in place, lists are flattened up front
+    pub fn from_object(
+        state: &'a ProgramState,
+        object: &'a Object,
+    ) -> Result<Self, VariableAccessError> {
         let object = match object {
-            Object::Ref(variable_ref) => state.evaluate_ref(*variable_ref).unwrap(),
+            Object::Ref(variable_ref) => state.evaluate_ref(*variable_ref)?,
             object => object,
         };
 
-        Self::Iter { object, idx: 0 }
+        match object {
+            Object::List(_) => {
+                let mut args = vec![];
+                flatten_into(state, object, &mut args)?;
+                Ok(Self::Many(args.into_iter()))
+            }
+            object => Ok(Self::Iter { object, idx: 0 }),
+        }
     }
 }
 
+/// Structs give their base and counters their current value, nested lists are flattened
+fn flatten_into(
+    state: &ProgramState,
+    object: &Object,
+    args: &mut Vec<String>,
+) -> Result<(), VariableAccessError> {
+    match object {
+        Object::Struct(value) => args.push(value.base.clone()),
+        Object::Counter(counter) => args.push(counter.current_value().to_string()),
+        Object::Bool(value) => args.push(value.to_string()),
+        Object::Ref(variable_ref) => flatten_into(state, state.evaluate_ref(*variable_ref)?, args)?,
+        Object::List(values) => {
+            for value in values {
+                flatten_into(state, value, args)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 impl<'a> Iterator for ObjectIter<'a> {
     type Item = String;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self {
             ObjectIter::Once(value) => value.take(),
+            ObjectIter::Many(values) => values.next(),
             ObjectIter::Iter { object, idx } => match object {
                 Object::Counter(counter) => {
                     if *idx >= counter.len() {
                         return None;
                     }
 
-                    let value = counter.start + *idx as i64;
+                    let value = counter.value_at(*idx);
                     *idx += 1;
                     Some(format!("{value}"))
                 }
@@ -104,15 +282,14 @@ impl<'a> Iterator for ObjectIter<'a> {
                         Some(value.base.clone())
                     }
                 },
-                Object::List(vec) => {
-                    let to_return = vec.get(*idx)?;
-                    *idx += 1;
-                    match to_return {
-                        Object::Struct(value) => Some(value.base.clone()),
-                        _ => panic!("Cannot iterate over list of non-struct values"),
+                Object::Bool(value) => match *idx > 0 {
+                    true => None,
+                    false => {
+                        *idx += 1;
+                        Some(value.to_string())
                     }
-                }
-                Object::Ref(_) => unreachable!(),
+                },
+                Object::List(_) | Object::Ref(_) => unreachable!(),
             },
         }
     }
@@ -120,7 +297,18 @@ impl<'a> Iterator for ObjectIter<'a> {
 
 #[derive(Clone, Debug)]
 pub struct Spawn {
+    pub detach: bool,
+    /// Written as `clear_env`, see `ProcessInfo::clear_env`
+    pub clear_env: bool,
+    /// Written as `name(..)`, used by `terminate` to find the process
+    pub name: Option<StringExpr>,
     pub working_dir: Option<StringExpr>,
+    /// Written as `filter(..)`, see `ProcessInfo::filter`
+    pub filter: Option<StringExpr>,
+    /// Written as `progress(..)`, see `ProcessInfo::progress`
+    pub progress: Option<StringExpr>,
+    /// Written as `nice(..)`, see `ProcessInfo::nice`
+    pub nice: Option<i32>,
     pub command: StringExpr,
     pub args: Vec<ArgBuilder>,
     pub stdout: OutputMap<StringExpr>,
@@ -128,12 +316,34 @@ pub struct Spawn {
 }
 
 impl Spawn {
-    pub fn evaluate(&self, state: &ProgramState) -> Result<ProcessInfo, VariableAccessError> {
+    /// Whether any argument is `combo()`, which has to be resolved by the caller
+    pub fn uses_combo(&self) -> bool {
+        self.args.iter().any(|arg| matches!(arg, ArgBuilder::Combo))
+    }
+
+    /// `combo` is the JSON passed for `combo()` arguments
+    pub fn evaluate(
+        &self,
+        state: &ProgramState,
+        names: &VarNames,
+        combo: Option<&str>,
+    ) -> Result<ProcessInfo, VariableAccessError> {
+        // The command is the program itself and is never split on spaces
         let command = self.command.evaluate(state)?;
+        if command.trim().is_empty() {
+            return Err(VariableAccessError::EmptyCommand);
+        }
+
         let mut process = ProcessInfo::new(command);
+        process.detached = self.detach;
+        process.clear_env = self.clear_env;
+
+        if let Some(name) = &self.name {
+            process.name = Some(name.evaluate(state)?);
+        }
 
         for arg in self.args.iter() {
-            let arg = arg.evaluate(state)?;
+            let arg = arg.evaluate(state, names, combo)?;
             process.args.extend(arg);
         }
 
@@ -152,6 +362,22 @@ impl Spawn {
             process.set_working_dir(working_dir.into());
         }
 
+        if let Some(filter) = &self.filter {
+            process.filter = Some(filter.evaluate(state)?);
+        }
+        if let Some(progress) = &self.progress {
+            let progress = progress.evaluate(state)?;
+            match Regex::new(&progress) {
+                Ok(regex) => process.progress = Some(regex),
+                Err(e) => {
+                    return Err(VariableAccessError::InvalidRegex(format!(
+                        "{progress}: {e}"
+                    )))
+                }
+            }
+        }
+        process.nice = self.nice;
+
         Ok(process)
     }
 }
@@ -161,5 +387,73 @@ pub enum Command {
     LimitSpawn(usize),
     Sleep(u64),
     Spawn(Spawn),
+    /// Spawns the process and blocks until it exits, other running processes are left alone
+    Run(Spawn),
+    /// Asks every running process with the name to stop with `SIGTERM` and stops tracking
+    /// them without waiting for them to exit
+    Terminate(StringExpr),
     WaitAll(Option<u64>),
+    /// Waits until the path exists, the program fails if it doesn't within `timeout` millis
+    WaitFile {
+        path: StringExpr,
+        timeout: Option<u64>,
+    },
+    /// Copies `src` over `dst`, relative paths are resolved like the working dir of a spawn
+    Copy {
+        src: StringExpr,
+        dst: StringExpr,
+    },
+    /// Makes `dst` a link to `src`, resolving relative paths like `Copy`
+    Symlink {
+        src: StringExpr,
+        dst: StringExpr,
+    },
+    /// Fails the program with `AssertionFailed` holding the message if `cond` doesn't hold
+    Assert {
+        cond: Condition,
+        message: StringExpr,
+    },
+    /// Adds a labelled header bar, spawns until the matching `EndGroup` are shown beneath it
+    StartGroup(StringExpr),
+    EndGroup,
+    /// Creates the directory and runs spawns without a `dir(..)` in it until the matching
+    /// `PopDir`, relative output paths are written inside it too. `{index}` in the directory is
+    /// replaced with the number of times the `cwd_each` block was entered before, `block` tells
+    /// the blocks of a program apart.
+    PushDir {
+        dir: StringExpr,
+        block: usize,
+    },
+    PopDir,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::program::{Counter, Object, ProgramState, Struct};
+
+    use super::ObjectIter;
+
+    fn value(base: &str) -> Object {
+        Object::Struct(Struct::new(base.to_string(), HashMap::new()))
+    }
+
+    #[test]
+    fn mixed_list_is_flattened() {
+        let state = ProgramState::new();
+        let counter = Counter {
+            offset: 2,
+            start: 5,
+            end: 10,
+        };
+        let list = Object::List(vec![
+            value("a"),
+            Object::Counter(counter),
+            Object::List(vec![value("b"), Object::List(vec![value("c")])]),
+        ]);
+
+        let args: Vec<String> = ObjectIter::from_object(&state, &list).unwrap().collect();
+        assert_eq!(args, ["a", "7", "b", "c"]);
+    }
 }
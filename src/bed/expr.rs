@@ -1,13 +1,23 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt::Write};
+
+use chrono::{Local, SecondsFormat};
 
 use crate::program::{
-    Counter, IterTarget, Object, ProgramState, Struct, VarFieldId, VarNameId, VariableAccessError,
+    Counter, IterTarget, ListIdx, Object, ProgramState, Struct, VarFieldId, VarNameId,
+    VariableAccessError,
 };
 
 #[derive(Clone, Debug)]
 pub enum StringInstance {
     String(String),
     Variable(VarFieldId),
+    /// Written as `[var:-default]`, falls back to `default` if the variable or field is missing
+    Default(VarFieldId, String),
+    /// Environment variable of the test bed process, written as `${NAME}`
+    Env(String),
+    /// Local time at evaluation, written as `{now:%Y-%m-%d}` with a strftime format or `{now}`
+    /// for RFC 3339
+    Now(Option<String>),
 }
 
 #[derive(Clone, Debug, Default)]
@@ -24,6 +34,26 @@ impl StringExpr {
                     let object = state.get_object(var)?;
                     object.write_to_string(state, &mut output)?;
                 }
+                StringInstance::Default(var, default) => match state.get_object(var) {
+                    Ok(object) => object.write_to_string(state, &mut output)?,
+                    Err(
+                        VariableAccessError::MissingVariable(_)
+                        | VariableAccessError::MissingField(_),
+                    ) => output.push_str(default),
+                    Err(e) => return Err(e),
+                },
+                StringInstance::Env(name) => match std::env::var(name) {
+                    Ok(value) => output.push_str(&value),
+                    Err(_) => return Err(VariableAccessError::MissingEnvVariable(name.clone())),
+                },
+                StringInstance::Now(format) => {
+                    let now = Local::now();
+
+                    match format {
+                        Some(format) => write!(output, "{}", now.format(format)).unwrap(),
+                        None => output.push_str(&now.to_rfc3339_opts(SecondsFormat::Secs, false)),
+                    }
+                }
             }
         }
 
@@ -35,6 +65,8 @@ impl StringExpr {
 pub enum RangeExpr {
     Integer(i64),
     Variable(StringExpr),
+    /// Length of a list or counter, written as `len(list)`
+    Len(VarFieldId),
 }
 
 impl RangeExpr {
@@ -45,6 +77,39 @@ impl RangeExpr {
                 let expr = value.evaluate(state)?;
                 expr.parse().map_err(|_| VariableAccessError::InvalidIdx)
             }
+            RangeExpr::Len(id) => list_len(state, id),
+        }
+    }
+}
+
+/// Length of the list or counter `id` resolves to, following a `ref`
+fn list_len(state: &ProgramState, id: &VarFieldId) -> Result<i64, VariableAccessError> {
+    let mut object = state.get_object(id)?;
+
+    if let Object::Ref(variable_ref) = object {
+        object = state.evaluate_ref(*variable_ref)?;
+    }
+
+    match object {
+        Object::List(list) => Ok(list.len() as i64),
+        Object::Counter(counter) => Ok(counter.len() as i64),
+        _ => Err(VariableAccessError::NotAList),
+    }
+}
+
+/// One side of a `==` or `!=` comparison
+#[derive(Clone, Debug)]
+pub enum CompareExpr {
+    String(StringExpr),
+    /// Written as `len(list)`, compared as the length's decimal string
+    Len(VarFieldId),
+}
+
+impl CompareExpr {
+    pub fn evaluate(&self, state: &ProgramState) -> Result<String, VariableAccessError> {
+        match self {
+            CompareExpr::String(value) => value.evaluate(state),
+            CompareExpr::Len(id) => Ok(list_len(state, id)?.to_string()),
         }
     }
 }
@@ -52,7 +117,20 @@ impl RangeExpr {
 #[derive(Clone, Debug)]
 pub enum IterTargetExpr {
     Variable(VarNameId),
-    Range { start: RangeExpr, end: RangeExpr },
+    Range {
+        start: RangeExpr,
+        end: RangeExpr,
+    },
+    /// Iterables joined with `++`, materialized into a list stored in `temp` when the loop starts
+    Chain {
+        parts: Vec<IterTargetExpr>,
+        temp: VarNameId,
+    },
+    /// A list written in place, evaluated into `temp` when the loop starts like a `Chain`
+    Inline {
+        values: Vec<ObjectExpr>,
+        temp: VarNameId,
+    },
 }
 
 impl IterTargetExpr {
@@ -60,6 +138,127 @@ impl IterTargetExpr {
         match self {
             IterTargetExpr::Variable(id) => IterTarget::Variable(*id),
             IterTargetExpr::Range { .. } => IterTarget::Range,
+            IterTargetExpr::Chain { temp, .. } | IterTargetExpr::Inline { temp, .. } => {
+                IterTarget::Variable(*temp)
+            }
+        }
+    }
+
+    /// Evaluates the iterable into a single list, counters and ranges become one integer
+    /// struct per value
+    pub fn materialize(&self, state: &mut ProgramState) -> Result<Object, VariableAccessError> {
+        let mut list = vec![];
+        self.materialize_into(state, &mut list)?;
+
+        Ok(Object::List(list))
+    }
+
+    fn materialize_into(
+        &self,
+        state: &mut ProgramState,
+        list: &mut Vec<Object>,
+    ) -> Result<(), VariableAccessError> {
+        let push_range = |list: &mut Vec<Object>, start: i64, end: i64| {
+            list.extend((start..end).map(|value| Object::new(value.to_string())));
+        };
+
+        match self {
+            IterTargetExpr::Variable(id) => {
+                let Some((_, object)) = state.get_value(*id) else {
+                    return Err(VariableAccessError::MissingVariable(*id));
+                };
+
+                let object = match object {
+                    Object::Ref(variable_ref) => state.evaluate_ref(*variable_ref)?,
+                    object => object,
+                };
+
+                match object {
+                    Object::List(values) => list.extend(values.iter().cloned()),
+                    Object::Counter(counter) => push_range(list, counter.start, counter.end),
+                    _ => return Err(VariableAccessError::NotAList),
+                }
+            }
+            IterTargetExpr::Range { start, end } => {
+                push_range(list, start.evaluate(state)?, end.evaluate(state)?);
+            }
+            IterTargetExpr::Chain { parts, .. } => {
+                for part in parts {
+                    part.materialize_into(state, list)?;
+                }
+            }
+            IterTargetExpr::Inline { values, .. } => {
+                for value in values {
+                    list.push(value.evaluate(state)?);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checked by a `ConditionalJump`, the body it guards only runs if the condition holds
+#[derive(Clone, Debug)]
+pub enum Condition {
+    /// Holds if the variable is `true`. Structs hold if their base is `"false"`, the way `if`
+    /// has always checked them.
+    Variable(VarFieldId),
+    /// Written as `exists(var)`, holds if the variable, field or list element is set
+    Exists(VarFieldId),
+    /// Compares the evaluated strings, written as `when var == value`
+    Equals(CompareExpr, CompareExpr),
+    NotEquals(CompareExpr, CompareExpr),
+    /// Written as `(value in list)`, holds if the base of an element of `list` equals `value`
+    /// like a `list[value]` lookup. Fails if `list` isn't a list.
+    In {
+        value: VarFieldId,
+        list: VarFieldId,
+    },
+}
+
+impl Condition {
+    pub fn evaluate(&self, state: &ProgramState) -> Result<bool, VariableAccessError> {
+        match self {
+            Condition::Variable(id) => {
+                let mut object = state.get_object(id)?;
+
+                if let Object::Ref(variable_ref) = object {
+                    object = state.evaluate_ref(*variable_ref)?;
+                }
+
+                match object {
+                    Object::Bool(value) => Ok(*value),
+                    Object::Struct(value) => Ok(value.base == "false"),
+                    x => Err(VariableAccessError::NotAStruct(x.clone())),
+                }
+            }
+            Condition::Exists(id) => match state.get_object(id) {
+                Ok(_) => Ok(true),
+                Err(
+                    VariableAccessError::MissingVariable(_)
+                    | VariableAccessError::MissingField(_)
+                    | VariableAccessError::InvalidIdx,
+                ) => Ok(false),
+                Err(e) => Err(e),
+            },
+            Condition::In { value, list } => {
+                let mut base = String::new();
+                state.get_object(value)?.write_to_string(state, &mut base)?;
+
+                let mut object = state.get_object(list)?;
+
+                if let Object::Ref(variable_ref) = object {
+                    object = state.evaluate_ref(*variable_ref)?;
+                }
+
+                match object {
+                    Object::List(list) => Ok(ListIdx::String(&base).position(list).is_ok()),
+                    _ => Err(VariableAccessError::NotAList),
+                }
+            }
+            Condition::Equals(lhs, rhs) => Ok(lhs.evaluate(state)? == rhs.evaluate(state)?),
+            Condition::NotEquals(lhs, rhs) => Ok(lhs.evaluate(state)? != rhs.evaluate(state)?),
         }
     }
 }
@@ -73,18 +272,51 @@ pub struct StructExpr {
 #[derive(Clone, Debug)]
 pub enum ObjectExpr {
     Clone(VarFieldId),
+    /// Aliases a variable or list element rather than cloning it. Reads see later
+    /// reassignments of the target. Storing it in a scope shallower than the target's fails
+    /// with `InvalidRef`, the alias would outlive the target.
+    Ref(VarFieldId),
+    /// Clones what a ref points to instead of the ref, only built by `for keep` loops over a
+    /// list that is dropped with the loop
+    Deref(VarFieldId),
+    /// Written as a bare `true` or `false`
+    BoolLit(bool),
+    /// Written as a bare integer, evaluates to a struct with its digits as the base like any
+    /// other string. Only parsed from integers written the way `i64` prints them, others are a
+    /// `StrLit` so they keep their text.
+    IntLit(i64),
+    /// A string without properties or interpolation, quoted or not
+    StrLit(String),
+    /// Written as `exists(var)`, `(value in list)` or `(var == value)`, evaluates to a bool
+    Condition(Condition),
     List(Vec<ObjectExpr>),
     Counter(RangeExpr, RangeExpr),
     Struct(StructExpr),
+    /// Written as `[body for iter in target]`, evaluates `body` once per element of `target`
+    /// with `iter` bound in a scope of its own, so `iter` isn't visible once the list is built
+    Comprehension {
+        body: Box<ObjectExpr>,
+        iter: VarNameId,
+        target: IterTargetExpr,
+    },
 }
 
 impl ObjectExpr {
-    pub fn evaluate(&self, state: &ProgramState) -> Result<Object, VariableAccessError> {
+    pub fn evaluate(&self, state: &mut ProgramState) -> Result<Object, VariableAccessError> {
         match self {
             ObjectExpr::Clone(variable_ref) => {
                 let object = state.get_object(variable_ref)?;
                 Ok(object.clone())
             }
+            ObjectExpr::Ref(variable_ref) => Ok(Object::Ref(state.get_ref(variable_ref)?)),
+            ObjectExpr::Deref(variable_ref) => match state.get_object(variable_ref)? {
+                Object::Ref(target) => state.evaluate_ref(*target).cloned(),
+                object => Ok(object.clone()),
+            },
+            ObjectExpr::BoolLit(value) => Ok(Object::Bool(*value)),
+            ObjectExpr::Condition(condition) => Ok(Object::Bool(condition.evaluate(state)?)),
+            ObjectExpr::IntLit(value) => Ok(Object::new(value.to_string())),
+            ObjectExpr::StrLit(value) => Ok(Object::new(value.clone())),
             ObjectExpr::List(list_expr) => {
                 let mut list = Vec::with_capacity(list_expr.len());
 
@@ -117,6 +349,31 @@ impl ObjectExpr {
                     properties,
                 )))
             }
+            ObjectExpr::Comprehension { body, iter, target } => {
+                let Object::List(values) = target.materialize(state)? else {
+                    unreachable!()
+                };
+
+                // `iter` is bound in a scope of its own, popped even if the body fails
+                state.new_scope();
+                let scope = state.scopes.len() - 1;
+
+                let list = values
+                    .into_iter()
+                    .map(|value| {
+                        state.insert_var(*iter, value, Some(scope));
+                        let object = body.evaluate(state)?;
+
+                        match object.refs_above(scope - 1) {
+                            true => Err(VariableAccessError::InvalidRef),
+                            false => Ok(object),
+                        }
+                    })
+                    .collect::<Result<Vec<_>, _>>();
+
+                state.pop_scope();
+                Ok(Object::List(list?))
+            }
         }
     }
 }
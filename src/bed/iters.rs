@@ -36,6 +36,11 @@ impl IterProgress {
         self.next_message.push_str(message);
     }
 
+    /// Message of the current iteration, including one not yet drawn to the bar
+    pub fn current_message(&self) -> &str {
+        &self.next_message
+    }
+
     pub fn update(&self) {
         if self.next_idx == 0 {
             self.bar.reset();
@@ -57,6 +62,15 @@ impl IterProgress {
         (pos, len)
     }
 
+    /// Latest position and length, including a position not yet drawn to the bar
+    pub fn get_final(&self) -> (u64, u64) {
+        (self.next_idx, self.bar.length().unwrap_or(0))
+    }
+
+    pub fn get_name(&self) -> String {
+        self.bar.prefix()
+    }
+
     pub fn get_eta(&self) -> Duration {
         self.bar.eta()
     }
@@ -69,21 +83,44 @@ impl IterProgress {
         self.bar.message()
     }
 
-    pub fn write_summary(&self, mut writer: impl Write) -> std::io::Result<usize> {
+    /// Writes a single line summary, `precise` adds milliseconds to the eta and elapsed time
+    pub fn write_summary(&self, mut writer: impl Write, precise: bool) -> std::io::Result<usize> {
         let var_name = self.bar.prefix();
         let message = self.get_msg();
         let (pos, len) = self.get_progress();
-        let eta = self.get_eta();
-        let elapsed = self.get_elapsed();
-        let (eta_s, eta_m, eta_h) = seconds_to_smh(eta.as_secs());
-        let (elapsed_s, elapsed_m, elapsed_h) = seconds_to_smh(elapsed.as_secs());
+        let eta = format_duration(self.get_eta(), precise);
+        let elapsed = format_duration(self.get_elapsed(), precise);
 
-        let formatted = format!("[{var_name} = {pos} / {len}] : Eta {eta_h}h:{eta_m}m:{eta_s}s : Elapsed {elapsed_h}h:{elapsed_m}m:{elapsed_s}s : {message}");
+        let formatted =
+            format!("[{var_name} = {pos} / {len}] : Eta {eta} : Elapsed {elapsed} : {message}");
         let bytes = formatted.as_bytes();
 
         writer.write_all(bytes)?;
         Ok(bytes.len())
     }
+
+    /// The same summary as `write_summary` for a JSON lines feed, times are in milliseconds
+    pub fn summary_json(&self) -> serde_json::Value {
+        let (pos, len) = self.get_progress();
+
+        serde_json::json!({
+            "name": self.bar.prefix(),
+            "position": pos,
+            "len": len,
+            "eta_ms": self.get_eta().as_millis() as u64,
+            "elapsed_ms": self.get_elapsed().as_millis() as u64,
+            "message": self.get_msg(),
+        })
+    }
+}
+
+fn format_duration(duration: Duration, precise: bool) -> String {
+    let (s, m, h) = seconds_to_smh(duration.as_secs());
+
+    match precise {
+        true => format!("{h}h:{m}m:{s}.{:03}s", duration.subsec_millis()),
+        false => format!("{h}h:{m}m:{s}s"),
+    }
 }
 
 fn seconds_to_smh(seconds: u64) -> (u64, u64, u64) {
@@ -1,17 +1,20 @@
 use std::{
+    collections::HashMap,
+    fmt::Write as _,
     io::{Seek, Write},
-    path::PathBuf,
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use indicatif::{MultiProgress, ProgressDrawTarget};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 
 use crate::program::{Executable, Object, ProgramState, VarNameId, VarNames, VariableAccessError};
 
 use self::{
-    commands::Command,
+    commands::{Command, Spawn},
     iters::IterProgress,
-    process::ProcessInfo,
+    process::{BarPosition, ProcessInfo, ResourceUsage},
+    status::StatusBar,
     templates::{yield_value, TemplateBuilder, TemplateCommand},
 };
 
@@ -19,20 +22,115 @@ pub mod commands;
 pub mod expr;
 pub mod iters;
 pub mod process;
+pub mod status;
 pub mod templates;
 
 pub const SLEEP_TIME: Duration = Duration::from_millis(100);
+/// Progress bar redraws per second unless set by `--refresh-hz`, the rate indicatif uses by
+/// default
+pub const REFRESH_HZ: u8 = 20;
+/// How long terminated processes still running at the end of the run get to exit before
+/// they are killed
+const TERMINATE_GRACE: Duration = Duration::from_secs(5);
+
+/// Number of processes by how they ended, reported at the end of a run
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessCounts {
+    pub spawned: usize,
+    pub succeeded: usize,
+    /// Includes processes that failed to spawn or timed out
+    pub failed: usize,
+    pub killed: usize,
+    /// Detached processes still running once every program finished
+    pub stopped: usize,
+    /// Stopped by a `terminate` command
+    pub terminated: usize,
+    /// CPU time summed over the processes that exited on their own and the largest peak
+    /// memory of any of them, `None` if no usage was collected
+    pub resources: Option<ResourceUsage>,
+}
+
+impl ProcessCounts {
+    fn add_resources(&mut self, usage: &ResourceUsage) {
+        let total = self.resources.get_or_insert_with(ResourceUsage::default);
+        total.user += usage.user;
+        total.system += usage.system;
+        total.peak_rss_kib = total.peak_rss_kib.max(usage.peak_rss_kib);
+    }
+}
+
+/// Header bar of a `group` block, `last` is the most recent bar inserted beneath it
+pub struct GroupBar {
+    header: ProgressBar,
+    last: ProgressBar,
+}
+
+/// How many groups, iterators and `cwd_each` dirs were open when a `timeout` block started,
+/// anything opened inside it is closed if it expires
+#[derive(Clone, Copy, Debug)]
+struct BlockDepth {
+    groups: usize,
+    iters: usize,
+    dirs: usize,
+}
 
 pub struct TestBed<'source> {
     pub templates: TemplateBuilder<'source>,
     pub var_names: VarNames,
 
+    /// Spawn limit each program starts with, a `limit` command overrides it until the next reset
+    pub default_spawn_limit: Option<usize>,
     pub spawn_limit: Option<usize>,
+    /// Shutdown the whole run as soon as a process fails
+    pub fail_fast: bool,
+    /// Log and skip template commands that hit a variable error instead of aborting the template
+    pub continue_on_error: bool,
+    /// Passed on to every spawned process, see `ProcessInfo::flush_lines`
+    pub flush_lines: bool,
+    /// Label process bars with the current loop variables, see `TestBed::iteration`
+    pub show_iters: bool,
+    /// Passed on to every spawned process, see `ProcessInfo::stderr_tail`
+    pub stderr_tail: usize,
+    /// Redraws per second of the progress bars, see `TestBed::set_refresh_hz`
+    refresh_hz: u8,
+    /// Time between polls of the wait loops, `SLEEP_TIME` unless set by `--poll-interval`
+    pub poll_interval: Duration,
+    /// Set by `--poll-backoff`, each wait doubles its poll interval after every poll up to this
+    pub poll_backoff: Option<Duration>,
+    /// Base environment of every spawned process, see `ProcessInfo::env`
+    pub env: Vec<(String, String)>,
+    /// Set once any process fails to spawn or exits unsuccessfully
+    pub failed: bool,
+    /// Set once any still running process had to be killed
+    pub killed: bool,
+    pub counts: ProcessCounts,
+    pub started: Instant,
+    /// Name, final position and length of each iterator, kept after its bar is finished
+    pub finished_iters: Vec<(String, u64, u64)>,
     pub processes: Vec<ProcessInfo>,
+    /// Sent `SIGTERM` by `terminate` and no longer tracked, kept to collect their exit status
+    pub terminated: Vec<ProcessInfo>,
     pub iters: Vec<(VarNameId, IterProgress)>,
+    pub groups: Vec<GroupBar>,
+    /// Directories of the enclosing `cwd_each` blocks, innermost last
+    pub dirs: Vec<PathBuf>,
+    /// Times each `cwd_each` block of the program was entered, by its `block`
+    dir_entries: HashMap<usize, usize>,
+    /// One per enclosing `timeout` block, innermost last
+    deadline_depths: Vec<BlockDepth>,
     pub multibar: MultiProgress,
+    /// Shown from `new` until `reset`, and again once `show_status` starts the next program.
+    /// `None` while drawing to another test bed's bars.
+    status: Option<StatusBar>,
+    /// Times each truncating output file has been opened this run
+    pub truncated_outputs: HashMap<PathBuf, usize>,
 
-    progress_file: Option<std::fs::File>,
+    progress_file: Option<ProgressOutput>,
+    /// Set by `BED_PROGRESS_PRECISION=ms`, writes eta and elapsed times with milliseconds
+    progress_precise: bool,
+    /// Set by `BED_PROGRESS_APPEND=1`, every snapshot is appended under a timestamp instead of
+    /// overwriting the previous one
+    progress_append: bool,
 }
 
 impl<'source> TestBed<'source> {
@@ -42,116 +140,711 @@ impl<'source> TestBed<'source> {
         var_names: VarNames,
     ) -> Self {
         let templates = TemplateBuilder::new(template_output, template_includes);
-        let progress = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
-
-        let progress_file = std::env::var("BED_PROGRESS").ok().map(|file| {
-            match std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(&file)
-            {
-                Ok(file) => file,
+        let progress =
+            MultiProgress::with_draw_target(ProgressDrawTarget::stdout_with_hz(REFRESH_HZ));
+
+        let progress_append = std::env::var("BED_PROGRESS_APPEND").is_ok_and(|value| value == "1");
+
+        // Progress is best effort, the run goes ahead without it if the output can't be opened
+        let progress_file = std::env::var("BED_PROGRESS").ok().and_then(|file| {
+            match ProgressOutput::open(&file, progress_append) {
+                Ok(output) => Some(output),
                 Err(e) => {
-                    panic!("Failed to create file `{file}`: {e}");
+                    eprintln!("Not writing progress to `{file}`: {e}");
+                    None
                 }
             }
         });
 
+        let progress_precise =
+            std::env::var("BED_PROGRESS_PRECISION").is_ok_and(|value| value == "ms");
+
         Self {
             templates,
             var_names,
+            default_spawn_limit: None,
             spawn_limit: None,
+            fail_fast: false,
+            continue_on_error: false,
+            flush_lines: false,
+            show_iters: false,
+            stderr_tail: 0,
+            refresh_hz: REFRESH_HZ,
+            poll_interval: SLEEP_TIME,
+            poll_backoff: None,
+            env: vec![],
+            failed: false,
+            killed: false,
+            counts: ProcessCounts::default(),
+            started: Instant::now(),
+            finished_iters: vec![],
             processes: vec![],
+            terminated: vec![],
             iters: vec![],
+            groups: vec![],
+            dirs: vec![],
+            dir_entries: HashMap::new(),
+            deadline_depths: vec![],
+            status: Some(StatusBar::new(&progress)),
             multibar: progress,
+            truncated_outputs: HashMap::new(),
             progress_file,
+            progress_precise,
+            progress_append,
         }
     }
 
     pub fn reset(&mut self, shutdown: &crate::program::Shutdown) {
-        self.wait_all(None, 0, shutdown);
-        self.processes.clear();
-        self.spawn_limit = None;
-        self.multibar = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+        self.wait_all(None, 0, None, shutdown);
+        self.processes.retain(|process| process.detached);
+        self.groups.clear();
+        self.dirs.clear();
+        self.dir_entries.clear();
+        self.deadline_depths.clear();
+        self.spawn_limit = self.default_spawn_limit;
+
+        if let Some(status) = self.status.take() {
+            status.finish();
+        }
+
+        // Detached processes still draw to the current bars
+        if self.processes.is_empty() {
+            self.multibar = MultiProgress::with_draw_target(self.draw_target());
+        }
+    }
+
+    /// Shows the status bar cleared by `reset` again before the next program runs
+    pub fn show_status(&mut self) {
+        if self.status.is_none() {
+            self.status = Some(StatusBar::new(&self.multibar));
+            self.update_status(None, 0);
+        }
+    }
+
+    /// Draws to the bars of another test bed, e.g. one building templates on another thread,
+    /// whose status bar then stands for both
+    pub fn draw_to(&mut self, multibar: MultiProgress) {
+        if let Some(status) = self.status.take() {
+            status.finish();
+        }
+        self.multibar = multibar;
+    }
+
+    /// Limits the progress bars to `hz` redraws per second, and each process bar to as many
+    /// updates of its output line, so many chatty processes don't spend their time redrawing
+    pub fn set_refresh_hz(&mut self, hz: u8) {
+        self.refresh_hz = hz;
+        self.multibar.set_draw_target(self.draw_target());
     }
 
+    fn draw_target(&self) -> ProgressDrawTarget {
+        ProgressDrawTarget::stdout_with_hz(self.refresh_hz)
+    }
+
+    /// Closes the `group` and `cwd_each` blocks a program failed inside of, so its `on_failure`
+    /// block starts outside of them. Processes are left running.
+    pub fn leave_blocks(&mut self) {
+        for group in self.groups.drain(..).rev() {
+            group.header.finish();
+        }
+        self.dirs.clear();
+        self.deadline_depths.clear();
+    }
+
+    fn end_group(&mut self) {
+        if let Some(group) = self.groups.pop() {
+            group.header.finish();
+
+            if let Some(parent) = self.groups.last_mut() {
+                parent.last = group.last;
+            }
+        }
+    }
+
+    /// Kills any detached processes that are still running, called once every program has run
+    pub fn stop_detached(&mut self) {
+        self.counts.stopped += self.processes.len();
+
+        for mut value in self.processes.drain(..) {
+            value.kill();
+        }
+
+        let start = Instant::now();
+        let mut interval = self.poll_interval;
+        self.reap_terminated();
+
+        while !self.terminated.is_empty() && start.elapsed() < TERMINATE_GRACE {
+            self.poll_sleep(&mut interval, [start.checked_add(TERMINATE_GRACE)]);
+            self.reap_terminated();
+        }
+
+        // Terminated processes that ignored the signal
+        for mut value in self.terminated.drain(..) {
+            value.kill();
+        }
+    }
+
+    /// Sleeps for `interval`, or less if one of `deadlines` or a process's `timeout` block
+    /// deadline comes first, so timeouts fire close to on time instead of up to a whole
+    /// interval late. `interval` starts at `poll_interval` for each wait and is doubled up to
+    /// `poll_backoff` after every poll.
+    fn poll_sleep<const N: usize>(&self, interval: &mut Duration, deadlines: [Option<Instant>; N]) {
+        let now = Instant::now();
+        let processes = self.processes.iter().map(|process| process.deadline);
+
+        let sleep = deadlines
+            .into_iter()
+            .chain(processes)
+            .flatten()
+            .map(|deadline| deadline.saturating_duration_since(now))
+            .fold(*interval, Duration::min);
+
+        std::thread::sleep(sleep);
+
+        if let Some(backoff) = self.poll_backoff {
+            *interval = interval
+                .saturating_mul(2)
+                .min(backoff)
+                .max(self.poll_interval);
+        }
+    }
+
+    /// Drops the terminated processes that have exited
+    fn reap_terminated(&mut self) {
+        self.terminated.retain_mut(|process| !process.reap());
+    }
+
+    /// Redraws the status bar. `phase` replaces what it says the test bed is doing, `inline`
+    /// counts a `run` process that isn't in `processes`.
+    fn update_status(&mut self, phase: Option<String>, inline: usize) {
+        let Some(status) = &mut self.status else {
+            return;
+        };
+
+        if let Some(phase) = phase {
+            status.set_phase(phase);
+        }
+        status.update(&self.counts, self.processes.len() + inline);
+    }
+
+    fn attached(&self) -> usize {
+        self.processes
+            .iter()
+            .filter(|process| !process.detached)
+            .count()
+    }
+
+    /// Waits until fewer than `remaining` attached processes are left. `deadline` is the
+    /// deadline of the enclosing `timeout` blocks, waiting stops once it passes and the program
+    /// skips the rest of the block.
     fn wait_all(
         &mut self,
         wait: Option<u64>,
         remaining: usize,
+        deadline: Option<Instant>,
         shutdown: &crate::program::Shutdown,
     ) {
         let duration = wait.unwrap_or(u64::MAX);
         let duration = Duration::from_millis(duration);
         let now = Instant::now();
+        let end = now.checked_add(duration);
         let mut kill = false;
+        let mut interval = self.poll_interval;
         let remaining = remaining.max(1);
 
-        while self.processes.len() >= remaining && now.elapsed() < duration {
+        if self.attached() >= remaining {
+            let phase = format!("Waiting for {} processes", self.attached());
+            self.update_status(Some(phase), 0);
+        }
+
+        while self.attached() >= remaining && now.elapsed() < duration {
             if shutdown.is_shutdown() {
                 kill = true;
                 break;
             }
+
+            self.time_out_expired(shutdown);
+            self.reap_terminated();
+
+            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                return;
+            }
             let mut i = 0;
 
             while i < self.processes.len() {
                 if self.processes[i].try_wait() {
-                    self.processes.swap_remove(i);
+                    let process = self.processes.swap_remove(i);
+                    self.exited(&process, shutdown);
                     continue;
                 }
                 i += 1;
             }
 
-            std::thread::sleep(SLEEP_TIME);
+            if self.fail_fast && self.failed {
+                kill = true;
+                break;
+            }
+
+            self.poll_sleep(&mut interval, [end, deadline]);
         }
 
         if kill {
             <Self as Executable<Command>>::shutdown(self);
+        } else if self.attached() >= remaining {
+            self.time_out(shutdown, |process| !process.detached);
+        }
+    }
+
+    /// The variables of the loops currently running and their values, e.g.
+    /// `[os=linux, arch=arm]`. Hidden loop variables such as the counter of a `repeat` are left
+    /// out, `None` outside of loops.
+    fn iteration(&self, stack: &ProgramState) -> Option<String> {
+        let values: Vec<_> = self
+            .iters
+            .iter()
+            .filter(|(id, _)| stack.get_value(*id).is_some())
+            .filter_map(|(_, iter)| {
+                let name = iter.get_name();
+                match name.starts_with('@') {
+                    true => None,
+                    false => Some(format!("{name}={}", iter.current_message())),
+                }
+            })
+            .collect();
+
+        match values.is_empty() {
+            true => None,
+            false => Some(format!("[{}]", values.join(", "))),
+        }
+    }
+
+    /// The variables of the loops currently running and their current values as a JSON object,
+    /// e.g. `{"os":"linux","arch":"arm"}`, for `combo()` arguments. Like `TestBed::iteration`
+    /// hidden loop variables are left out and outer loops come first.
+    fn combo(&self, stack: &ProgramState) -> Result<String, VariableAccessError> {
+        let mut entries = vec![];
+
+        for (id, iter) in self.iters.iter() {
+            let name = iter.get_name();
+            if name.starts_with('@') {
+                continue;
+            }
+            let Some((_, object)) = stack.get_value(*id) else {
+                continue;
+            };
+
+            // Built by hand since a `serde_json::Map` would sort the loops by name
+            let entry = serde_json::to_string(&name).and_then(|name| {
+                let value = serde_json::to_string(&object.to_serialize(stack, &self.var_names))?;
+                Ok(format!("{name}:{value}"))
+            });
+            entries.push(entry.map_err(|e| VariableAccessError::SerializeError(e.to_string()))?);
+        }
+
+        Ok(format!("{{{}}}", entries.join(",")))
+    }
+
+    /// Spawns the process once there is a free slot under the spawn limit. Returns `None` if
+    /// the enclosing `timeout` block expired while waiting or the process failed to start.
+    fn start(
+        &mut self,
+        spawn: &Spawn,
+        stack: &ProgramState,
+        shutdown: &crate::program::Shutdown,
+    ) -> Result<Option<ProcessInfo>, VariableAccessError> {
+        let deadline = stack.deadline();
+
+        if let Some(limit) = self.spawn_limit {
+            if self.attached() >= limit {
+                self.wait_all(None, limit, deadline, shutdown);
+            }
+        }
+
+        // The enclosing `timeout` block expired while waiting for a free slot
+        if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+            return Ok(None);
+        }
+
+        self.iters.iter().for_each(|value| value.1.update());
+        self.write_progress();
+
+        let combo = match spawn.uses_combo() {
+            true => Some(self.combo(stack)?),
+            false => None,
+        };
+        let mut process = spawn.evaluate(stack, &self.var_names, combo.as_deref())?;
+        process.flush_lines = self.flush_lines;
+        process.stderr_tail = self.stderr_tail;
+        process.refresh_hz = self.refresh_hz;
+        if self.show_iters {
+            process.iteration = self.iteration(stack);
+        }
+        process.env = self.env.clone();
+        process.deadline = deadline;
+        if let Some(dir) = self.dirs.last().filter(|_| process.working_dir.is_none()) {
+            process.stdout = process.stdout.map(|path| dir.join(path));
+            process.stderr = process.stderr.map(|path| dir.join(path));
+            process.working_dir = Some(dir.clone());
+        }
+        self.check_truncated(&process);
+
+        self.update_status(Some(format!("Spawning {}", process.command)), 0);
+
+        let position = match self.groups.last() {
+            Some(group) => BarPosition::After(&group.last),
+            None => BarPosition::FromBack(self.iters.len()),
+        };
+
+        self.counts.spawned += 1;
+
+        if let Err(e) = process.run(position, &self.multibar) {
+            self.multibar
+                .println(format!("Failed to spawn {}: {e}", process.command))
+                .ok();
+            self.counts.failed += 1;
+            self.update_status(None, 0);
+            self.set_failed(shutdown);
+            return Ok(None);
+        }
+
+        if let (Some(group), Some(running)) = (self.groups.last_mut(), &process.running) {
+            group.last = running.bar.bar.clone();
+        }
+        self.update_status(None, 1);
+
+        Ok(Some(process))
+    }
+
+    /// Polls a single process started by `run` until it exits. On a shutdown or once the
+    /// enclosing `timeout` block expires it is handed back to `processes` so it is killed or
+    /// timed out like any other.
+    fn run_inline(
+        &mut self,
+        mut process: ProcessInfo,
+        deadline: Option<Instant>,
+        shutdown: &crate::program::Shutdown,
+    ) {
+        let mut interval = self.poll_interval;
+        self.update_status(Some(format!("Running {}", process.command)), 1);
+
+        loop {
+            if process.try_wait() {
+                self.exited(&process, shutdown);
+                return;
+            }
+
+            if shutdown.is_shutdown() {
+                self.processes.push(process);
+                <Self as Executable<Command>>::shutdown(self);
+                return;
+            }
+
+            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                self.processes.push(process);
+                self.time_out_expired(shutdown);
+                return;
+            }
+
+            self.time_out_expired(shutdown);
+            self.poll_sleep(&mut interval, [deadline]);
+        }
+    }
+
+    /// Relative paths are inside the directory of the enclosing `cwd_each`, like the working
+    /// dir of a spawn
+    fn resolve_path(&self, path: String) -> PathBuf {
+        match self.dirs.last() {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// Times out the processes whose `timeout` block deadline has passed
+    fn time_out_expired(&mut self, shutdown: &crate::program::Shutdown) {
+        let now = Instant::now();
+
+        if self.processes.iter().any(|process| process.is_expired(now)) {
+            self.time_out(shutdown, |process| process.is_expired(now));
+        }
+    }
+
+    /// Kills every process matching `filter`, e.g. the attached ones left after a `wait_all`
+    /// timeout. They are reported as failed rather than killed so a timeout can be told apart
+    /// from a shutdown.
+    fn time_out(
+        &mut self,
+        shutdown: &crate::program::Shutdown,
+        filter: impl Fn(&ProcessInfo) -> bool,
+    ) {
+        let mut timed_out = false;
+        let mut i = 0;
+
+        while i < self.processes.len() {
+            if !filter(&self.processes[i]) {
+                i += 1;
+                continue;
+            }
+
+            let mut process = self.processes.swap_remove(i);
+
+            // It may have exited since the last poll
+            if process.try_wait() {
+                self.exited(&process, shutdown);
+                continue;
+            }
+
+            self.multibar
+                .println(format!("Timed out: {}", process.describe()))
+                .ok();
+            process.time_out();
+            self.counts.failed += 1;
+            timed_out = true;
+        }
+
+        if timed_out {
+            self.update_status(None, 0);
+            self.set_failed(shutdown);
+        }
+    }
+
+    /// Warns the second time a truncating output is opened, usually a loop that forgot to
+    /// put the iteration into the file name and keeps overwriting earlier output
+    fn check_truncated(&mut self, process: &ProcessInfo) {
+        let outputs = [process.stdout.truncated(), process.stderr.truncated()];
+
+        for path in outputs.into_iter().flatten() {
+            let count = self.truncated_outputs.entry(path.clone()).or_default();
+            *count += 1;
+
+            if *count == 2 {
+                self.multibar
+                    .println(format!(
+                        "Warning: `{}` is truncated by more than one process, earlier output is overwritten",
+                        path.display()
+                    ))
+                    .ok();
+            }
+        }
+    }
+
+    fn exited(&mut self, process: &ProcessInfo, shutdown: &crate::program::Shutdown) {
+        if let Some(resources) = process.resources() {
+            self.counts.add_resources(resources);
+        }
+
+        if process.is_failed() {
+            self.print_stderr_tail(process);
+            self.counts.failed += 1;
+            self.set_failed(shutdown);
+        } else {
+            self.counts.succeeded += 1;
+        }
+        self.update_status(None, 0);
+    }
+
+    fn print_stderr_tail(&self, process: &ProcessInfo) {
+        let lines = process.stderr_tail();
+
+        if lines.is_empty() {
+            return;
+        }
+
+        let mut message = format!("Failed: {}", process.describe());
+        for line in lines {
+            message.push_str("\n  ");
+            message.push_str(&line);
+        }
+
+        self.multibar.println(message).ok();
+    }
+
+    fn finish_iters(&mut self) {
+        self.finish_iters_from(0);
+    }
+
+    /// Finishes the bars of the iterators past the first `from`
+    fn finish_iters_from(&mut self, from: usize) {
+        for (_, value) in self.iters.drain(from..) {
+            let name = value.get_name();
+            let (pos, len) = value.get_final();
+            value.finish();
+
+            match self.finished_iters.iter_mut().find(|iter| iter.0 == name) {
+                Some(iter) => *iter = (name, pos, len),
+                None => self.finished_iters.push((name, pos, len)),
+            }
+        }
+    }
+
+    /// The process counts and finished iterators, printed by the CLI at the end of a run
+    pub fn summary(&self) -> String {
+        let counts = &self.counts;
+        let mut out = String::new();
+        writeln!(out, "Summary").unwrap();
+        writeln!(out, "  spawned:   {}", counts.spawned).unwrap();
+        writeln!(out, "  succeeded: {}", counts.succeeded).unwrap();
+        writeln!(out, "  failed:    {}", counts.failed).unwrap();
+        writeln!(out, "  killed:    {}", counts.killed).unwrap();
+
+        if counts.stopped > 0 {
+            writeln!(out, "  stopped:   {}", counts.stopped).unwrap();
+        }
+        if counts.terminated > 0 {
+            writeln!(out, "  terminated: {}", counts.terminated).unwrap();
+        }
+
+        writeln!(out, "  elapsed:   {:.2?}", self.started.elapsed()).unwrap();
+
+        if let Some(resources) = &counts.resources {
+            writeln!(out, "  resources: {resources}").unwrap();
+        }
+
+        if self.finished_iters.is_empty() {
+            return out;
+        }
+
+        writeln!(out, "Iterators").unwrap();
+        for (name, pos, len) in self.finished_iters.iter() {
+            writeln!(out, "  {name}: {pos}/{len}").unwrap();
+        }
+
+        out
+    }
+
+    fn set_failed(&mut self, shutdown: &crate::program::Shutdown) {
+        self.failed = true;
+
+        if self.fail_fast {
+            shutdown.shutdown();
         }
     }
 
     fn write_progress(&mut self) {
-        let Some(file) = &mut self.progress_file else {
+        let Some(output) = &mut self.progress_file else {
             return;
         };
+
+        // A full disk or the reader going away shouldn't fail the run, the feed just stops
+        if let Err(e) = output.write(&self.iters, self.progress_precise, self.progress_append) {
+            self.multibar
+                .println(format!("Stopped writing progress to `BED_PROGRESS`: {e}"))
+                .ok();
+            self.progress_file = None;
+        }
+    }
+}
+
+/// Where `BED_PROGRESS` snapshots are written
+enum ProgressOutput {
+    /// Rewritten in place, or appended to with `BED_PROGRESS_APPEND=1`
+    File(std::fs::File),
+    /// A FIFO or Unix socket can't seek, so every snapshot is written as a line of JSON
+    Stream(Box<dyn Write + Send + Sync>),
+}
+
+impl ProgressOutput {
+    fn open(path: &str, append: bool) -> std::io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+            let file_type = std::fs::metadata(path).map(|meta| meta.file_type());
+
+            if file_type.as_ref().is_ok_and(FileTypeExt::is_socket) {
+                let stream = UnixStream::connect(path)?;
+                return Ok(ProgressOutput::Stream(Box::new(stream)));
+            }
+            if file_type.as_ref().is_ok_and(FileTypeExt::is_fifo) {
+                // Blocks until the pipe has a reader
+                let pipe = std::fs::OpenOptions::new().write(true).open(path)?;
+                return Ok(ProgressOutput::Stream(Box::new(pipe)));
+            }
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .append(append)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        Ok(ProgressOutput::File(file))
+    }
+
+    fn write(
+        &mut self,
+        iters: &[(VarNameId, IterProgress)],
+        precise: bool,
+        append: bool,
+    ) -> std::io::Result<()> {
+        let file = match self {
+            ProgressOutput::File(file) => file,
+            ProgressOutput::Stream(stream) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let iters: Vec<_> = iters
+                    .iter()
+                    .map(|(_, value)| value.summary_json())
+                    .collect();
+                let snapshot = serde_json::json!({
+                    "unix_ms": now.as_millis() as u64,
+                    "iters": iters,
+                });
+
+                writeln!(stream, "{snapshot}")?;
+                return stream.flush();
+            }
+        };
+
+        if append {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            writeln!(file, "=== {}.{:03} ===", now.as_secs(), now.subsec_millis())?;
+
+            for (_, value) in iters.iter() {
+                value.write_summary(&mut *file, precise)?;
+                file.write_all(b"\n")?;
+            }
+            return Ok(());
+        }
+
         let len = file.metadata().map(|meta| meta.len()).unwrap_or(0);
-        file.seek(std::io::SeekFrom::Start(0))
-            .expect("Failed to seek file");
+        file.seek(std::io::SeekFrom::Start(0))?;
 
         let mut written = 0;
 
-        for (_, value) in self.iters.iter() {
-            written += value
-                .write_summary(&mut *file)
-                .expect("Failed to write to file");
-            file.write_all(&[b'\n']).expect("Failed to write new line");
+        for (_, value) in iters.iter() {
+            written += value.write_summary(&mut *file, precise)?;
+            file.write_all(b"\n")?;
             written += 1;
         }
 
         if written < len as usize {
             let zeros = vec![b' '; len as usize - written];
-            file.write_all(&zeros).expect("Failed to write zeros");
+            file.write_all(&zeros)?;
         }
+
+        Ok(())
     }
 }
 
 impl<'source> Executable<Command> for TestBed<'source> {
     fn shutdown(&mut self) {
-        for mut value in self.processes.drain(..) {
+        self.killed |= !self.processes.is_empty();
+        self.counts.killed += self.processes.len();
+
+        for mut value in self.processes.drain(..).chain(self.terminated.drain(..)) {
             value.kill();
         }
 
-        for (_, value) in self.iters.drain(..) {
-            value.finish();
-        }
+        self.finish_iters();
     }
 
     fn finish(&mut self, _: &mut ProgramState, shutdown: &crate::program::Shutdown) {
-        self.wait_all(None, 0, shutdown);
-
-        for (_, value) in self.iters.drain(..) {
-            value.finish();
-        }
+        self.wait_all(None, 0, None, shutdown);
+        self.finish_iters();
     }
 
     fn execute(
@@ -165,42 +858,191 @@ impl<'source> Executable<Command> for TestBed<'source> {
             Command::Sleep(millis) => {
                 let duration = Duration::from_millis(*millis);
                 let start = std::time::Instant::now();
+                let end = start.checked_add(duration);
+                let deadline = stack.deadline();
+                let mut interval = self.poll_interval;
 
                 while start.elapsed() < duration {
                     if shutdown.is_shutdown() {
                         break;
                     }
-                    std::thread::sleep(SLEEP_TIME);
+                    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                        break;
+                    }
+                    self.time_out_expired(shutdown);
+                    self.poll_sleep(&mut interval, [end, deadline]);
                 }
             }
             Command::Spawn(spawn) => {
-                if let Some(limit) = self.spawn_limit {
-                    if self.processes.len() >= limit {
-                        self.wait_all(None, limit, shutdown);
-                    }
+                if let Some(process) = self.start(spawn, stack, shutdown)? {
+                    self.processes.push(process);
                 }
+            }
+            Command::Run(spawn) => {
+                if let Some(process) = self.start(spawn, stack, shutdown)? {
+                    self.run_inline(process, stack.deadline(), shutdown);
+                }
+            }
+            Command::Terminate(name) => {
+                let name = name.evaluate(stack)?;
+                let mut found = false;
+                let mut i = 0;
+
+                while i < self.processes.len() {
+                    if self.processes[i].name.as_deref() != Some(name.as_str()) {
+                        i += 1;
+                        continue;
+                    }
 
-                self.iters.iter().for_each(|value| value.1.update());
-                self.write_progress();
+                    let mut process = self.processes.swap_remove(i);
+                    found = true;
 
-                let mut process = spawn.evaluate(stack)?;
-                if let Err(e) = process.run(self.iters.len(), &self.multibar) {
+                    // It may have exited since the last poll
+                    if process.try_wait() {
+                        self.exited(&process, shutdown);
+                        continue;
+                    }
+
+                    process.terminate();
+                    self.counts.terminated += 1;
+                    self.terminated.push(process);
+                }
+
+                if !found {
                     self.multibar
-                        .println(&format!("Failed to spawn {}: {e}", process.command))
+                        .println(format!("No running process named `{name}` to terminate"))
                         .ok();
-                    return Ok(());
                 }
+            }
+            Command::StartGroup(name) => {
+                let name = name.evaluate(stack)?;
+                let header = ProgressBar::new_spinner();
+                header
+                    .set_style(ProgressStyle::with_template("{prefix:.bold} {wide_msg}").unwrap());
+                header.set_prefix(format!("== {name} =="));
 
-                self.processes.push(process);
+                let header = match self.groups.last() {
+                    Some(group) => self.multibar.insert_after(&group.last, header),
+                    None => self.multibar.insert_from_back(self.iters.len(), header),
+                };
+
+                if let Some(group) = self.groups.last_mut() {
+                    group.last = header.clone();
+                }
+
+                self.groups.push(GroupBar {
+                    last: header.clone(),
+                    header,
+                });
+            }
+            Command::EndGroup => self.end_group(),
+            Command::PushDir { dir, block } => {
+                let entries = self.dir_entries.entry(*block).or_default();
+                let dir = dir
+                    .evaluate(stack)?
+                    .replace("{index}", &entries.to_string());
+                *entries += 1;
+                let dir = self.resolve_path(dir);
+
+                if let Err(e) = std::fs::create_dir_all(&dir) {
+                    return Err(VariableAccessError::CreateDirFailed(format!(
+                        "{}: {e}",
+                        dir.display()
+                    )));
+                }
+
+                self.dirs.push(dir);
+            }
+            Command::PopDir => {
+                self.dirs.pop();
+            }
+            Command::Copy { src, dst } => {
+                let src = self.resolve_path(src.evaluate(stack)?);
+                let dst = self.resolve_path(dst.evaluate(stack)?);
+
+                if let Err(e) = copy_file(&src, &dst) {
+                    return Err(VariableAccessError::CopyFailed(format!(
+                        "{} -> {}: {e}",
+                        src.display(),
+                        dst.display()
+                    )));
+                }
+            }
+            Command::Symlink { src, dst } => {
+                let src = self.resolve_path(src.evaluate(stack)?);
+                let dst = self.resolve_path(dst.evaluate(stack)?);
+
+                if let Err(e) = symlink(&src, &dst) {
+                    return Err(VariableAccessError::SymlinkFailed(format!(
+                        "{} -> {}: {e}",
+                        src.display(),
+                        dst.display()
+                    )));
+                }
+            }
+            Command::Assert { cond, message } => {
+                if !cond.evaluate(stack)? {
+                    return Err(VariableAccessError::AssertionFailed(
+                        message.evaluate(stack)?,
+                    ));
+                }
             }
             Command::WaitAll(timeout) => {
-                self.wait_all(*timeout, 0, shutdown);
+                self.wait_all(*timeout, 0, stack.deadline(), shutdown);
+            }
+            Command::WaitFile { path, timeout } => {
+                let path = path.evaluate(stack)?;
+                let resolved = self.resolve_path(path.clone());
+                let start = Instant::now();
+                let duration = Duration::from_millis(timeout.unwrap_or(u64::MAX));
+                let end = start.checked_add(duration);
+                let deadline = stack.deadline();
+                let mut interval = self.poll_interval;
+
+                while !resolved.exists() {
+                    if shutdown.is_shutdown() {
+                        break;
+                    }
+                    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                        break;
+                    }
+                    if start.elapsed() >= duration {
+                        return Err(VariableAccessError::WaitFileTimeout(path));
+                    }
+                    self.time_out_expired(shutdown);
+                    self.poll_sleep(&mut interval, [end, deadline]);
+                }
             }
         }
 
         Ok(())
     }
 
+    fn enter_deadline(&mut self) {
+        self.deadline_depths.push(BlockDepth {
+            groups: self.groups.len(),
+            iters: self.iters.len(),
+            dirs: self.dirs.len(),
+        });
+    }
+
+    fn leave_deadline(&mut self) {
+        self.deadline_depths.pop();
+    }
+
+    fn expire_deadlines(&mut self, count: usize) {
+        let keep = self.deadline_depths.len().saturating_sub(count);
+        let Some(depth) = self.deadline_depths.drain(keep..).next() else {
+            return;
+        };
+
+        while self.groups.len() > depth.groups {
+            self.end_group();
+        }
+        self.finish_iters_from(depth.iters.min(self.iters.len()));
+        self.dirs.truncate(depth.dirs);
+    }
+
     fn set_iter(&mut self, iter_var: VarNameId, idx: usize, var: &Object) {
         let len = match var {
             Object::Counter(counter) => counter.len(),
@@ -229,14 +1071,14 @@ impl<'source> Executable<Command> for TestBed<'source> {
                     bar.set_message(&value.base);
                 }
             }
-            Object::Counter(counter) => bar.set_message(&format!("{}", counter.idx())),
+            Object::Counter(counter) => bar.set_message(&format!("{}", counter.current_value())),
             _ => {}
         }
     }
 
     fn print(&self, program: &ProgramState, object: &Object) {
         let display = object.to_display(program, &self.var_names);
-        self.multibar.println(&format!("{display}\n")).ok();
+        self.multibar.println(format!("{display}\n")).ok();
     }
 }
 
@@ -258,29 +1100,408 @@ impl<'source> Executable<TemplateCommand> for TestBed<'source> {
                         state.insert_var(*output, object, None);
                         return Ok(());
                     }
-                    Err(templates::TemplateBuildError::VariableError(e)) => return Err(e),
+                    Err(templates::TemplateBuildError::VariableError(e))
+                        if !self.continue_on_error =>
+                    {
+                        return Err(e)
+                    }
                     Err(e) => e,
                 }
             }
 
-            TemplateCommand::Yield { output, object } => {
-                match object.evaluate(state, &mut self.templates, &self.var_names) {
-                    Ok(object) => {
-                        yield_value(*output, object, state);
+            TemplateCommand::Yield { output, objects } => {
+                // Nothing is yielded unless every object evaluates
+                let evaluated: Result<Vec<_>, _> = objects
+                    .iter()
+                    .map(|object| object.evaluate(state, &mut self.templates, &self.var_names))
+                    .collect();
+
+                match evaluated {
+                    Ok(evaluated) => {
+                        for object in evaluated {
+                            yield_value(*output, object, state);
+                        }
                         return Ok(());
                     }
-                    Err(templates::TemplateBuildError::VariableError(e)) => return Err(e),
+                    Err(templates::TemplateBuildError::VariableError(e))
+                        if !self.continue_on_error =>
+                    {
+                        return Err(e)
+                    }
                     Err(e) => e,
                 }
             }
         };
 
         println!("{err}\n");
-        return Ok(());
+        Ok(())
     }
 
     fn print(&self, program: &ProgramState, object: &Object) {
         let display = object.to_display(program, &self.var_names);
-        self.multibar.println(&format!("{display}\n")).ok();
+        self.multibar.println(format!("{display}\n")).ok();
+    }
+}
+
+fn copy_file(src: &Path, dst: &Path) -> std::io::Result<()> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    std::fs::copy(src, dst).map(|_| ())
+}
+
+/// `src` is made absolute since a relative link target is resolved from the link's directory
+fn symlink(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let src = std::path::absolute(src)?;
+
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if dst.symlink_metadata().is_ok_and(|meta| !meta.is_dir()) {
+        std::fs::remove_file(dst)?;
+    }
+
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(src, dst);
+
+    #[cfg(windows)]
+    return match src.is_dir() {
+        true => std::os::windows::fs::symlink_dir(src, dst),
+        false => std::os::windows::fs::symlink_file(src, dst),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bed::commands::ObjectIter,
+        parser::parse_source,
+        program::Shutdown,
+        tests::{read_written, TempDir},
+    };
+
+    /// Runs the unnamed block of `source` and returns the test bed it ran on
+    fn run_commands(source: &str) -> TestBed<'static> {
+        try_run_commands(source).unwrap()
+    }
+
+    fn try_run_commands(source: &str) -> Result<TestBed<'static>, VariableAccessError> {
+        try_run_commands_with(source, |_| {})
+    }
+
+    /// Like `try_run_commands`, `configure` can change the test bed before the block runs
+    fn try_run_commands_with(
+        source: &str,
+        configure: impl FnOnce(&mut TestBed<'static>),
+    ) -> Result<TestBed<'static>, VariableAccessError> {
+        let parsed = parse_source(source).unwrap();
+        let (_, program) = parsed.commands_program(None).unwrap().unwrap();
+        let mut test_bed = TestBed::new(PathBuf::new(), vec![], parsed.names);
+        configure(&mut test_bed);
+        let mut state = ProgramState::new();
+        state.new_scope();
+
+        program
+            .run(&mut test_bed, &mut state, &Shutdown::new())
+            .map_err(|(_, e)| e)?;
+        Ok(test_bed)
+    }
+
+    /// Runs the globals and then the templates of `source` like a run would, templates are
+    /// looked up in and built into `dir`
+    fn run_templates(
+        source: &str,
+        dir: &Path,
+        globals_only: bool,
+    ) -> (TestBed<'static>, ProgramState) {
+        let parsed = parse_source(source).unwrap();
+        let globals = parsed.globals_program();
+        let templates = parsed.template_program();
+        let mut test_bed = TestBed::new(dir.to_path_buf(), vec![dir.to_path_buf()], parsed.names);
+        test_bed.templates.globals_only = globals_only;
+        let mut state = ProgramState::new();
+        let shutdown = Shutdown::new();
+
+        globals.run(&mut test_bed, &mut state, &shutdown).unwrap();
+        for (_, program) in templates {
+            state.new_scope();
+            program.run(&mut test_bed, &mut state, &shutdown).unwrap();
+        }
+        (test_bed, state)
+    }
+
+    #[test]
+    fn combination_loop_yields_flat_pairs() {
+        let (test_bed, state) = run_templates(
+            "[globals]\nxs = [a, b];\nys = [1, 2];\n\n[template.pairs]\nfor (x, y) in (xs, ys) {\n    yield [x], [x] + - + [y];\n}\n",
+            &std::env::temp_dir(),
+            false,
+        );
+        let id = test_bed.var_names.0.get_index_of("pairs").unwrap();
+        let Some(Object::List(pairs)) = state.scopes[0].0.get(&VarNameId(id)) else {
+            panic!("nothing yielded");
+        };
+        let pairs: Vec<String> = ObjectIter::from_slice(&state, pairs).unwrap().collect();
+
+        assert_eq!(pairs, ["a", "a-1", "a", "a-2", "b", "b-1", "b", "b-2"]);
+    }
+
+    #[test]
+    fn wait_file_looks_inside_cwd_each() {
+        let dir = TempDir::new("wait");
+        std::fs::create_dir_all(dir.join("run")).unwrap();
+        std::fs::write(dir.join("run/ready"), "").unwrap();
+
+        let result = try_run_commands(&format!(
+            "[commands]\ncwd_each \"{}\" {{\n    wait_file ready timeout 200;\n}}\n",
+            dir.join("run").display()
+        ))
+        .map(|_| ());
+
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[test]
+    fn multiline_string_interpolates_across_lines() {
+        let (test_bed, state) = run_templates(
+            "[globals]\nname = srv;\nports = [80, 443];\ntext = \"\"\"host {name}\n  ports {ports[0]},{ports[1]}\n{} \\{name} ${PATH} $x\n\"\"\" + [name];\n",
+            &std::env::temp_dir(),
+            false,
+        );
+        let id = test_bed.var_names.0.get_index_of("text").unwrap();
+        let mut text = String::new();
+        state.scopes[0].0[&VarNameId(id)]
+            .write_to_string(&state, &mut text)
+            .unwrap();
+
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(
+            text,
+            format!("host srv\n  ports 80,443\n{{}} {{name}} {path} $x\nsrv")
+        );
+    }
+
+    #[test]
+    fn innermost_variable_wins_in_templates() {
+        let dir = TempDir::new("shadow");
+        std::fs::write(dir.join("show.j2"), "{{ name }}").unwrap();
+        let source = "[globals]\nname = global;\n\n[template.out]\nfor name in [inner] {\n    yield (build(\"show.j2\", [name] + .txt));\n}\n";
+
+        run_templates(source, &dir, false);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("inner.txt")).unwrap(),
+            "inner"
+        );
+
+        run_templates(source, &dir, true);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("inner.txt")).unwrap(),
+            "global"
+        );
+    }
+
+    #[test]
+    fn status_bar_is_shown_from_the_start() {
+        let mut test_bed = TestBed::new(PathBuf::new(), vec![], VarNames::default());
+        assert!(test_bed.status.is_some());
+
+        test_bed.reset(&Shutdown::new());
+        assert!(test_bed.status.is_none());
+
+        test_bed.show_status();
+        assert!(test_bed.status.is_some());
+    }
+
+    #[test]
+    fn expired_timeout_closes_its_groups() {
+        let test_bed = run_commands(
+            "[commands]\ntimeout 50 {\n    group inner {\n        for i in 0..3 {\n            sleep 1000;\n        }\n    }\n}\n",
+        );
+
+        assert!(test_bed.groups.is_empty());
+        assert!(test_bed.deadline_depths.is_empty());
+    }
+
+    #[test]
+    fn expired_timeout_leaves_its_cwd_each() {
+        let dir = TempDir::new("cwd");
+        let test_bed = run_commands(&format!(
+            "[commands]\ntimeout 50 {{\n    cwd_each \"{}\" {{\n        sleep 1000;\n    }}\n}}\n",
+            dir.display()
+        ));
+
+        assert!(test_bed.dirs.is_empty());
+        assert!(test_bed.deadline_depths.is_empty());
+    }
+
+    #[test]
+    fn cwd_each_index_counts_the_iterations() {
+        let dir = TempDir::new("index");
+        let test_bed = run_commands(&format!(
+            "[commands]\nfor a in 0..2 {{\n    for b in 0..2 {{\n        cwd_each \"{}/run-{{index}}\" {{\n            wait_all;\n        }}\n    }}\n}}\n",
+            dir.display()
+        ));
+
+        let mut runs: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        runs.sort();
+
+        assert_eq!(runs, ["run-0", "run-1", "run-2", "run-3"]);
+        assert!(test_bed.dirs.is_empty());
+    }
+
+    #[test]
+    fn integer_literals_keep_their_text() {
+        run_commands(
+            "[commands]\nid = 12345678901234567890;\nz = 007;\nn = -0;\nk = 42;\nassert (id == 12345678901234567890) \"id is \" + [id];\nassert (z == 007) \"z is \" + [z];\nassert (n == -0) \"n is \" + [n];\nassert (k == 42) \"k is \" + [k];\n",
+        );
+
+        let parsed = parse_source("[commands]\nz = 007;\nk = -42;\n").unwrap();
+        let formatted = crate::parser::format::format_parsed(&parsed);
+        assert!(formatted.contains("z = \"007\";"), "{formatted}");
+        assert!(formatted.contains("k = -42;"), "{formatted}");
+    }
+
+    #[test]
+    fn conditions_evaluate_to_bools() {
+        run_commands(
+            "[commands]\nmode = release;\nmodes = [debug, release];\nrelease = (mode == release);\ndebug = (mode != release);\nlisted = (mode in modes);\nhas_port = exists(port);\nassert (release) \"release\";\nassert (listed) \"listed\";\nassert (debug == false) \"debug\";\nif has_port {\n    x = [missing];\n}\n",
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_with_spaces_is_never_split() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("split");
+        let tool = dir.join("dir with spaces/tool");
+        std::fs::create_dir_all(tool.parent().unwrap()).unwrap();
+        std::fs::write(&tool, "#!/bin/sh\necho \"$#\"\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let out = dir.join("out.txt");
+        let test_bed = run_commands(&format!(
+            "[commands]\nrun stdout(\"{}\") \"{}\" one;\n",
+            out.display(),
+            tool.display()
+        ));
+
+        assert!(!test_bed.failed);
+        assert_eq!(read_written(&out), "1\n");
+    }
+
+    #[test]
+    fn empty_command_is_an_error() {
+        let result = try_run_commands("[commands]\ntool = \"\";\nspawn [tool];\n").map(|_| ());
+        assert!(
+            matches!(result, Err(VariableAccessError::EmptyCommand)),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn comprehension_scope_does_not_leak() {
+        run_commands(
+            "[commands]\nbase = [a, b];\nitem = outer;\nnames = [[item] + _s for item in base];\nassert (item == outer) \"item is \" + [item];\nassert (names[1] == b_s) \"second is \" + [names[1]];\n",
+        );
+    }
+
+    #[test]
+    fn zero_padded_index_is_a_name() {
+        run_commands(
+            "[commands]\nl = [a, b, c, d, e, f, g, h, \"007\"];\nname = \"007\";\nidx = 7;\nby_name = [l[name]];\nby_idx = [l[idx]];\nassert (by_name == \"007\") \"by name \" + [by_name];\nassert (by_idx == h) \"by index \" + [by_idx];\n",
+        );
+    }
+
+    #[test]
+    fn range_loop_compares_counter_value() {
+        run_commands(
+            "[commands]\nhits = [];\nmisses = [];\nfor i in 0..5 {\n    j = ref i;\n    if (i == 3) {\n        hits.push([i]);\n    }\n    if (j != 3) exists(i) {\n        misses.push([j]);\n    }\n}\nassert (hits[0] == 3) \"hit \" + [hits[0]];\nsecond_hit = exists(hits[1]);\nassert (second_hit == false) \"hit twice\";\nassert (misses[2] == 2) \"third miss \" + [misses[2]];\nassert (misses[3] == 4) \"last miss \" + [misses[3]];\n",
+        );
+    }
+
+    #[test]
+    fn if_on_a_struct_holds_when_it_is_false() {
+        run_commands(
+            "[commands]\nhits = [];\nyes = \"true\";\nno = \"false\";\nflag = true;\nif yes {\n    hits.push(yes);\n}\nif no {\n    hits.push(no);\n}\nif flag {\n    hits.push(flag);\n}\nassert (hits[0] == no) \"first \" + [hits[0]];\nassert (hits[1] == flag) \"second \" + [hits[1]];\nassert (len(hits) == 2) \"hits\";\n",
+        );
+    }
+
+    #[test]
+    fn len_compares_in_conditions() {
+        run_commands(
+            "[commands]\nl = [a, b, c];\nn = 3;\nhits = [];\nassert (len(l) == 3) \"len\";\nassert (n == len(l)) \"n\";\nif (len(l) != 2) {\n    hits.push(if);\n}\nwhen len(l) == [n] {\n    hits.push(when);\n}\nfor i in 0..len(l) {\n    if (len(hits) == [i]) {\n        hits.push([i]);\n    }\n}\nassert (len(hits) == 3) \"hits\";\n",
+        );
+
+        let result = try_run_commands("[commands]\ns = \"abc\";\nassert (len(s) == 3) \"s\";\n");
+        assert!(
+            matches!(result, Err(VariableAccessError::NotAList)),
+            "{:?}",
+            result.map(|_| ())
+        );
+    }
+
+    #[test]
+    fn unset_target_leaves_a_dangling_ref() {
+        for source in [
+            "[commands]\nx = \"hello\";\nr = ref x;\nunset x;\nprint(r);\n",
+            "[commands]\nx = [a, b];\nr = ref x[1];\nunset x;\nspawn echo [r];\n",
+            "[commands]\nx = hello;\nl = [a, ref x];\nunset x;\nspawn echo ...l;\n",
+        ] {
+            let result = try_run_commands(source).map(|_| ());
+            assert!(
+                matches!(result, Err(VariableAccessError::DanglingRef(_))),
+                "{result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn timeout_fires_close_to_its_deadline() {
+        for source in [
+            "[commands]\nsleep 130;\n",
+            "[commands]\ntimeout 130 {\n    sleep 1000;\n}\n",
+        ] {
+            let start = Instant::now();
+            try_run_commands_with(source, |test_bed| {
+                test_bed.poll_interval = Duration::from_secs(1);
+            })
+            .unwrap();
+            let elapsed = start.elapsed();
+
+            // A whole poll interval late before sleeps were cut short at the deadline, the
+            // bound leaves room for a loaded machine
+            assert!(elapsed >= Duration::from_millis(130), "{elapsed:?}");
+            assert!(elapsed < Duration::from_millis(130 + 90), "{elapsed:?}");
+        }
+    }
+
+    #[test]
+    fn progress_output_errors_are_returned() {
+        let dir = TempDir::new("missing");
+        let path = dir.join("missing/progress.txt");
+        assert!(ProgressOutput::open(path.to_str().unwrap(), false).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn closed_progress_stream_is_an_error() {
+        let (stream, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+        drop(reader);
+
+        let mut output = ProgressOutput::Stream(Box::new(stream));
+        assert!(output.write(&[], false, false).is_err());
+    }
+
+    #[test]
+    fn counter_interpolates_its_value_mid_loop() {
+        run_commands(
+            "[commands]\nhit = none;\nfor i in -2..3 {\n    name = run_ + [i];\n    if (i == -1) {\n        hit := [name];\n    }\n}\nassert (hit == run_-1) \"interpolated as \" + [hit];\n",
+        );
     }
 }
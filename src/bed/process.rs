@@ -1,22 +1,34 @@
 use std::{
-    fs::OpenOptions,
+    collections::VecDeque,
+    fs::{File, OpenOptions},
     io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
     path::{Path, PathBuf},
-    process::{Child, Command, Stdio},
+    process::{Child, ChildStdout, Command, ExitStatus, Stdio},
     sync::{
         atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError},
         Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use console::Term;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use regex::Regex;
 
 use crate::program::Shutdown;
 
-use super::{commands::OutputMap, SLEEP_TIME};
+use super::{commands::OutputMap, REFRESH_HZ, SLEEP_TIME};
 
+/// How long a filter can take to fail before the output is passed to it, a filter that exits
+/// unsuccessfully within it is replaced by unfiltered output
+const FILTER_STARTUP: Duration = Duration::from_millis(50);
+
+/// Width assumed for process bars when the terminal reports none or fewer than `MIN_TERM_COLS`
+const FALLBACK_TERM_COLS: usize = 80;
+const MIN_TERM_COLS: usize = 20;
+
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum ProcessState {
     Running,
@@ -24,23 +36,100 @@ pub enum ProcessState {
     Error(io::Error),
     Failed(Option<i32>),
     Finished,
+    /// Sent `SIGTERM` by a `terminate` command
+    Terminated,
 }
 
-#[derive(Clone, Copy)]
+/// Resources used by a process and its waited for children, only collected on Unix
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResourceUsage {
+    pub peak_rss_kib: u64,
+    pub user: Duration,
+    pub system: Duration,
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "user {:.2}s, sys {:.2}s, peak {:.1} MiB",
+            self.user.as_secs_f64(),
+            self.system.as_secs_f64(),
+            self.peak_rss_kib as f64 / 1024.0
+        )
+    }
+}
+
+#[cfg(unix)]
+impl From<libc::rusage> for ResourceUsage {
+    fn from(value: libc::rusage) -> Self {
+        let duration = |time: libc::timeval| {
+            Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+        };
+
+        // Reported in bytes on macOS and KiB everywhere else
+        let peak_rss_kib = match cfg!(target_os = "macos") {
+            true => value.ru_maxrss as u64 / 1024,
+            false => value.ru_maxrss as u64,
+        };
+
+        Self {
+            peak_rss_kib,
+            user: duration(value.ru_utime),
+            system: duration(value.ru_stime),
+        }
+    }
+}
+
+/// Like `Child::try_wait` but reaps the process with `wait4` to collect its resource usage.
+/// The `Child` isn't told, so it must not be waited on or killed afterwards.
+#[cfg(unix)]
+fn try_wait_with_usage(
+    child: &mut Child,
+) -> io::Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let mut status = 0;
+    // SAFETY: `rusage` is plain old data and `wait4` only writes into the pointers given
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let pid = child.id() as libc::pid_t;
+
+    match unsafe { libc::wait4(pid, &mut status, libc::WNOHANG, &mut usage) } {
+        0 => Ok(None),
+        -1 => match io::Error::last_os_error() {
+            e if e.kind() == ErrorKind::Interrupted => Ok(None),
+            e => Err(e),
+        },
+        _ => Ok(Some((ExitStatus::from_raw(status), Some(usage.into())))),
+    }
+}
+
+#[cfg(not(unix))]
+fn try_wait_with_usage(
+    child: &mut Child,
+) -> io::Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+    Ok(child.try_wait()?.map(|status| (status, None)))
+}
+
+#[derive(Clone, Copy, Default)]
 struct BarUsage {
     truncated: bool,
     prefix: usize,
     message: usize,
 }
 
-impl Default for BarUsage {
-    fn default() -> Self {
-        Self {
-            truncated: false,
-            prefix: Default::default(),
-            message: Default::default(),
-        }
-    }
+/// When the output line was last shown and the newest line dropped since then
+#[derive(Default)]
+struct LastMessage {
+    shown: Option<Instant>,
+    pending: Option<String>,
+}
+
+/// Where a new process bar is inserted into the `MultiProgress`
+#[derive(Clone, Copy)]
+pub enum BarPosition<'a> {
+    FromBack(usize),
+    After(&'a ProgressBar),
 }
 
 #[derive(Clone)]
@@ -50,19 +139,33 @@ pub struct ProcessBar {
     ident: String,
     stdout: Arc<AtomicBool>,
     stderr: Arc<AtomicBool>,
+    /// Set once a `limited(..)` output reached its size and started discarding
+    stdout_full: Arc<AtomicBool>,
+    stderr_full: Arc<AtomicBool>,
     status: Arc<Mutex<ProcessState>>,
     term: Term,
+    /// See `ProcessBar::set_message`
+    last_message: Arc<Mutex<LastMessage>>,
+    message_interval: Duration,
 }
 
 impl ProcessBar {
-    pub fn new(idx: usize, multibar: &MultiProgress, ident: String) -> Self {
+    pub fn new(
+        position: BarPosition,
+        multibar: &MultiProgress,
+        ident: String,
+        refresh_hz: u8,
+    ) -> Self {
         let bar = ProgressBar::new_spinner();
         bar.set_style(
             ProgressStyle::default_spinner()
                 .template("{spinner} {prefix:.bold.dim} {wide_msg}")
                 .unwrap(),
         );
-        let bar = multibar.insert_from_back(idx, bar);
+        let bar = match position {
+            BarPosition::FromBack(idx) => multibar.insert_from_back(idx, bar),
+            BarPosition::After(after) => multibar.insert_after(after, bar),
+        };
 
         let output = Self {
             bar,
@@ -71,7 +174,11 @@ impl ProcessBar {
             ident,
             stdout: Arc::new(AtomicBool::new(false)),
             stderr: Arc::new(AtomicBool::new(false)),
+            stdout_full: Arc::new(AtomicBool::new(false)),
+            stderr_full: Arc::new(AtomicBool::new(false)),
             term: Term::stdout(),
+            last_message: Arc::new(Mutex::new(LastMessage::default())),
+            message_interval: Duration::from_secs(1) / refresh_hz.max(1) as u32,
         };
         let available = output.term_cols();
         let prefix = output.prepare_prefix();
@@ -99,23 +206,34 @@ impl ProcessBar {
         self.stderr.store(value, Ordering::Release);
     }
 
-    pub fn prepare_prefix(&self) -> String {
-        let stdout = self.stdout.load(Ordering::Acquire);
-        let stderr = self.stderr.load(Ordering::Acquire);
-
-        let mut prefix = String::new();
-        if stdout {
-            prefix.push_str("!stdout");
+    /// Marks the bar once the stream's `limited(..)` file is full and shows the mark right away
+    fn set_full(&self, stderr: bool) {
+        match stderr {
+            true => self.stderr_full.store(true, Ordering::Release),
+            false => self.stdout_full.store(true, Ordering::Release),
         }
 
-        if stderr {
-            if stdout {
-                prefix.push_str(" ");
-            }
-            prefix.push_str("!stderr");
-        }
+        let available = self.term_cols();
+        let prefix = self.prepare_prefix();
+        let mut usage = self.usage.lock().unwrap();
+        self.update_prefix(available, prefix, &mut usage);
+    }
 
-        if stdout || stderr {
+    pub fn prepare_prefix(&self) -> String {
+        let marks = [
+            (&self.stdout, "!stdout"),
+            (&self.stderr, "!stderr"),
+            (&self.stdout_full, "stdout full"),
+            (&self.stderr_full, "stderr full"),
+        ];
+        let marks: Vec<_> = marks
+            .into_iter()
+            .filter(|(set, _)| set.load(Ordering::Acquire))
+            .map(|(_, mark)| mark)
+            .collect();
+
+        let mut prefix = marks.join(" ");
+        if !marks.is_empty() {
             prefix.push_str(": ");
         }
 
@@ -124,10 +242,15 @@ impl ProcessBar {
         prefix
     }
 
+    /// Columns left for the prefix and message, assumes `FALLBACK_TERM_COLS` if the terminal
+    /// size can't be read or is too narrow to be real, such as on CI
     fn term_cols(&self) -> usize {
         let extra = self.extra_space();
-        let (_, cols) = self.term.size();
-        (cols as usize).max(extra) - extra
+        let cols = match self.term.size_checked() {
+            Some((_, cols)) if cols as usize >= MIN_TERM_COLS => cols as usize,
+            _ => FALLBACK_TERM_COLS,
+        };
+        cols - extra
     }
 
     fn update_prefix(&self, available: usize, mut prefix: String, usage: &mut BarUsage) {
@@ -167,6 +290,9 @@ impl ProcessBar {
         }
     }
 
+    /// Shows an output line on the bar. Lines coming faster than the bars are redrawn are held
+    /// back since they would be replaced before being seen, only the newest is kept and shown
+    /// by `show_pending` if no other line follows it.
     pub fn set_message(&self, msg: String) {
         {
             let status = &*self.status.lock().unwrap();
@@ -177,6 +303,50 @@ impl ProcessBar {
             }
         }
 
+        {
+            let mut last = self.last_message.lock().unwrap();
+            let now = Instant::now();
+
+            if last
+                .shown
+                .is_some_and(|shown| now.duration_since(shown) < self.message_interval)
+            {
+                last.pending = Some(msg);
+                return;
+            }
+            last.shown = Some(now);
+            last.pending = None;
+        }
+
+        self.show_message(msg);
+    }
+
+    /// Shows the last line held back by `set_message`, called while polling the process so a
+    /// process that goes quiet still ends up showing its latest line
+    pub fn show_pending(&self) {
+        let msg = {
+            let mut last = self.last_message.lock().unwrap();
+            let now = Instant::now();
+
+            if last
+                .shown
+                .is_some_and(|shown| now.duration_since(shown) < self.message_interval)
+            {
+                return;
+            }
+            let Some(msg) = last.pending.take() else {
+                return;
+            };
+            last.shown = Some(now);
+            msg
+        };
+
+        if let ProcessState::Running = *self.status.lock().unwrap() {
+            self.show_message(msg);
+        }
+    }
+
+    fn show_message(&self, msg: String) {
         let available = self.term_cols();
 
         {
@@ -188,13 +358,27 @@ impl ProcessBar {
         self.bar.inc(1);
     }
 
+    pub fn is_failed(&self) -> bool {
+        matches!(
+            *self.status.lock().unwrap(),
+            ProcessState::Failed(_) | ProcessState::Error(_)
+        )
+    }
+
     pub fn set_state(&self, state: ProcessState) {
-        match state {
-            ProcessState::Running => return,
-            _ => {}
+        self.finish(state, None);
+    }
+
+    /// Sets the final state, followed by the resource usage if it was collected
+    pub fn finish(&self, state: ProcessState, resources: Option<&ResourceUsage>) {
+        if let ProcessState::Running = state {
+            return;
         }
 
-        let message = format!("{:?}", state);
+        let message = match resources {
+            Some(resources) => format!("{state:?} ({resources})"),
+            None => format!("{state:?}"),
+        };
         *self.status.lock().unwrap() = state;
         let available = self.term_cols();
 
@@ -209,10 +393,41 @@ impl ProcessBar {
 
 pub struct ProcessInfo {
     pub command: String,
+    /// Set with `spawn name(..)`, several processes may share a name
+    pub name: Option<String>,
+    /// Loop variables shown before the command on the bar, see `--show-iters`
+    pub iteration: Option<String>,
     pub args: Vec<String>,
     pub stdout: OutputMap<PathBuf>,
     pub stderr: OutputMap<PathBuf>,
     pub working_dir: Option<PathBuf>,
+    /// Shell command stdout is piped through, its output replaces the process's stdout. If it
+    /// can't be started or fails straight away the output is captured unfiltered.
+    pub filter: Option<String>,
+    /// Only lines matching it are shown on the bar, as the first capture group or the whole
+    /// match if there is none. Applies to both streams.
+    pub progress: Option<Regex>,
+    /// Scheduling priority set in the child before it runs, higher is lower priority. Failing to
+    /// set it fails the spawn. Ignored on platforms other than Unix.
+    pub nice: Option<i32>,
+    /// Detached processes outlive `wait_all` and program resets, they are only killed once
+    /// every program has finished
+    pub detached: bool,
+    /// Flush output files after every line instead of buffering them
+    pub flush_lines: bool,
+    /// Number of stderr lines kept to print if the process fails, `0` keeps none. Only lines
+    /// shown on the bar are kept, see `--stderr-tail`
+    pub stderr_tail: usize,
+    /// Set on top of the inherited environment, e.g. from `--env-file`
+    pub env: Vec<(String, String)>,
+    /// Start the process with only `env` instead of inheriting test-bed's environment. The
+    /// filter still inherits it.
+    pub clear_env: bool,
+    /// Output line updates per second on the bar, see `ProcessBar::set_message`
+    pub refresh_hz: u8,
+    /// Earliest deadline of the `timeout` blocks it was spawned in, the process is timed out
+    /// by the next poll after it passes
+    pub deadline: Option<Instant>,
     pub running: Option<ProcessStatus>,
 }
 
@@ -220,14 +435,30 @@ impl ProcessInfo {
     pub fn new(command: String) -> Self {
         Self {
             command,
+            name: None,
+            iteration: None,
             args: vec![],
             working_dir: None,
+            filter: None,
+            progress: None,
+            nice: None,
+            detached: false,
+            flush_lines: false,
+            stderr_tail: 0,
+            refresh_hz: REFRESH_HZ,
+            env: vec![],
+            clear_env: false,
+            deadline: None,
             stdout: OutputMap::Print,
             stderr: OutputMap::Print,
             running: None,
         }
     }
 
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+
     pub fn set_stdout(&mut self, out: OutputMap<PathBuf>) -> &mut Self {
         self.stdout = out;
         self
@@ -243,20 +474,48 @@ impl ProcessInfo {
         self
     }
 
-    pub fn run(&mut self, idx: usize, multibar: &MultiProgress) -> io::Result<()> {
+    pub fn run(&mut self, position: BarPosition, multibar: &MultiProgress) -> io::Result<()> {
         let pat = ['/', '\\'];
 
-        let mut ident = self.command.split(pat).last().unwrap_or("?").to_string();
+        let mut ident = self
+            .command
+            .split(pat)
+            .next_back()
+            .unwrap_or("?")
+            .to_string();
+
+        if let Some(iteration) = &self.iteration {
+            ident.insert_str(0, &format!("{iteration} "));
+        }
 
         for arg in self.args.iter() {
             ident.push(' ');
             ident.push_str(arg);
         }
 
-        let bar = ProcessBar::new(idx, multibar, ident);
+        let bar = ProcessBar::new(position, multibar, ident, self.refresh_hz);
+
+        let filter = match &self.filter {
+            Some(filter) => match self.spawn_filter(filter) {
+                Ok(child) => Some(child),
+                Err(e) => {
+                    multibar
+                        .println(format!(
+                            "Failed to spawn filter `{filter}`, showing unfiltered output: {e}"
+                        ))
+                        .ok();
+                    None
+                }
+            },
+            None => None,
+        };
 
         let mut process = Command::new(&self.command);
         process.args(self.args.iter());
+        if self.clear_env {
+            process.env_clear();
+        }
+        process.envs(self.env.iter().map(|(key, value)| (key, value)));
         process.stdout(Stdio::piped());
         process.stderr(Stdio::piped());
 
@@ -264,42 +523,77 @@ impl ProcessInfo {
             process.current_dir(dir);
         }
 
-        let mut spawned = process.spawn()?;
-        let stdout = spawned.stdout.take().unwrap();
+        #[cfg(unix)]
+        if let Some(nice) = self.nice {
+            use std::os::unix::process::CommandExt;
+
+            // Only calls the async signal safe `setpriority` between fork and exec
+            unsafe {
+                process.pre_exec(
+                    move || match libc::setpriority(libc::PRIO_PROCESS, 0, nice) {
+                        0 => Ok(()),
+                        _ => Err(io::Error::last_os_error()),
+                    },
+                );
+            }
+        }
 
-        match &self.stdout {
-            OutputMap::Print => spawn_progress_writer(stdout, bar.clone()),
-            OutputMap::Create(file) => {
-                if let Err(_) = spawn_file_writer(stdout, file, false) {
-                    bar.set_stdout(true);
+        let mut spawned = match process.spawn() {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                if let Some(mut filter) = filter {
+                    filter.kill().ok();
+                    filter.wait().ok();
                 }
+                return Err(e);
             }
-            OutputMap::Append(file) => {
-                if let Err(_) = spawn_file_writer(stdout, file, true) {
+        };
+
+        // Only tag stderr when both streams share the bar
+        let stderr_tag = match self.stdout.shows_on_bar() && self.stderr.shows_on_bar() {
+            true => Some(STDERR_TAG),
+            false => None,
+        };
+
+        let stdout = spawned.stdout.take().unwrap();
+        let progress = ProgressSink::new(bar.clone(), None).with_regex(self.progress.clone());
+        match (filter, &self.filter) {
+            (Some(filter), Some(command)) => spawn_filtered_writer(
+                stdout,
+                filter,
+                command.clone(),
+                self.stdout.clone(),
+                progress,
+                self.flush_lines,
+                multibar.clone(),
+            ),
+            _ => {
+                let result =
+                    spawn_output_writer(stdout, &self.stdout, progress, false, self.flush_lines);
+                if result.is_err() {
                     bar.set_stdout(true);
                 }
             }
         }
 
+        let tail = match self.stderr_tail > 0 && self.stderr.shows_on_bar() {
+            true => Some(StderrTail::new(self.stderr_tail)),
+            false => None,
+        };
+
         let stderr = spawned.stderr.take().unwrap();
-        match &self.stderr {
-            OutputMap::Print => spawn_progress_writer(stderr, bar.clone()),
-            OutputMap::Create(file) => {
-                if let Err(_) = spawn_file_writer(stderr, file, false) {
-                    bar.set_stderr(true);
-                }
-            }
-            OutputMap::Append(file) => {
-                if let Err(_) = spawn_file_writer(stderr, file, true) {
-                    bar.set_stderr(true);
-                }
-            }
+        let progress = ProgressSink::new(bar.clone(), stderr_tag)
+            .with_tail(tail.clone())
+            .with_regex(self.progress.clone());
+        if spawn_output_writer(stderr, &self.stderr, progress, true, self.flush_lines).is_err() {
+            bar.set_stderr(true);
         }
 
         let status = ProcessStatus {
-            pid: spawned.id(),
             process: spawned,
             bar,
+            resources: None,
+            tail,
         };
 
         self.running = Some(status);
@@ -307,21 +601,102 @@ impl ProcessInfo {
         Ok(())
     }
 
+    /// Runs `filter` through the shell so it can use its own pipes and quoting
+    fn spawn_filter(&self, filter: &str) -> io::Result<Child> {
+        let (shell, flag) = match cfg!(windows) {
+            true => ("cmd", "/C"),
+            false => ("sh", "-c"),
+        };
+
+        let mut command = Command::new(shell);
+        command.args([flag, filter]);
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+        command.stdin(Stdio::piped());
+        command.stdout(Stdio::piped());
+        command.stderr(Stdio::piped());
+
+        if let Some(dir) = &self.working_dir {
+            command.current_dir(dir);
+        }
+
+        command.spawn()
+    }
+
+    /// Returns true once the process has exited unsuccessfully or could not be waited on
+    pub fn is_failed(&self) -> bool {
+        match &self.running {
+            Some(process) => process.bar.is_failed(),
+            None => false,
+        }
+    }
+
     pub fn kill(&mut self) {
         if let Some(mut value) = self.running.take() {
             value.kill()
         }
     }
 
+    /// Asks the process to stop with `SIGTERM` without waiting for it, it keeps running until it
+    /// exits on its own or is killed. Without signals it is killed straight away.
+    pub fn terminate(&mut self) {
+        let Some(running) = &mut self.running else {
+            return;
+        };
+
+        #[cfg(unix)]
+        {
+            // SAFETY: the child hasn't been waited on so its pid can't have been reused
+            let result = unsafe { libc::kill(running.process.id() as libc::pid_t, libc::SIGTERM) };
+
+            match result {
+                0 => running.bar.set_state(ProcessState::Terminated),
+                _ => running.kill(),
+            }
+        }
+
+        #[cfg(not(unix))]
+        running.kill();
+    }
+
+    /// Collects the exit status of a terminated process without touching its bar, returns
+    /// true once it has exited
+    pub fn reap(&mut self) -> bool {
+        match &mut self.running {
+            Some(running) => !matches!(running.process.try_wait(), Ok(None)),
+            None => true,
+        }
+    }
+
+    /// Kills the process, marking it as failed instead of killed
+    pub fn time_out(&mut self) {
+        if let Some(mut value) = self.running.take() {
+            value.process.kill().ok();
+            value.bar.set_state(ProcessState::Failed(None));
+        }
+    }
+
+    /// The command followed by its arguments
+    pub fn describe(&self) -> String {
+        let mut description = self.command.clone();
+
+        for arg in self.args.iter() {
+            description.push(' ');
+            description.push_str(arg);
+        }
+
+        description
+    }
+
     pub fn try_wait(&mut self) -> bool {
         let process = match self.running.as_mut() {
             Some(process) => process,
             None => return true,
         };
 
+        process.bar.show_pending();
         process.bar.inc(1);
-        let status = match process.process.try_wait() {
-            Ok(Some(status)) => status,
+        let (status, resources) = match try_wait_with_usage(&mut process.process) {
+            Ok(Some(exit)) => exit,
             Ok(None) => return false,
             Err(e) => {
                 process.bar.set_state(ProcessState::Error(e));
@@ -329,14 +704,34 @@ impl ProcessInfo {
             }
         };
 
-        match status.success() {
-            true => process.bar.set_state(ProcessState::Finished),
-            false => process.bar.set_state(ProcessState::Failed(status.code())),
-        }
+        process.resources = resources;
+        let state = match status.success() {
+            true => ProcessState::Finished,
+            false => ProcessState::Failed(status.code()),
+        };
+        process.bar.finish(state, resources.as_ref());
 
         true
     }
 
+    /// Last lines the process wrote to stderr, empty unless `stderr_tail` is set and stderr is
+    /// shown on the bar
+    pub fn stderr_tail(&self) -> Vec<String> {
+        match self
+            .running
+            .as_ref()
+            .and_then(|status| status.tail.as_ref())
+        {
+            Some(tail) => tail.lines(),
+            None => vec![],
+        }
+    }
+
+    /// Resource usage of a process that has exited, `None` if it's unavailable on this platform
+    pub fn resources(&self) -> Option<&ResourceUsage> {
+        self.running.as_ref()?.resources.as_ref()
+    }
+
     pub fn _wait_or_terminate(&mut self, wait: Option<Duration>, shutdown: &Shutdown) {
         let mut process = match self.running.take() {
             Some(process) => process,
@@ -383,8 +778,10 @@ impl ProcessInfo {
 
 pub struct ProcessStatus {
     pub process: Child,
-    pub pid: u32,
     pub bar: ProcessBar,
+    /// Set once the process exits, see `ProcessInfo::try_wait`
+    pub resources: Option<ResourceUsage>,
+    pub tail: Option<StderrTail>,
 }
 
 impl ProcessStatus {
@@ -396,78 +793,591 @@ impl ProcessStatus {
     }
 }
 
-fn spawn_file_writer<R: Read + Send, P>(reader: R, path: P, append: bool) -> std::io::Result<()>
-where
-    R: Read + Send + 'static,
-    P: AsRef<Path>,
-{
-    let create_parent = |path: &Path| -> io::Result<()> {
-        let path: &Path = path.as_ref();
+/// Buffered output is flushed once this many bytes are pending
+const FLUSH_BYTES: usize = 64 * 1024;
+/// or once this long has passed since the last flush, even if the process has gone quiet
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+struct FileSink {
+    writer: BufWriter<File>,
+    path: String,
+    bytes: Vec<u8>,
+    flush_lines: bool,
+    last_flush: Instant,
+    rotation: Option<Rotation>,
+    limit: Option<Limit>,
+}
+
+/// Size of a `limited(..)` output, `written` counts the bytes in the file
+struct Limit {
+    max_bytes: u64,
+    written: u64,
+    /// Set once output was dropped, the bar is only marked the first time
+    discarding: bool,
+    bar: ProcessBar,
+    stderr: bool,
+}
+
+/// Size limit of a `rotate(..)` output, `written` counts the bytes in the current file
+struct Rotation {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: Option<usize>,
+    written: u64,
+}
+
+impl Rotation {
+    /// `base.log` becomes `base.1.log`, `base` becomes `base.1`
+    fn numbered(&self, index: usize) -> PathBuf {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{stem}.{index}.{}", ext.to_string_lossy()),
+            None => format!("{stem}.{index}"),
+        };
+
+        self.path.with_file_name(name)
+    }
+
+    /// Moves every rotated file up by one and the current file to `base.1.ext`, deleting
+    /// the ones past `keep`
+    fn shift(&self) -> io::Result<()> {
+        let mut last = 1;
+        while self.numbered(last).exists() {
+            last += 1;
+        }
+
+        for index in (1..last).rev() {
+            let from = self.numbered(index);
+
+            match self.keep {
+                Some(keep) if index >= keep => std::fs::remove_file(from)?,
+                _ => std::fs::rename(from, self.numbered(index + 1))?,
+            }
+        }
+
+        match self.keep {
+            Some(0) => std::fs::remove_file(&self.path),
+            _ => std::fs::rename(&self.path, self.numbered(1)),
+        }
+    }
+}
+
+fn open_file(path: &Path, append: bool) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    match append {
+        true => OpenOptions::new().append(append).create(true).open(path),
+        false => OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path),
+    }
+}
+
+impl FileSink {
+    fn open(path: &Path, append: bool, flush_lines: bool) -> io::Result<Self> {
+        let file = open_file(path, append)?;
+
+        Ok(Self {
+            writer: BufWriter::with_capacity(FLUSH_BYTES, file),
+            path: path.as_os_str().to_string_lossy().to_string(),
+            bytes: vec![],
+            flush_lines,
+            last_flush: Instant::now(),
+            rotation: None,
+            limit: None,
+        })
+    }
+
+    fn limited(
+        path: &Path,
+        max_bytes: u64,
+        bar: ProcessBar,
+        stderr: bool,
+        flush_lines: bool,
+    ) -> io::Result<Self> {
+        let mut sink = Self::open(path, false, flush_lines)?;
+        sink.limit = Some(Limit {
+            max_bytes,
+            written: 0,
+            discarding: false,
+            bar,
+            stderr,
+        });
+
+        Ok(sink)
+    }
+
+    fn rotating(
+        path: &Path,
+        max_bytes: u64,
+        keep: Option<usize>,
+        flush_lines: bool,
+    ) -> io::Result<Self> {
+        let mut sink = Self::open(path, false, flush_lines)?;
+        sink.rotation = Some(Rotation {
+            path: path.to_path_buf(),
+            max_bytes,
+            keep,
+            written: 0,
+        });
+
+        Ok(sink)
+    }
+
+    fn write(&mut self, available: &[u8]) -> io::Result<()> {
+        let mut bytes = std::mem::take(&mut self.bytes);
+        bytes.clear();
+        bytes.extend_from_slice(available);
+        bytes.retain(|value| *value != b'\r');
+
+        let result = match self.limit.is_some() {
+            true => self.write_limited(&bytes),
+            false => self.write_rotating(&bytes),
+        };
+        self.bytes = bytes;
+
+        if let Err(e) = result {
+            println!("Write Failed {}: {}", self.path, e);
+            return Err(e);
+        }
+
+        let flush = match self.flush_lines {
+            true => self.bytes.contains(&b'\n'),
+            false => self.last_flush.elapsed() >= FLUSH_INTERVAL,
+        };
+
+        if flush {
+            self.writer.flush().ok();
+            self.last_flush = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    /// Writes `bytes`, starting a new file whenever the current one would grow past the
+    /// rotation limit. Files are cut after the last full line that fits, or mid line if a
+    /// single line is larger than the limit.
+    fn write_rotating(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        let Some(rotation) = &mut self.rotation else {
+            // Full buffers are written out by the `BufWriter` itself
+            return self.writer.write_all(bytes);
+        };
+
+        while rotation.written + bytes.len() as u64 > rotation.max_bytes {
+            let room = (rotation.max_bytes - rotation.written) as usize;
+            let cut = match bytes[..room].iter().rposition(|value| *value == b'\n') {
+                Some(newline) => newline + 1,
+                None if rotation.written == 0 => room,
+                None => 0,
+            };
 
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
+            self.writer.write_all(&bytes[..cut])?;
+            self.writer.flush()?;
+            bytes = &bytes[cut..];
+
+            rotation.shift()?;
+            self.writer = BufWriter::with_capacity(FLUSH_BYTES, open_file(&rotation.path, false)?);
+            rotation.written = 0;
+        }
+
+        rotation.written += bytes.len() as u64;
+        self.writer.write_all(bytes)
+    }
+
+    /// Writes `bytes` until the file holds the limit and drops the rest. Keeps returning `Ok`
+    /// once full so the writer carries on draining the pipe.
+    fn write_limited(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let Some(limit) = &mut self.limit else {
+            return self.write_rotating(bytes);
+        };
+
+        let room = limit.max_bytes - limit.written;
+        let cut = bytes.len().min(room.try_into().unwrap_or(usize::MAX));
+        let full = cut < bytes.len() && !limit.discarding;
+        limit.written += cut as u64;
+
+        if full {
+            limit.discarding = true;
+            limit.bar.set_full(limit.stderr);
+        }
+
+        self.writer.write_all(&bytes[..cut])?;
+        if full {
+            self.writer.flush()?;
         }
 
         Ok(())
-    };
+    }
+
+    /// Flushes output left in the buffer, called when no write arrived for `FLUSH_INTERVAL`
+    fn flush_idle(&mut self) {
+        if self.writer.buffer().is_empty() {
+            return;
+        }
+
+        self.writer.flush().ok();
+        self.last_flush = Instant::now();
+    }
+
+    fn finish(mut self) {
+        if let Err(e) = self.writer.flush() {
+            println!("Write Failed {}: {}", self.path, e);
+        }
+    }
+}
+
+/// Prefixed to stderr messages when stdout is also shown on the bar
+const STDERR_TAG: &str = "[err] ";
+
+/// Ring buffer of the last lines written to a stream, shared between the writer thread and
+/// the process
+#[derive(Clone)]
+pub struct StderrTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    /// Set by the writer thread once the stream is drained
+    closed: Arc<AtomicBool>,
+    size: usize,
+}
+
+impl StderrTail {
+    pub fn new(size: usize) -> Self {
+        Self {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(size))),
+            closed: Arc::new(AtomicBool::new(false)),
+            size,
+        }
+    }
+
+    fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+
+        if lines.len() == self.size {
+            lines.pop_front();
+        }
+
+        lines.push_back(line);
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+    }
+
+    /// Lines kept so far. A process can exit before its last output was read, so this waits
+    /// up to `SLEEP_TIME` for the stream to close, e.g. children holding it open aren't waited on.
+    pub fn lines(&self) -> Vec<String> {
+        let start = Instant::now();
+
+        while !self.closed.load(Ordering::Acquire) && start.elapsed() < SLEEP_TIME {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+struct ProgressSink {
+    bar: ProcessBar,
+    tag: Option<&'static str>,
+    bytes: Vec<u8>,
+    clear: bool,
+    tail: Option<StderrTail>,
+    /// See `ProcessInfo::progress`
+    regex: Option<Regex>,
+    /// Set once the current line was pushed to `tail`, so `\r\n` and blank lines don't
+    /// repeat it
+    tailed: bool,
+}
+
+impl ProgressSink {
+    fn new(bar: ProcessBar, tag: Option<&'static str>) -> Self {
+        Self {
+            bar,
+            tag,
+            bytes: vec![],
+            clear: false,
+            tail: None,
+            regex: None,
+            tailed: false,
+        }
+    }
+
+    fn with_regex(mut self, regex: Option<Regex>) -> Self {
+        self.regex = regex;
+        self
+    }
+
+    fn with_tail(mut self, tail: Option<StderrTail>) -> Self {
+        self.tail = tail;
+        self
+    }
+
+    fn write(&mut self, available: &[u8]) {
+        for &byte in available.iter() {
+            if byte == b'\n' {
+                self.push_tail();
+            }
+
+            if byte == b'\n' || byte == b'\r' {
+                self.clear = true;
+                continue;
+            }
+
+            if self.clear {
+                self.bytes.clear();
+                self.clear = false;
+                self.tailed = false;
+            }
+
+            self.bytes.push(byte);
+        }
+
+        let line = String::from_utf8_lossy(&self.bytes);
+
+        // Lines that don't match leave the last match on the bar
+        let value = match &self.regex {
+            Some(regex) => match regex.captures(&line) {
+                Some(captures) => {
+                    let value = captures.get(1).or_else(|| captures.get(0)).unwrap();
+                    value.as_str().to_string()
+                }
+                None => return,
+            },
+            None => line.into_owned(),
+        };
+
+        match self.tag {
+            Some(tag) => self.bar.set_message(format!("{tag}{value}")),
+            None => self.bar.set_message(value),
+        }
+    }
+
+    /// Keeps the current line in the tail, lines only overwritten with `\r` are skipped
+    fn push_tail(&mut self) {
+        let Some(tail) = &self.tail else {
+            return;
+        };
+
+        if self.tailed || self.bytes.is_empty() {
+            return;
+        }
+
+        tail.push(String::from_utf8_lossy(&self.bytes).into_owned());
+        self.tailed = true;
+    }
+
+    /// Keeps a last line that didn't end in a newline once the stream is drained
+    fn close_tail(&mut self) {
+        self.push_tail();
+
+        if let Some(tail) = &self.tail {
+            tail.close();
+        }
+    }
+}
+
+/// Spawns the thread that drains `reader` into the sink selected by `output`. Returns an error
+/// if the output file could not be opened, in which case a tee still writes to the bar.
+/// Passes `stdout` through `filter` on a thread of its own and writes what the filter prints to
+/// `output`. If the filter has already failed when the process first writes, e.g. because the
+/// command doesn't exist, the output is written unfiltered instead. The filter's stderr is
+/// printed above the bars.
+fn spawn_filtered_writer(
+    mut stdout: ChildStdout,
+    mut filter: Child,
+    command: String,
+    output: OutputMap<PathBuf>,
+    progress: ProgressSink,
+    flush_lines: bool,
+    multibar: MultiProgress,
+) {
+    if let Some(stderr) = filter.stderr.take() {
+        let multibar = multibar.clone();
+        let command = command.clone();
+
+        std::thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                multibar.println(format!("Filter `{command}`: {line}")).ok();
+            }
+        });
+    }
+
+    std::thread::spawn(move || {
+        let mut first = vec![0; 8 * 1024];
+        let read = loop {
+            match stdout.read(&mut first) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => break result.unwrap_or(0),
+            }
+        };
+        first.truncate(read);
+
+        let bar = progress.bar.clone();
+        let failed = wait_for_exit(&mut filter, FILTER_STARTUP).filter(|status| !status.success());
 
-    let path = path.as_ref();
-    create_parent(path)?;
-    let file = match append {
-        true => OpenOptions::new().append(append).create(true).open(path)?,
-        false => OpenOptions::new().write(true).create(true).open(path)?,
-    };
+        if let Some(status) = failed {
+            multibar
+                .println(format!(
+                    "Filter `{command}` failed with {status}, showing unfiltered output"
+                ))
+                .ok();
 
-    let mut writer = BufWriter::new(file);
-    let path = path.as_os_str().to_string_lossy().to_string();
+            let unfiltered = io::Cursor::new(first).chain(stdout);
+            if spawn_output_writer(unfiltered, &output, progress, false, flush_lines).is_err() {
+                bar.set_stdout(true);
+            }
+            return;
+        }
+
+        let mut stdin = filter.stdin.take().unwrap();
+        let filtered = filter.stdout.take().unwrap();
+        if spawn_output_writer(filtered, &output, progress, false, flush_lines).is_err() {
+            bar.set_stdout(true);
+        }
+
+        // Keeps reading once the filter is gone so the process doesn't block on a full pipe
+        let passed = stdin
+            .write_all(&first)
+            .and_then(|_| io::copy(&mut stdout, &mut stdin));
+        if passed.is_err() {
+            io::copy(&mut stdout, &mut io::sink()).ok();
+        }
+
+        // Closes the filter's stdin so it sees the end of the output, its exit code doesn't
+        // matter once it has started, e.g. `grep` fails when nothing matched
+        drop(stdin);
+        filter.wait().ok();
+    });
+}
+
+/// Polls `child` until it exits or `timeout` passes
+fn wait_for_exit(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let start = Instant::now();
+
+    loop {
+        match child.try_wait() {
+            Ok(None) if start.elapsed() < timeout => std::thread::sleep(Duration::from_millis(5)),
+            Ok(status) => return status,
+            Err(_) => return None,
+        }
+    }
+}
+
+fn spawn_output_writer<R>(
+    reader: R,
+    output: &OutputMap<PathBuf>,
+    progress: ProgressSink,
+    stderr: bool,
+    flush_lines: bool,
+) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+{
+    match output {
+        OutputMap::Print => spawn_progress_writer(reader, progress),
+        OutputMap::Create(file) => {
+            spawn_file_writer(reader, FileSink::open(file, false, flush_lines)?)
+        }
+        OutputMap::Append(file) => {
+            spawn_file_writer(reader, FileSink::open(file, true, flush_lines)?)
+        }
+        OutputMap::Rotate {
+            path,
+            max_bytes,
+            keep,
+        } => spawn_file_writer(
+            reader,
+            FileSink::rotating(path, *max_bytes, *keep, flush_lines)?,
+        ),
+        OutputMap::Limited { path, max_bytes } => {
+            let bar = progress.bar.clone();
+            let sink = FileSink::limited(path, *max_bytes, bar, stderr, flush_lines)?;
+            spawn_file_writer(reader, sink)
+        }
+        OutputMap::Tee(file) => match FileSink::open(file, false, flush_lines) {
+            Ok(sink) => spawn_tee_writer(reader, sink, progress),
+            Err(e) => {
+                spawn_progress_writer(reader, progress);
+                return Err(e);
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Reads `reader` on a thread of its own and sends on each chunk, so the thread writing them
+/// can wake up to flush while the process is quiet. Stops once the receiver is dropped.
+fn spawn_chunk_reader<R>(reader: R) -> Receiver<io::Result<Vec<u8>>>
+where
+    R: Read + Send + 'static,
+{
+    let (send, recv) = channel();
 
     std::thread::spawn(move || {
         let mut reader = BufReader::new(reader);
-        let mut bytes = vec![];
 
         loop {
             let available = match reader.fill_buf() {
-                Ok(available) => available,
-                Err(_) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    send.send(Err(e)).ok();
+                    break;
+                }
             };
 
-            bytes.clear();
-            bytes.extend_from_slice(available);
-            bytes.retain(|value| *value != b'\r');
-            let consumed = available.len();
-            reader.consume(consumed);
+            let used = available.len();
 
-            if consumed == 0 {
+            if used == 0 || send.send(Ok(available.to_vec())).is_err() {
                 break;
             }
+            reader.consume(used);
+        }
+    });
 
-            if let Err(e) = writer.write_all(&bytes) {
-                println!("Write Failed {}: {}", path, e);
-                break;
+    recv
+}
+
+fn spawn_file_writer<R>(reader: R, mut sink: FileSink)
+where
+    R: Read + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let chunks = spawn_chunk_reader(reader);
+
+        loop {
+            match chunks.recv_timeout(FLUSH_INTERVAL) {
+                Ok(Ok(chunk)) => {
+                    if sink.write(&chunk).is_err() {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => sink.flush_idle(),
+                Ok(Err(_)) | Err(RecvTimeoutError::Disconnected) => break,
             }
-            writer.flush().ok();
         }
-    });
 
-    Ok(())
+        sink.finish();
+    });
 }
 
-fn spawn_progress_writer<R: Read + Send>(reader: R, bar: ProcessBar)
+fn spawn_progress_writer<R>(reader: R, mut sink: ProgressSink)
 where
     R: Read + Send + 'static,
 {
     std::thread::spawn(move || {
         let mut reader = BufReader::new(reader);
-        let mut bytes = vec![];
-        // let mut output = String::new();
-        let mut clear = false;
 
         loop {
             let available = match reader.fill_buf() {
                 Ok(n) => n,
                 Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
                 Err(e) => {
-                    bar.set_message(format!("Error: {e}"));
+                    sink.bar.set_message(format!("Error: {e}"));
                     break;
                 }
             };
@@ -478,23 +1388,117 @@ where
                 break;
             }
 
-            for &byte in available.iter() {
-                if byte == b'\n' || byte == b'\r' {
-                    clear = true;
+            sink.write(available);
+            reader.consume(used);
+        }
+
+        sink.close_tail();
+    });
+}
+
+/// Fans a single stream out to both a file and the progress bar, since a child's pipe can only
+/// be read once.
+fn spawn_tee_writer<R>(reader: R, file: FileSink, mut progress: ProgressSink)
+where
+    R: Read + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let chunks = spawn_chunk_reader(reader);
+        let mut file = Some(file);
+
+        loop {
+            let chunk = match chunks.recv_timeout(FLUSH_INTERVAL) {
+                Ok(Ok(chunk)) => chunk,
+                Ok(Err(e)) => {
+                    progress.bar.set_message(format!("Error: {e}"));
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(sink) = &mut file {
+                        sink.flush_idle();
+                    }
                     continue;
                 }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
 
-                if clear {
-                    bytes.clear();
-                    clear = false;
+            // Keep showing output on the bar even if the file stops accepting writes
+            if let Some(sink) = &mut file {
+                if sink.write(&chunk).is_err() {
+                    file = None;
                 }
-
-                bytes.push(byte);
             }
 
-            reader.consume(used);
-            let value = String::from_utf8_lossy(&bytes);
-            bar.set_message(value.to_string());
+            progress.write(&chunk);
+        }
+
+        progress.close_tail();
+
+        if let Some(sink) = file {
+            sink.finish();
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use indicatif::{MultiProgress, ProgressDrawTarget};
+
+    use super::{BarPosition, OutputMap, ProcessBar, ProcessInfo};
+    use crate::tests::{read_written, TempDir};
+
+    /// Runs `echo hello` through `filter` into a file and returns what was written to it
+    #[cfg(unix)]
+    fn filtered_echo(name: &str, filter: &str) -> String {
+        let dir = TempDir::new(name);
+        let path = dir.join("out.log");
+
+        let multibar = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let mut process = ProcessInfo::new("echo".into());
+        process.args = vec!["hello".into()];
+        process.filter = Some(filter.into());
+        process.set_stdout(OutputMap::Create(path.clone()));
+        process.run(BarPosition::FromBack(0), &multibar).unwrap();
+        while !process.try_wait() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        read_written(&path)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_is_passed_through_the_filter() {
+        assert_eq!(filtered_echo("filter", "tr a-z A-Z"), "HELLO\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn missing_filter_falls_back_to_unfiltered_output() {
+        assert_eq!(
+            filtered_echo("no-filter", "test-bed-missing-filter --flag"),
+            "hello\n"
+        );
+    }
+
+    #[test]
+    fn dropped_line_is_shown_on_the_next_poll() {
+        let multibar = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let bar = ProcessBar::new(BarPosition::FromBack(0), &multibar, "tool".into(), 20);
+
+        bar.set_message("first".into());
+        bar.set_message("second".into());
+        bar.set_message("last".into());
+        assert_eq!(bar.bar.message(), "first");
+
+        // Too soon after `first`, the line stays held back
+        bar.show_pending();
+        assert_eq!(bar.bar.message(), "first");
+
+        std::thread::sleep(Duration::from_millis(60));
+        bar.show_pending();
+        assert_eq!(bar.bar.message(), "last");
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+use super::ProcessCounts;
+
+/// Spinner above the other bars with what the test bed is doing and how many processes are
+/// running, have succeeded and have failed
+pub struct StatusBar {
+    phase: String,
+    bar: ProgressBar,
+}
+
+impl StatusBar {
+    pub fn new(multibar: &MultiProgress) -> Self {
+        let bar = ProgressBar::new_spinner();
+        bar.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner} {wide_msg}")
+                .unwrap(),
+        );
+        let bar = multibar.insert(0, bar);
+        // Keeps spinning while every process is quiet
+        bar.enable_steady_tick(Duration::from_millis(100));
+
+        let status = Self {
+            phase: String::from("Starting"),
+            bar,
+        };
+        status.update(&ProcessCounts::default(), 0);
+        status
+    }
+
+    pub fn set_phase(&mut self, phase: String) {
+        self.phase = phase;
+    }
+
+    pub fn update(&self, counts: &ProcessCounts, running: usize) {
+        self.bar.set_message(format!(
+            "{} : running {running} : succeeded {} : failed {}",
+            self.phase, counts.succeeded, counts.failed
+        ));
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
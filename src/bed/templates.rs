@@ -1,10 +1,14 @@
-use std::{collections::HashMap, fmt::Display, io::ErrorKind, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use minijinja::{Environment, Source};
+use minijinja::{value::Value, Environment, Source};
 
-use crate::program::{
-    Object, ObjectSerialize, ProgramState, Struct, VarNameId, VarNames, VariableAccessError,
-};
+use crate::program::{Object, ProgramState, Struct, VarNameId, VarNames, VariableAccessError};
 
 use super::expr::{ObjectExpr, StringExpr};
 
@@ -59,8 +63,18 @@ impl Display for TemplateBuildError {
     }
 }
 
+/// Template context key holding every object yielded so far, see `ProgramState::yielded`
+pub const YIELDED_KEY: &str = "yielded";
+
+/// Renders templates found in the include paths into the output directory.
+///
+/// The loader backed `Source` memoizes each template the first time it is compiled, so building
+/// the same template many times in a loop only reads and parses the file once.
 pub struct TemplateBuilder<'source> {
     pub environment: Environment<'source>,
+    /// Render templates with only the globals, set by `--template-globals-only`. Otherwise
+    /// every scope is visible and the innermost variable with a name wins.
+    pub globals_only: bool,
     output: PathBuf,
 }
 
@@ -86,6 +100,7 @@ impl<'source> TemplateBuilder<'source> {
 
         Self {
             environment: env,
+            globals_only: false,
             output,
         }
     }
@@ -97,6 +112,7 @@ impl<'source> TemplateBuilder<'source> {
         state: &ProgramState,
         names: &VarNames,
     ) -> Result<String, TemplateBuildError> {
+        // Cached by the source after the first load
         let template = match self.environment.get_template(&template_path) {
             Ok(template) => template,
             Err(e) => {
@@ -104,10 +120,15 @@ impl<'source> TemplateBuilder<'source> {
             }
         };
 
-        let mut current_params: HashMap<&str, ObjectSerialize> = Default::default();
+        let mut current_params: HashMap<&str, Value> = Default::default();
         // self.current_params.clear();
 
-        for scope in state.scopes.iter().rev() {
+        let scopes = match self.globals_only {
+            true => &state.scopes[..1],
+            false => &state.scopes[..],
+        };
+
+        for scope in scopes.iter().rev() {
             for (name, value) in scope.0.iter() {
                 let name = match names.evaluate(*name) {
                     Some(name) => name,
@@ -118,11 +139,16 @@ impl<'source> TemplateBuilder<'source> {
                     continue;
                 }
 
-                let value = value.to_serialize(state, names);
+                let value = Value::from_serializable(&value.to_serialize(state, names));
                 current_params.insert(name, value);
             }
         }
 
+        // A variable named `yielded` takes precedence
+        current_params
+            .entry(YIELDED_KEY)
+            .or_insert_with(|| serialize_yielded(state, names));
+
         let mut output_file = self.output.clone();
         output_file.push(output_name);
 
@@ -162,7 +188,7 @@ impl<'source> TemplateBuilder<'source> {
             }
         }
 
-        if let Err(e) = std::fs::write(&output_file, rendered) {
+        if let Err(e) = write_atomic(&output_file, rendered.as_bytes()) {
             return Err(TemplateBuildError::BuildError {
                 template_path,
                 output_path,
@@ -174,7 +200,48 @@ impl<'source> TemplateBuilder<'source> {
     }
 }
 
+/// Everything yielded so far regardless of the template it was yielded into. Serialized on
+/// every build since the lists can be replaced and the refs in them retargeted between builds.
+fn serialize_yielded(state: &ProgramState, names: &VarNames) -> Value {
+    let mut yielded = vec![];
+
+    for id in state.yielded.iter() {
+        let Some(Object::List(list)) = state.scopes[0].0.get(id) else {
+            continue;
+        };
+
+        for object in list {
+            yielded.push(Value::from_serializable(&object.to_serialize(state, names)));
+        }
+    }
+
+    Value::from(yielded)
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so an interrupted
+/// run leaves either the previous file or the complete render but never a partial one
+fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    // Parallel template builds may render to the same output at once
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let temp = path.with_file_name(format!(".{name}.{}.{count}.tmp", std::process::id()));
+
+    let result = std::fs::write(&temp, contents).and_then(|_| std::fs::rename(&temp, path));
+
+    if result.is_err() {
+        std::fs::remove_file(&temp).ok();
+    }
+
+    result
+}
+
 pub fn yield_value(output: VarNameId, to_yield: Object, state: &mut ProgramState) {
+    if !state.yielded.contains(&output) {
+        state.yielded.push(output);
+    }
+
     match state.scopes[0].0.get_mut(&output) {
         Some(Object::List(list)) => {
             list.push(to_yield);
@@ -206,16 +273,33 @@ impl BuildStringExpr {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct PropertyExpr {
+    pub value: ObjectExpr,
+    /// Written as `prop ?= expr`, the property is left out if a variable or field it
+    /// references is missing
+    pub optional: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum BuildTargets {
+    /// `build(template, output)` evaluates to a struct with the output path as its base
+    One(BuildStringExpr),
+    /// `build(a -> x, b -> y)` renders every template with the same context and evaluates to a
+    /// list with a struct per output, each with the properties
+    Many(Vec<BuildStringExpr>),
+}
+
 #[derive(Clone, Debug)]
 pub struct BuildObjectExpr {
-    pub base: BuildStringExpr,
-    pub properties: HashMap<VarNameId, ObjectExpr>,
+    pub targets: BuildTargets,
+    pub properties: HashMap<VarNameId, PropertyExpr>,
 }
 
 impl BuildObjectExpr {
-    pub fn new(base: BuildStringExpr) -> Self {
+    pub fn new(targets: BuildTargets) -> Self {
         Self {
-            base,
+            targets,
             properties: HashMap::new(),
         }
     }
@@ -226,15 +310,45 @@ impl BuildObjectExpr {
         builder: &mut TemplateBuilder<'a>,
         names: &VarNames,
     ) -> Result<Object, TemplateBuildError> {
-        let base = self.base.evaluate(state, builder, names)?;
+        let outputs = match &self.targets {
+            BuildTargets::One(target) => vec![target.evaluate(state, builder, names)?],
+            BuildTargets::Many(targets) => {
+                let mut outputs = Vec::with_capacity(targets.len());
+
+                for target in targets {
+                    outputs.push(target.evaluate(state, builder, names)?);
+                }
+
+                outputs
+            }
+        };
         let mut properties = HashMap::default();
 
-        for (key, value) in self.properties.iter() {
-            let value = value.evaluate(state)?;
+        for (key, property) in self.properties.iter() {
+            let value = match property.value.evaluate(state) {
+                Ok(value) => value,
+                Err(
+                    VariableAccessError::MissingVariable(_) | VariableAccessError::MissingField(_),
+                ) if property.optional => continue,
+                Err(e) => return Err(e.into()),
+            };
             properties.insert(*key, value);
         }
 
-        Ok(Object::Struct(Struct { base, properties }))
+        let mut objects = outputs
+            .into_iter()
+            .map(|base| {
+                Object::Struct(Struct {
+                    base,
+                    properties: properties.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        match self.targets {
+            BuildTargets::One(_) => Ok(objects.pop().unwrap()),
+            BuildTargets::Many(_) => Ok(Object::List(objects)),
+        }
     }
 }
 
@@ -266,8 +380,69 @@ pub enum TemplateCommand {
         output: VarNameId,
         object: BuildObjectExpr,
     },
+    /// Written as `yield a, b, c`, the objects are appended in order
     Yield {
         output: VarNameId,
-        object: YieldExpr,
+        objects: Vec<YieldExpr>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::program::{Object, ProgramState, Struct, VarNames, VariableRef};
+
+    use super::{yield_value, TemplateBuilder};
+    use crate::tests::TempDir;
+
+    #[test]
+    fn yielded_lists_render_their_current_values() {
+        let dir = TempDir::new("yielded");
+        std::fs::write(
+            dir.join("list.j2"),
+            "{% for item in yielded %}{{ item }} {% endfor %}",
+        )
+        .unwrap();
+
+        let mut names = VarNames::default();
+        let output = names.replace("servers");
+        let target = names.replace("target");
+        let mut state = ProgramState::new();
+        state.new_scope();
+        let mut builder = TemplateBuilder::new(dir.join("out"), vec![dir.to_path_buf()]);
+        let text = |base: &str| Object::Struct(Struct::new(base.to_string(), HashMap::new()));
+        let mut render = |state: &ProgramState| {
+            if let Err(e) = builder.build("list.j2".into(), "list".into(), state, &names) {
+                panic!("{e}");
+            }
+            std::fs::read_to_string(dir.join("out/list")).unwrap()
+        };
+
+        yield_value(output, text("a"), &mut state);
+        yield_value(output, text("b"), &mut state);
+        let first = render(&state);
+
+        // Replaced by a list of the same length
+        state.scopes[0]
+            .0
+            .insert(output, Object::List(vec![text("c"), text("d")]));
+        let replaced = render(&state);
+
+        // A yielded ref shows what it points at when the template is built
+        state.scopes[0].0.insert(target, text("old"));
+        let target_ref = VariableRef {
+            scope: 0,
+            target,
+            offset: 0,
+        };
+        yield_value(output, Object::Ref(target_ref), &mut state);
+        render(&state);
+        state.scopes[0].0.insert(target, text("new"));
+        let retargeted = render(&state);
+
+        assert_eq!(first, "a b ");
+        assert_eq!(replaced, "c d ");
+        assert_eq!(retargeted, "c d new ");
+    }
+}
@@ -0,0 +1,1068 @@
+#[macro_use]
+extern crate pest_derive;
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub mod bed;
+pub mod parser;
+pub mod program;
+
+use bed::{commands::Command, templates::TemplateCommand, ProcessCounts, TestBed};
+use parser::{
+    dump::dump_parsed, explain::explain_program, format::format_parsed, parse_source,
+    parse_test_bed, read_scenario, CommandProgram, Parsed, STDIN_PATH,
+};
+use program::{
+    Instruction, Object, Program, ProgramState, Rng, Shutdown, VarNameId, VarNames,
+    VariableAccessError,
+};
+
+/// Variable seeded with `RunOptions::profile`, it holds an empty string if no profile is set
+pub const PROFILE_VARIABLE: &str = "profile";
+
+/// Which command blocks of a scenario to run
+#[derive(Clone, Debug)]
+pub enum ToRun {
+    /// The unnamed `[commands]` block
+    Default,
+    /// Named blocks, `None` selects the unnamed block
+    Specific(Vec<Option<String>>),
+    All,
+    /// `take` blocks of `--all` starting at index `from` in source order, every remaining block
+    /// if `take` is `None`
+    Slice {
+        from: usize,
+        take: Option<usize>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct RunOptions {
+    /// Print each compiled program before running it
+    pub debug: bool,
+    /// Print the parsed expressions of each selected program before they are compiled
+    pub debug_ast: bool,
+    /// Shutdown the whole run as soon as a process fails
+    pub fail_fast: bool,
+    /// Default spawn limit for each program
+    pub jobs: Option<usize>,
+    /// Maximum number of instructions executed per program run
+    pub instruction_limit: Option<u64>,
+    /// Flush output files after every line so they can be tailed live
+    pub flush_lines: bool,
+    /// Prefix each process bar with the loop variables it was spawned with
+    pub show_iters: bool,
+    /// Number of stderr lines printed when a process fails, `0` prints none
+    pub stderr_tail: usize,
+    /// Time between polls while waiting on processes, files and sleeps
+    pub poll_interval: Duration,
+    /// Progress bar redraws per second, see `TestBed::set_refresh_hz`
+    pub refresh_hz: u8,
+    /// Doubles the poll interval of each wait after every poll, up to this
+    pub poll_backoff: Option<Duration>,
+    /// Skip template commands that hit a variable error rather than aborting the template
+    pub continue_on_error: bool,
+    /// Build each template program on its own thread, see `build_templates_parallel`
+    pub parallel_templates: bool,
+    /// Render templates with only the globals in scope, see `TemplateBuilder::globals_only`
+    pub template_globals_only: bool,
+    /// Value of the `profile` variable, checked with `when profile == name { ... }`
+    pub profile: Option<String>,
+    /// Seed for `for sample` loops, a clock based seed is used if `None`
+    pub seed: Option<u64>,
+    /// Environment variables set for every spawned process
+    pub env: Vec<(String, String)>,
+    /// Variables loaded by `--var-file`, seeded before the params so a param overrides them
+    pub vars: Vec<(String, serde_json::Value)>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            debug: false,
+            debug_ast: false,
+            fail_fast: false,
+            jobs: None,
+            instruction_limit: Some(program::DEFAULT_INSTRUCTION_LIMIT),
+            flush_lines: false,
+            show_iters: false,
+            stderr_tail: 0,
+            poll_interval: bed::SLEEP_TIME,
+            refresh_hz: bed::REFRESH_HZ,
+            poll_backoff: None,
+            continue_on_error: false,
+            parallel_templates: false,
+            template_globals_only: false,
+            profile: None,
+            seed: None,
+            env: vec![],
+            vars: vec![],
+        }
+    }
+}
+
+/// Outcome of a run
+#[derive(Clone, Debug, Default)]
+pub struct RunSummary {
+    /// A globals, template or command program returned an error
+    pub errored: bool,
+    /// A process failed to spawn or exited unsuccessfully
+    pub failed: bool,
+    /// A running process had to be killed
+    pub killed: bool,
+    /// The run was cut short by `fail_fast`
+    pub stopped_early: bool,
+    pub processes: ProcessCounts,
+    /// Timing of each command block that ran, in the order they ran
+    pub blocks: Vec<BlockTiming>,
+    /// Process counts and finished iterators of a run that got through its command blocks,
+    /// empty otherwise
+    pub report: String,
+}
+
+/// Wall clock time of a command block, split into running its program and waiting for the
+/// processes it left running once the program finished
+#[derive(Clone, Debug)]
+pub struct BlockTiming {
+    /// `None` for the unnamed block
+    pub name: Option<String>,
+    pub run: Duration,
+    pub wait: Duration,
+}
+
+impl BlockTiming {
+    pub fn total(&self) -> Duration {
+        self.run + self.wait
+    }
+}
+
+impl RunSummary {
+    pub fn is_success(&self) -> bool {
+        !(self.errored || self.failed || self.killed)
+    }
+
+    /// Non-zero if anything went wrong, unless failures are ignored. Stopping early through
+    /// `fail_fast` is always reported as a failure.
+    pub fn exit_code(&self, ignore_failures: bool) -> i32 {
+        match self.stopped_early || (!ignore_failures && !self.is_success()) {
+            true => 1,
+            false => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    MissingDefaultProgram,
+    MissingProgram(Option<String>),
+    /// A `*` or `?` pattern that didn't match any named command block
+    NoMatchingProgram(String),
+    /// A `--from` / `--take` slice that doesn't fit in the number of command blocks
+    InvalidSlice {
+        from: usize,
+        take: Option<usize>,
+        blocks: usize,
+    },
+    InvalidParam(String, VariableAccessError),
+    /// A variable named by a selected command block's `requires` that no param or global set
+    MissingRequiredParam(String),
+    /// A scenario has a syntax error or its includes form a cycle
+    Parse(String),
+    /// A scenario file could not be read
+    Io(PathBuf, std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::MissingDefaultProgram => write!(f, "No default command to run"),
+            Error::MissingProgram(name) => write!(f, "Missing program: {name:?}"),
+            Error::NoMatchingProgram(pattern) => write!(f, "No program matches `{pattern}`"),
+            Error::InvalidSlice { from, take, blocks } => match take {
+                Some(take) => write!(
+                    f,
+                    "`--from {from} --take {take}` is out of range, there are {blocks} command blocks"
+                ),
+                None => write!(
+                    f,
+                    "`--from {from}` is out of range, there are {blocks} command blocks"
+                ),
+            },
+            Error::InvalidParam(name, e) => write!(f, "Failed to set param `{name}`: {e}"),
+            Error::MissingRequiredParam(name) => write!(f, "missing required parameter: {name}"),
+            Error::Parse(e) => write!(f, "Failed to parse scenario\n{e}"),
+            Error::Io(path, e) => write!(f, "Failed to read `{}`: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parses and runs the scenario at `path` with the default options. `params` are
+/// `(variable, value)` pairs where the variable may be `name.property`.
+pub fn run_scenario(
+    path: impl AsRef<Path>,
+    selection: ToRun,
+    params: Vec<(String, String)>,
+) -> Result<RunSummary, Error> {
+    let shutdown = Shutdown::new();
+    run_scenario_with(path, selection, params, &RunOptions::default(), &shutdown)
+}
+
+/// Parses the scenario at `path` and writes it back out in canonical form, `include`
+/// directives are kept rather than resolved
+pub fn format_scenario(path: impl AsRef<Path>) -> Result<String, Error> {
+    let path = path.as_ref();
+    let source = read_scenario(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+
+    Ok(format_parsed(&parse_source(&source)?))
+}
+
+/// Parses the scenario at `path` along with the scenarios it includes and describes it as
+/// pretty printed JSON, see `dump_parsed`
+pub fn dump_scenario(path: impl AsRef<Path>) -> Result<String, Error> {
+    let parsed = parse_test_bed(path)?;
+    Ok(serde_json::to_string_pretty(&dump_parsed(&parsed)).unwrap())
+}
+
+/// Files a run of a scenario reads, `--watch` runs the scenario again when one of them changes.
+/// Paths are absolute.
+#[derive(Clone, Debug, Default)]
+pub struct ScenarioSources {
+    /// The scenario and every scenario it includes
+    pub files: Vec<PathBuf>,
+    /// Template include directories, watched along with everything inside them
+    pub template_dirs: Vec<PathBuf>,
+    /// Template output directory if the scenario sets one, changes inside it never count
+    pub output: Option<PathBuf>,
+}
+
+impl ScenarioSources {
+    /// Parses the scenario at `path` to find its includes
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let parsed = parse_test_bed(path)?;
+        let absolute = |path: &Path| {
+            path.canonicalize()
+                .or_else(|_| std::path::absolute(path))
+                .unwrap_or_else(|_| path.to_path_buf())
+        };
+
+        Ok(Self {
+            template_dirs: parsed.includes.iter().map(|dir| absolute(dir)).collect(),
+            output: Some(&parsed.output)
+                .filter(|output| !output.as_os_str().is_empty())
+                .map(|output| absolute(output)),
+            files: parsed.files,
+        })
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        if self
+            .output
+            .as_ref()
+            .is_some_and(|output| path.starts_with(output))
+        {
+            return false;
+        }
+
+        self.files.iter().any(|file| file == path)
+            || self.template_dirs.iter().any(|dir| path.starts_with(dir))
+    }
+}
+
+/// Describes the loops and spawns of the command block `name` without running anything, see
+/// `explain_program`. `None` is the unnamed block.
+pub fn explain_scenario(path: impl AsRef<Path>, name: Option<String>) -> Result<String, Error> {
+    let mut parsed = parse_test_bed(path)?;
+    let id = name.as_ref().map(|name| parsed.names.replace(name));
+
+    // Rejects the calls a run would reject
+    parsed.commands_program(id)?;
+    parsed.on_failure_program(id)?;
+
+    let Some(exprs) = parsed.commands.get(&id) else {
+        return Err(Error::MissingProgram(name));
+    };
+
+    let header = match &name {
+        Some(name) => format!("`{name}` Program"),
+        None => "Default Program".to_string(),
+    };
+
+    let on_failure = parsed.on_failure.get(&id).map(Vec::as_slice);
+    Ok(format!(
+        "{header}\n{}",
+        explain_program(&parsed, exprs, on_failure)
+    ))
+}
+
+/// Describes the output and include directories, spawn limit, selected command blocks and
+/// params a run of the scenario at `path` would use, without running anything
+pub fn describe_config(
+    path: impl AsRef<Path>,
+    selection: ToRun,
+    params: &[(String, String)],
+    options: &RunOptions,
+) -> Result<String, Error> {
+    let path = path.as_ref();
+    let mut parsed = parse_test_bed(path)?;
+    let programs = select_programs(&mut parsed, selection)?;
+    let mut out = String::new();
+
+    // Relative paths are resolved against the working directory, like the run does
+    let resolved = |path: &Path| {
+        std::path::absolute(Path::new(".").join(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+            .display()
+            .to_string()
+    };
+
+    let scenario = match path == Path::new(STDIN_PATH) {
+        true => "stdin".to_string(),
+        false => resolved(path),
+    };
+    writeln!(out, "Scenario: {scenario}").unwrap();
+    writeln!(out, "Output: {}", resolved(&parsed.output)).unwrap();
+
+    writeln!(out, "Includes:").unwrap();
+    for include in parsed.includes.iter() {
+        writeln!(out, "  {}", resolved(include)).unwrap();
+    }
+    if parsed.includes.is_empty() {
+        writeln!(out, "  none").unwrap();
+    }
+
+    let limit = match options.jobs {
+        Some(jobs) => format!("{jobs} (--jobs)"),
+        None => "none".to_string(),
+    };
+    writeln!(out, "Spawn limit: {limit}").unwrap();
+
+    writeln!(out, "Commands:").unwrap();
+    for (name, program) in programs.iter() {
+        let name = match name {
+            Some(name) => format!("`{name}`"),
+            None => "Default".to_string(),
+        };
+        let limits: Vec<_> = program
+            .0
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Command(Command::LimitSpawn(limit)) => Some(limit.to_string()),
+                _ => None,
+            })
+            .collect();
+
+        match limits.is_empty() {
+            true => writeln!(out, "  {name}").unwrap(),
+            false => writeln!(out, "  {name} (limit {})", limits.join(", ")).unwrap(),
+        }
+    }
+
+    writeln!(out, "Params:").unwrap();
+    for (variable, value) in params.iter() {
+        writeln!(out, "  {variable} = {value}").unwrap();
+    }
+    if params.is_empty() {
+        writeln!(out, "  none").unwrap();
+    }
+
+    if let Some(profile) = &options.profile {
+        writeln!(out, "Profile: {profile}").unwrap();
+    }
+    if let Some(seed) = options.seed {
+        writeln!(out, "Seed: {seed}").unwrap();
+    }
+
+    Ok(out)
+}
+
+pub fn run_scenario_with(
+    path: impl AsRef<Path>,
+    selection: ToRun,
+    params: Vec<(String, String)>,
+    options: &RunOptions,
+    shutdown: &Shutdown,
+) -> Result<RunSummary, Error> {
+    let parsed = parse_test_bed(path)?;
+    run_parsed(parsed, selection, params, options, shutdown)
+}
+
+/// Runs the globals, every template program and then the selected command programs of an
+/// already parsed scenario
+pub fn run_parsed(
+    mut parsed: Parsed,
+    selection: ToRun,
+    params: Vec<(String, String)>,
+    options: &RunOptions,
+    shutdown: &Shutdown,
+) -> Result<RunSummary, Error> {
+    let command_programs = select_programs(&mut parsed, selection)?;
+
+    if options.debug_ast {
+        for (id, exprs) in parsed.templates.iter() {
+            let name = parsed.names.evaluate(*id).unwrap_or("?");
+            println!("`{name}` Template: {exprs:#?}");
+        }
+
+        for (name, _) in command_programs.iter() {
+            let id = name.as_ref().map(|name| parsed.names.replace(name));
+            let exprs = &parsed.commands[&id];
+
+            match name {
+                Some(name) => println!("`{name}` Program: {exprs:#?}"),
+                None => println!("Default Program: {exprs:#?}"),
+            }
+        }
+    }
+
+    let mut seeded: HashMap<(VarNameId, Option<VarNameId>), Object> = HashMap::new();
+
+    for (variable, value) in params.iter() {
+        let id = match variable.split_once('.') {
+            Some((id, property)) => (
+                parsed.names.replace(id),
+                Some(parsed.names.replace(property)),
+            ),
+            None => (parsed.names.replace(variable), None),
+        };
+
+        seeded.insert(id, Object::new(value.to_string()));
+    }
+
+    let required: Vec<_> = command_programs
+        .iter()
+        .filter_map(|(name, _)| {
+            let id = name.as_ref().map(|name| parsed.names.replace(name));
+            parsed.requires.get(&id)
+        })
+        .flatten()
+        .copied()
+        .collect();
+
+    let mut on_failure = HashMap::new();
+
+    for (name, _) in command_programs.iter() {
+        let id = name.as_ref().map(|name| parsed.names.replace(name));
+
+        if let Some(program) = parsed.on_failure_program(id)? {
+            on_failure.insert(name.clone(), program);
+        }
+    }
+
+    let template_programs = parsed.template_program();
+    let globals_program = parsed.globals_program();
+    let globals_lines = parsed.globals_lines.clone();
+    let output = parsed.output.clone();
+    let includes = parsed.includes.clone();
+    let mut test_bed = TestBed::new(parsed.output, parsed.includes, parsed.names);
+    test_bed.fail_fast = options.fail_fast;
+    test_bed.continue_on_error = options.continue_on_error;
+    test_bed.flush_lines = options.flush_lines;
+    test_bed.show_iters = options.show_iters;
+    test_bed.stderr_tail = options.stderr_tail;
+    test_bed.poll_interval = options.poll_interval;
+    test_bed.set_refresh_hz(options.refresh_hz);
+    test_bed.templates.globals_only = options.template_globals_only;
+    test_bed.poll_backoff = options.poll_backoff;
+    test_bed.env = options.env.clone();
+    test_bed.default_spawn_limit = options.jobs;
+    test_bed.spawn_limit = options.jobs;
+
+    let mut state = ProgramState::new();
+    state.instruction_limit = options.instruction_limit;
+    let seed = options.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default()
+    });
+    state.rng = Rng::new(seed);
+
+    // A clock based seed is the only way to reproduce a failed run
+    let random = globals_program.is_random()
+        || template_programs
+            .iter()
+            .any(|(_, program)| program.is_random())
+        || command_programs
+            .iter()
+            .any(|(_, program)| program.is_random())
+        || on_failure.values().any(Program::is_random);
+    if options.seed.is_none() && random {
+        eprintln!("Seed: {seed}, pass `--seed {seed}` to repeat this run");
+    }
+    state.new_scope();
+
+    // Params and globals named `profile` take precedence
+    let profile = options.profile.clone().unwrap_or_default();
+    let profile_id = test_bed.var_names.replace(PROFILE_VARIABLE);
+    state.insert_var(profile_id, Object::new(profile), Some(0));
+
+    for (variable, value) in options.vars.iter() {
+        let id = test_bed.var_names.replace(variable);
+        let value = Object::from_json(value, &mut test_bed.var_names);
+        state.insert_var(id, value, Some(0));
+    }
+
+    for ((id, property), value) in seeded.iter() {
+        if let Err(e) = state.set_var(*id, *property, value.clone()) {
+            let name = test_bed.var_names.evaluate(*id).unwrap_or("?").to_string();
+            return Err(Error::InvalidParam(name, e));
+        }
+    }
+
+    let mut errored = false;
+
+    if let Err((instruction, e)) = globals_program.run(&mut test_bed, &mut state, shutdown) {
+        match globals_lines.get(instruction) {
+            Some((file, line)) => {
+                let e = describe_error(&test_bed.var_names, &e);
+                let at = match file {
+                    Some(file) => format!("at {}:{line}", file.display()),
+                    None => format!("on line {line}"),
+                };
+                test_bed
+                    .multibar
+                    .println(format!("Global {at} failed: {e}"))
+                    .ok();
+            }
+            None => report_error(&test_bed, "Globals", (instruction, e)),
+        }
+        return Ok(summarize(true, &test_bed));
+    }
+
+    for id in required {
+        if state.get_value(id).is_none() {
+            let name = test_bed.var_names.evaluate(id).unwrap_or("?");
+            return Err(Error::MissingRequiredParam(name.to_string()));
+        }
+    }
+
+    if options.parallel_templates {
+        errored |= build_templates_parallel(
+            template_programs,
+            &output,
+            &includes,
+            &test_bed,
+            &mut state,
+            options,
+            shutdown,
+        );
+    } else {
+        for (name, program) in template_programs {
+            test_bed
+                .multibar
+                .println(format!("Building `{name}` Template"))
+                .ok();
+
+            if options.debug {
+                println!("{program}");
+            }
+            let depth = state.scopes.len();
+            state.new_scope();
+            if let Err(e) = program.run(&mut test_bed, &mut state, shutdown) {
+                report_error(&test_bed, &format!("Template `{name}`"), e);
+                errored = true;
+            }
+            state.truncate_scopes(depth);
+        }
+    }
+
+    let mut blocks = vec![];
+
+    for (block, program) in command_programs {
+        let name = match &block {
+            Some(name) => format!("`{name}` Program"),
+            None => "Default Program".to_string(),
+        };
+        test_bed.show_status();
+        test_bed.multibar.println(format!("Running {name}")).ok();
+
+        if options.debug {
+            println!("{program}");
+        }
+
+        let start = Instant::now();
+        let depth = state.scopes.len();
+        state.new_scope();
+        if let Err(e) = program.run(&mut test_bed, &mut state, shutdown) {
+            report_error(&test_bed, &name, e);
+            errored = true;
+
+            // Sees the variables of the block but not of the loops it failed in. Its own
+            // errors are reported, the block has failed either way.
+            if let Some(cleanup) = on_failure.get(&block) {
+                test_bed
+                    .multibar
+                    .println(format!("Running on_failure of {name}"))
+                    .ok();
+
+                test_bed.leave_blocks();
+                state.truncate_scopes(depth + 1);
+                state.new_scope();
+                if let Err(e) = cleanup.run(&mut test_bed, &mut state, shutdown) {
+                    report_error(&test_bed, &format!("on_failure of {name}"), e);
+                }
+            }
+        }
+        state.truncate_scopes(depth);
+        let run = start.elapsed();
+
+        test_bed.reset(shutdown);
+        let timing = BlockTiming {
+            name: block,
+            run,
+            wait: start.elapsed() - run,
+        };
+
+        test_bed
+            .multibar
+            .println(format!(
+                "Finished {name} in {:.2?} ({:.2?} running, {:.2?} waiting)",
+                timing.total(),
+                timing.run,
+                timing.wait
+            ))
+            .ok();
+        blocks.push(timing);
+    }
+
+    test_bed.stop_detached();
+
+    Ok(RunSummary {
+        blocks,
+        report: test_bed.summary(),
+        ..summarize(errored, &test_bed)
+    })
+}
+
+/// Builds the command programs picked by `selection`, in the order they are run
+fn select_programs(parsed: &mut Parsed, selection: ToRun) -> Result<Vec<CommandProgram>, Error> {
+    let programs = match selection {
+        ToRun::Default => match parsed.commands_program(None)? {
+            Some(command) => vec![command],
+            None => return Err(Error::MissingDefaultProgram),
+        },
+        ToRun::All => parsed.all_programs()?,
+        ToRun::Slice { from, take } => {
+            let mut programs = parsed.all_programs()?;
+            let blocks = programs.len();
+            let end = match take {
+                Some(take) => from.checked_add(take),
+                None => Some(blocks),
+            };
+
+            let Some(end) = end.filter(|end| from < blocks && *end <= blocks) else {
+                return Err(Error::InvalidSlice { from, take, blocks });
+            };
+
+            programs.truncate(end);
+            programs.drain(..from);
+            programs
+        }
+        ToRun::Specific(names) => {
+            let mut programs = vec![];
+
+            for name in names {
+                if let Some(pattern) = name.as_deref().filter(|name| is_glob(name)) {
+                    let matches = matching_programs(parsed, pattern);
+
+                    if matches.is_empty() {
+                        return Err(Error::NoMatchingProgram(pattern.to_string()));
+                    }
+
+                    for id in matches {
+                        programs.extend(parsed.commands_program(id)?);
+                    }
+                    continue;
+                }
+
+                let id = name.as_ref().map(|name| parsed.names.replace(name));
+
+                match parsed.commands_program(id)? {
+                    Some(program) => programs.push(program),
+                    None => return Err(Error::MissingProgram(name)),
+                }
+            }
+
+            programs
+        }
+    };
+
+    Ok(programs)
+}
+
+/// Builds each template on its own thread from a copy of the state the globals left behind.
+/// Only the list each template yields into is merged back, so templates that read another
+/// template's yields or assign to globals have to be built sequentially. Returns true if any
+/// template errored.
+fn build_templates_parallel(
+    template_programs: Vec<(String, Program<TemplateCommand>)>,
+    output: &Path,
+    includes: &[PathBuf],
+    test_bed: &TestBed,
+    state: &mut ProgramState,
+    options: &RunOptions,
+    shutdown: &Shutdown,
+) -> bool {
+    let results = std::thread::scope(|scope| {
+        let handles: Vec<_> = template_programs
+            .into_iter()
+            .map(|(name, program)| {
+                let mut state = state.clone();
+                let names = test_bed.var_names.clone();
+                let multibar = test_bed.multibar.clone();
+
+                scope.spawn(move || {
+                    // Each thread has its own template environment rather than sharing one
+                    let mut test_bed = TestBed::new(output.to_path_buf(), includes.to_vec(), names);
+                    test_bed.draw_to(multibar);
+                    test_bed.continue_on_error = options.continue_on_error;
+                    test_bed.templates.globals_only = options.template_globals_only;
+                    test_bed
+                        .multibar
+                        .println(format!("Building `{name}` Template"))
+                        .ok();
+
+                    if options.debug {
+                        println!("{program}");
+                    }
+
+                    state.new_scope();
+                    let errored = match program.run(&mut test_bed, &mut state, shutdown) {
+                        Ok(()) => false,
+                        Err(e) => {
+                            report_error(&test_bed, &format!("Template `{name}`"), e);
+                            true
+                        }
+                    };
+
+                    (name, errored, state)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let mut errored = false;
+
+    for (name, template_errored, mut template_state) in results {
+        errored |= template_errored;
+
+        let Some(id) = test_bed.var_names.0.get_index_of(name.as_str()) else {
+            continue;
+        };
+        let id = VarNameId(id);
+
+        if let Some(yielded) = template_state.scopes[0].0.remove(&id) {
+            state.scopes[0].0.insert(id, yielded);
+
+            if !state.yielded.contains(&id) {
+                state.yielded.push(id);
+            }
+        }
+    }
+
+    errored
+}
+
+fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+/// Named command blocks matching `pattern`, in source order
+fn matching_programs(parsed: &Parsed, pattern: &str) -> Vec<Option<VarNameId>> {
+    parsed
+        .commands
+        .keys()
+        .filter(|id| {
+            id.and_then(|id| parsed.names.evaluate(id))
+                .is_some_and(|name| glob_match(pattern.as_bytes(), name.as_bytes()))
+        })
+        .copied()
+        .collect()
+}
+
+/// `*` matches any run of characters and `?` a single character
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(a), Some(b)) if a == b => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+fn report_error(test_bed: &TestBed, name: &str, (instruction, e): (usize, VariableAccessError)) {
+    test_bed
+        .multibar
+        .println(format!("{name} failed at instruction {instruction}: {e}"))
+        .ok();
+}
+
+/// Shows the names of missing variables and fields rather than their ids
+fn describe_error(names: &VarNames, e: &VariableAccessError) -> String {
+    let name = |id| names.evaluate(id).unwrap_or("?");
+
+    match e {
+        VariableAccessError::MissingVariable(id) => format!("MissingVariable({})", name(*id)),
+        VariableAccessError::MissingField(id) => format!("MissingField({})", name(*id)),
+        VariableAccessError::DanglingRef(id) => format!("DanglingRef({})", name(*id)),
+        e => e.to_string(),
+    }
+}
+
+fn summarize(errored: bool, test_bed: &TestBed) -> RunSummary {
+    RunSummary {
+        errored,
+        failed: test_bed.failed,
+        killed: test_bed.killed,
+        stopped_early: test_bed.fail_fast && test_bed.failed,
+        processes: test_bed.counts,
+        blocks: vec![],
+        report: String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh directory under the temp dir, removed with everything in it when dropped so a
+    /// failing test cleans up too
+    pub(crate) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("test-bed-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    /// Waits for an output file to be written, processes' output is written on threads of
+    /// their own that can still be running once the process has exited
+    pub(crate) fn read_written(path: &Path) -> String {
+        let start = std::time::Instant::now();
+
+        loop {
+            let written = std::fs::read_to_string(path).unwrap_or_default();
+
+            if !written.is_empty() || start.elapsed() > std::time::Duration::from_secs(5) {
+                return written;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    /// Writes `files` into a fresh directory under the temp dir and returns it
+    fn scenario_dir(name: &str, files: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir::new(name);
+
+        for (file, source) in files {
+            std::fs::write(dir.join(file), source).unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn syntax_error_is_returned() {
+        let dir = scenario_dir("syntax", &[("main.bed", "[commands]\nspawn ./a\n")]);
+        let result = dump_scenario(dir.join("main.bed"));
+
+        assert!(matches!(result, Err(Error::Parse(e)) if e.contains("main.bed")));
+    }
+
+    #[test]
+    fn missing_file_is_returned() {
+        let result = explain_scenario("/nonexistent/test-bed/main.bed", None);
+        assert!(matches!(result, Err(Error::Io(..))));
+    }
+
+    #[test]
+    fn include_cycle_is_returned() {
+        let dir = scenario_dir(
+            "cycle",
+            &[
+                ("a.bed", "include \"b.bed\"\n\n[commands]\nwait_all;\n"),
+                ("b.bed", "include \"a.bed\"\n"),
+            ],
+        );
+        let result = ScenarioSources::new(dir.join("a.bed"));
+
+        assert!(matches!(result, Err(Error::Parse(e)) if e.starts_with("Include cycle")));
+    }
+
+    #[test]
+    fn bad_calls_are_parse_errors() {
+        let cases = [
+            (
+                "recursive",
+                "def a() {\n    call b();\n}\n\ndef b() {\n    call a();\n}\n\n[commands]\ncall a();\n",
+                "Recursive call: a -> b -> a",
+            ),
+            (
+                "undefined",
+                "[commands]\ncall deploy(host = alpha);\n",
+                "`call deploy` has no matching `def`",
+            ),
+            (
+                "missing-param",
+                "def deploy(host) {\n    wait_all;\n}\n\n[commands]\ncall deploy();\n",
+                "`call deploy` is missing the param `host`",
+            ),
+        ];
+
+        for (name, source, message) in cases {
+            let dir = scenario_dir(name, &[("main.bed", source)]);
+            let path = dir.join("main.bed");
+
+            let result = run_scenario(&path, ToRun::Default, vec![]);
+            assert!(
+                matches!(&result, Err(Error::Parse(e)) if e == message),
+                "{name}"
+            );
+
+            let result = explain_scenario(&path, None);
+            assert!(
+                matches!(&result, Err(Error::Parse(e)) if e == message),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn globals_see_earlier_globals() {
+        let dir = scenario_dir("globals", &[("b.j2", "{{ b }}")]);
+        let source = format!(
+            "[includes]\n\"{dir}\"\n\n[output]\n\"{dir}/out\"\n\n[globals]\na = base;\nb = [a] + _suffix;\n\n[template.render]\nyield build(\"b.j2\", b.txt);\n\n[commands]\nassert (b == base_suffix) \"b is \" + [b];\n",
+            dir = dir.display()
+        );
+        std::fs::write(dir.join("main.bed"), source).unwrap();
+
+        let summary = run_scenario(dir.join("main.bed"), ToRun::Default, vec![]).unwrap();
+
+        assert!(!summary.errored);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("out/b.txt")).unwrap(),
+            "base_suffix"
+        );
+    }
+
+    #[test]
+    fn build_output_names_use_nested_fields() {
+        let dir = scenario_dir("nested", &[("server.j2", "{{ server.base }}")]);
+        let source = format!(
+            "[includes]\n\"{dir}\"\n\n[output]\n\"{dir}/out\"\n\n[globals]\nservers = [\n    (alpha, net = (n1, host = alpha.local)),\n    (beta, net = (n2, host = beta.local)),\n];\n\n[template.configs]\nfor server in servers {{\n    yield build(\"server.j2\", [server.net.host] + .conf);\n}}\n\n[commands]\n",
+            dir = dir.display()
+        );
+        std::fs::write(dir.join("main.bed"), source).unwrap();
+
+        let summary = run_scenario(dir.join("main.bed"), ToRun::Default, vec![]).unwrap();
+
+        assert!(!summary.errored);
+        for (host, base) in [("alpha.local", "alpha"), ("beta.local", "beta")] {
+            let rendered = std::fs::read_to_string(dir.join(format!("out/{host}.conf")));
+            assert_eq!(rendered.unwrap(), base);
+        }
+    }
+
+    #[test]
+    fn slice_past_the_blocks_is_invalid() {
+        let source = "[commands.a]\nwait_all;\n\n[commands.b]\nwait_all;\n";
+        let mut parsed = parse_source(source).unwrap();
+
+        let take_rest = ToRun::Slice {
+            from: 1,
+            take: None,
+        };
+        let names: Vec<_> = select_programs(&mut parsed, take_rest)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, [Some("b".to_string())]);
+
+        for (from, take) in [(2, None), (1, Some(2)), (1, Some(usize::MAX))] {
+            let result = select_programs(&mut parsed, ToRun::Slice { from, take });
+            assert!(
+                matches!(result, Err(Error::InvalidSlice { blocks: 2, .. })),
+                "{from} {take:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn included_globals_keep_their_file() {
+        let dir = scenario_dir(
+            "global-files",
+            &[
+                ("a.bed", "include \"b.bed\"\n\n[globals]\nx = a;\n"),
+                ("b.bed", "[globals]\ny = b;\n\nz = [missing];\n"),
+            ],
+        );
+        let parsed = parse_test_bed(dir.join("a.bed")).unwrap();
+
+        assert_eq!(
+            parsed.globals_lines,
+            [
+                (Some(dir.join("b.bed")), 2),
+                (Some(dir.join("b.bed")), 4),
+                (Some(dir.join("a.bed")), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn forward_global_reference_is_missing() {
+        let parsed = parse_source("[globals]\na = base;\nb = [c] + _suffix;\nc = late;\n").unwrap();
+        let program = parsed.globals_program();
+        let lines = parsed.globals_lines.clone();
+        let mut test_bed = TestBed::new(PathBuf::new(), vec![], parsed.names);
+        let mut state = ProgramState::new();
+        state.new_scope();
+
+        let Err((instruction, e)) = program.run(&mut test_bed, &mut state, &Shutdown::new()) else {
+            panic!("forward reference resolved");
+        };
+
+        assert_eq!(lines.get(instruction), Some(&(None, 3)));
+        assert_eq!(
+            describe_error(&test_bed.var_names, &e),
+            "MissingVariable(c)"
+        );
+    }
+}
@@ -1,31 +1,68 @@
-#[macro_use]
-extern crate pest_derive;
+use std::{
+    error::Error,
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use std::{collections::HashMap, sync::mpsc::channel};
+use notify::{event::ModifyKind, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use test_bed::{
+    describe_config, dump_scenario, explain_scenario, format_scenario, parser::STDIN_PATH,
+    program::Shutdown, run_scenario_with, RunOptions, ScenarioSources, ToRun,
+};
 
-mod bed;
-mod parser;
-mod program;
+/// How long a timed out run has to shut down before the process exits anyway
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
 
-use bed::TestBed;
-use parser::parse_test_bed;
-use program::{ProgramState, Shutdown, VarNameId};
-
-#[derive(Clone, Debug)]
-pub enum ToRun {
-    Specific(Vec<Option<VarNameId>>),
-    All,
-}
+/// With `--watch`, a run starts once no file has changed for this long
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
 
 fn main() {
     let mut args = std::env::args();
     args.next();
 
-    let commands = args.next().unwrap();
-    let mut parsed = parse_test_bed(commands);
+    let scenario = args.next().unwrap();
+
+    if scenario == "fmt" {
+        let file = args.next().expect("Expected a scenario file after `fmt`");
+
+        match format_scenario(file) {
+            Ok(formatted) => print!("{formatted}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if scenario == "dump" {
+        let file = args.next().expect("Expected a scenario file after `dump`");
+        match dump_scenario(file) {
+            Ok(dumped) => println!("{dumped}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let mut commands = vec![];
     let mut run_all = false;
-    let mut debug = false;
+    let mut ignore_failures = false;
+    let mut global_timeout = None;
+    let mut quiet = false;
+    let mut show_config = false;
+    let mut explain = None;
+    let mut watch = false;
+    let mut from = None;
+    let mut take = None;
+    let mut options = RunOptions::default();
 
     while let Some(value) = args.next() {
         match value.as_str() {
@@ -34,7 +71,169 @@ fn main() {
                 break;
             }
             "--debug" => {
-                debug = true;
+                options.debug = true;
+                continue;
+            }
+            "--debug=ast" => {
+                options.debug_ast = true;
+                continue;
+            }
+            "--fail-fast" => {
+                options.fail_fast = true;
+                continue;
+            }
+            "--flush-lines" => {
+                options.flush_lines = true;
+                continue;
+            }
+            "--show-iters" => {
+                options.show_iters = true;
+                continue;
+            }
+            "--continue-on-error" => {
+                options.continue_on_error = true;
+                continue;
+            }
+            "--template-globals-only" => {
+                options.template_globals_only = true;
+                continue;
+            }
+            "--parallel-templates" => {
+                options.parallel_templates = true;
+                continue;
+            }
+            "--quiet" => {
+                quiet = true;
+                continue;
+            }
+            "--no-color" => {
+                console::set_colors_enabled(false);
+                console::set_colors_enabled_stderr(false);
+                continue;
+            }
+            "--print-config" => {
+                show_config = true;
+                continue;
+            }
+            "--explain" => {
+                match args.next() {
+                    Some(name) if name == "." => explain = Some(None),
+                    Some(name) => explain = Some(Some(name)),
+                    None => panic!("Expected a command block name or `.` after `--explain`"),
+                }
+                continue;
+            }
+            "--watch" => {
+                watch = true;
+                continue;
+            }
+            "--ignore-failures" => {
+                ignore_failures = true;
+                continue;
+            }
+            "--jobs" => {
+                match args.next().and_then(|value| value.parse().ok()) {
+                    Some(0) | None => panic!("Expected a positive number after `--jobs`"),
+                    Some(limit) => options.jobs = Some(limit),
+                }
+                continue;
+            }
+            "--stderr-tail" => {
+                match args.next().and_then(|value| value.parse().ok()) {
+                    Some(lines) => options.stderr_tail = lines,
+                    None => panic!("Expected a number of lines after `--stderr-tail`"),
+                }
+                continue;
+            }
+            "--poll-interval" => {
+                match args.next().and_then(|value| value.parse().ok()) {
+                    Some(0) | None => {
+                        panic!("Expected a positive number of millis after `--poll-interval`")
+                    }
+                    Some(millis) => options.poll_interval = Duration::from_millis(millis),
+                }
+                continue;
+            }
+            "--refresh-hz" => {
+                match args.next().and_then(|value| value.parse().ok()) {
+                    Some(0) | None => {
+                        panic!("Expected a number of redraws per second from 1 to 255 after `--refresh-hz`")
+                    }
+                    Some(hz) => options.refresh_hz = hz,
+                }
+                continue;
+            }
+            "--poll-backoff" => {
+                match args.next().and_then(|value| value.parse().ok()) {
+                    Some(0) | None => {
+                        panic!("Expected a positive number of millis after `--poll-backoff`")
+                    }
+                    Some(millis) => options.poll_backoff = Some(Duration::from_millis(millis)),
+                }
+                continue;
+            }
+            "--from" => {
+                match args.next().and_then(|value| value.parse().ok()) {
+                    Some(index) => from = Some(index),
+                    None => panic!("Expected a block index after `--from`"),
+                }
+                continue;
+            }
+            "--take" => {
+                match args.next().and_then(|value| value.parse().ok()) {
+                    Some(0) | None => panic!("Expected a positive number after `--take`"),
+                    Some(count) => take = Some(count),
+                }
+                continue;
+            }
+            "--profile" => {
+                match args.next() {
+                    Some(profile) => options.profile = Some(profile),
+                    None => panic!("Expected a profile name after `--profile`"),
+                }
+                continue;
+            }
+            "--seed" => {
+                match args.next().and_then(|value| value.parse().ok()) {
+                    Some(seed) => options.seed = Some(seed),
+                    None => panic!("Expected a number after `--seed`"),
+                }
+                continue;
+            }
+            "--env-file" => {
+                let Some(path) = args.next() else {
+                    panic!("Expected a file after `--env-file`");
+                };
+                match parse_env_file(&path) {
+                    Ok(env) => options.env.extend(env),
+                    Err(e) => panic!("Failed to read env file `{path}`: {e}"),
+                }
+                continue;
+            }
+            "--var-file" => {
+                let Some(path) = args.next() else {
+                    panic!("Expected a JSON file after `--var-file`");
+                };
+                match parse_var_file(&path) {
+                    Ok(vars) => options.vars.extend(vars),
+                    Err(e) => panic!("Failed to read var file `{path}`: {e}"),
+                }
+                continue;
+            }
+            "--timeout-global" => {
+                match args.next().as_deref().and_then(parse_duration) {
+                    Some(duration) => global_timeout = Some(duration),
+                    None => panic!("Expected a duration such as `300s` after `--timeout-global`"),
+                }
+                continue;
+            }
+            "--instruction-limit" => {
+                let limit = args.next().and_then(|value| value.parse().ok());
+                options.instruction_limit = match limit {
+                    Some(0) => None,
+                    Some(limit) => Some(limit),
+                    None => panic!("Expected a number after `--instruction-limit`"),
+                };
                 continue;
             }
             "." => {
@@ -45,63 +244,74 @@ fn main() {
                 break;
             }
             x => {
-                let id = parsed.names.replace(x);
-                commands.push(Some(id));
+                commands.push(Some(x.to_string()));
             }
         }
     }
 
-    let mut params = HashMap::new();
+    if let Some(name) = explain {
+        match explain_scenario(scenario, name) {
+            Ok(explained) => print!("{explained}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    while let Some(value) = args.next() {
-        let mut split = value.split("=");
-        let variable = split.next().unwrap();
-        let id = match variable.split_once(".") {
-            Some((id, property)) => (
-                parsed.names.replace(id),
-                Some(parsed.names.replace(property)),
-            ),
-            None => (parsed.names.replace(variable), None),
-        };
+    let mut params = vec![];
 
-        let value = match split.next() {
-            Some(value) => value,
+    for value in args {
+        match value.split_once('=') {
+            Some((variable, value)) => params.push((variable.to_string(), value.to_string())),
             None => {
                 panic!("Invalid input arg `{value}`, expected <variable>=<value>")
             }
-        };
+        }
+    }
 
-        params.insert(id, program::Object::new(value.to_string()));
+    if !run_all && (from.is_some() || take.is_some()) {
+        panic!("`--from` and `--take` slice the blocks of `--all` and must come before it");
     }
 
-    let command_programs = match commands.is_empty() && !run_all {
-        true => match parsed.commands_program(None) {
-            Some(command) => vec![command],
-            None => panic!("No default command to run"),
+    let selection = match (run_all, commands.is_empty()) {
+        (true, _) if from.is_some() || take.is_some() => ToRun::Slice {
+            from: from.unwrap_or(0),
+            take,
         },
-        false => match run_all {
-            true => parsed.all_programs(),
-            false => {
-                let mut programs = vec![];
-
-                for value in commands {
-                    match parsed.commands_program(value) {
-                        Some(program) => programs.push(program),
-                        None => {
-                            let name = value.map(|value| parsed.names.evaluate(value)).flatten();
-                            panic!("Missing program: {:?}", name);
-                        }
-                    }
-                }
+        (true, _) => ToRun::All,
+        (false, true) => ToRun::Default,
+        (false, false) => ToRun::Specific(commands),
+    };
 
-                programs
+    if show_config {
+        match describe_config(scenario, selection, &params, &options) {
+            Ok(config) => print!("{config}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
             }
-        },
-    };
+        }
+        return;
+    }
 
-    let template_programs = parsed.template_program();
-    let globals_program = parsed.globals;
-    let mut test_bed = TestBed::new(parsed.output, parsed.includes, parsed.names);
+    if watch && scenario == STDIN_PATH {
+        panic!("`--watch` needs a scenario file, stdin can only be read once");
+    }
+
+    if watch {
+        let run = Run {
+            scenario,
+            selection,
+            params,
+            options,
+            quiet,
+            ignore_failures,
+            global_timeout,
+        };
+        run_watch(run);
+    }
 
     let shutdown = Shutdown::new();
     let (send, recv) = channel();
@@ -110,58 +320,323 @@ fn main() {
 
     ctrlc::set_handler(move || {
         if shutdown_clone.shutdown() {
-            send_clone.send(()).ok();
+            send_clone.send(130).ok();
         }
     })
     .unwrap();
 
+    // Shuts the run down like a ctrl-c once the timeout expires, forcing an exit if the worker
+    // doesn't wind down within the grace period
+    if let Some(timeout) = global_timeout {
+        let send = send.clone();
+        let shutdown = shutdown.clone();
+
+        std::thread::spawn(move || {
+            std::thread::sleep(timeout);
+            eprintln!("Global timeout of {timeout:?} reached, shutting down");
+            shutdown.shutdown();
+            std::thread::sleep(SHUTDOWN_GRACE);
+            send.send(124).ok();
+        });
+    }
+
     std::thread::spawn(move || {
-        let mut state = ProgramState::new();
-        state.new_scope();
+        // A panic would otherwise leave main waiting forever
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            run_scenario_with(scenario, selection, params, &options, &shutdown)
+        }));
+
+        let code = match result {
+            Ok(Ok(summary)) => {
+                if !quiet {
+                    print!("{}", summary.report);
+                }
+                summary.exit_code(ignore_failures)
+            }
+            Ok(Err(e)) => {
+                eprintln!("{e}");
+                1
+            }
+            Err(_) => 101,
+        };
+
+        send.send(code).ok();
+    });
+
+    let code = recv.recv().unwrap();
+    std::process::exit(code);
+}
+
+/// Everything needed to start a run again with `--watch`
+struct Run {
+    scenario: String,
+    selection: ToRun,
+    params: Vec<(String, String)>,
+    options: RunOptions,
+    quiet: bool,
+    ignore_failures: bool,
+    global_timeout: Option<Duration>,
+}
+
+enum WatchEvent {
+    /// The current run finished with this exit code
+    Finished(i32),
+    /// These paths were created, changed or removed
+    Changed(Vec<PathBuf>),
+    /// The first ctrl-c, a second one exits straight away
+    Interrupted,
+}
 
-        for ((id, property), value) in params.iter() {
-            state.set_var(*id, *property, value.clone()).unwrap();
+/// Runs the scenario, then again whenever a scenario file or template it reads changes, until
+/// ctrl-c. A change during a run shuts it down first. The global timeout shuts down each run
+/// rather than exiting.
+fn run_watch(run: Run) -> ! {
+    let run = Arc::new(run);
+    let (send, recv) = channel();
+    let current = Arc::new(Mutex::new(Shutdown::new()));
+
+    {
+        let send = send.clone();
+        let current = current.clone();
+
+        ctrlc::set_handler(move || {
+            if current.lock().unwrap().shutdown() {
+                std::process::exit(130);
+            }
+            send.send(WatchEvent::Interrupted).ok();
+        })
+        .unwrap();
+    }
+
+    let changes = send.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+
+        let changed = match event.kind {
+            EventKind::Create(_) | EventKind::Remove(_) => true,
+            EventKind::Modify(kind) => !matches!(kind, ModifyKind::Metadata(_)),
+            _ => false,
+        };
+        if changed {
+            changes.send(WatchEvent::Changed(event.paths)).ok();
         }
+    })
+    .expect("Failed to start watching for changes");
+
+    let mut sources = ScenarioSources {
+        files: vec![std::path::absolute(&run.scenario).unwrap()],
+        ..Default::default()
+    };
+    let mut watched = vec![];
 
-        globals_program.run(&mut test_bed, &mut state, &shutdown).unwrap();
-        for (name, program) in template_programs {
-            test_bed
-                .multibar
-                .println(format!("Building `{name}` Template"))
-                .ok();
+    loop {
+        // A scenario that fails to parse keeps the previous files so fixing it starts a run
+        if let Ok(found) = ScenarioSources::new(&run.scenario) {
+            sources = found;
+        }
+        watched = watch_sources(&mut watcher, watched, &sources);
+
+        let shutdown = Shutdown::new();
+        *current.lock().unwrap() = shutdown.clone();
+        spawn_run(run.clone(), shutdown.clone(), send.clone());
+
+        let mut running = true;
+        let mut interrupted = false;
+        let mut restarting = false;
+
+        loop {
+            match recv.recv().unwrap() {
+                WatchEvent::Finished(code) => {
+                    running = false;
+
+                    if interrupted {
+                        std::process::exit(code);
+                    }
+                    if restarting {
+                        break;
+                    }
+                    eprintln!("Waiting for changes to `{}`", run.scenario);
+                }
+                WatchEvent::Interrupted if running => interrupted = true,
+                WatchEvent::Interrupted => std::process::exit(130),
+                WatchEvent::Changed(paths) => {
+                    if !paths.iter().any(|path| sources.contains(path)) {
+                        continue;
+                    }
+                    if !running {
+                        break;
+                    }
+                    restarting = true;
+                    shutdown.shutdown();
+                }
+            }
+        }
 
-            if debug {
-                println!("{program}");
+        // Editors often write a file in several steps
+        loop {
+            match recv.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(WatchEvent::Interrupted) => std::process::exit(130),
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
             }
-            state.new_scope();
-            program.run(&mut test_bed, &mut state, &shutdown).unwrap();
-            state.pop_scope();
         }
 
-        for (name, program) in command_programs {
-            match name {
-                Some(name) => test_bed
-                    .multibar
-                    .println(format!("Running `{name}` Program"))
-                    .ok(),
-                None => test_bed
-                    .multibar
-                    .println(format!("Running Default Program"))
-                    .ok(),
-            };
-
-            if debug {
-                println!("{program}");
-            }
-
-            state.new_scope();
-            program.run(&mut test_bed, &mut state, &shutdown).unwrap();
-            state.pop_scope();
-            test_bed.reset(&shutdown);
+        eprintln!("Change detected, running `{}` again", run.scenario);
+    }
+}
+
+/// Watches the directories holding `sources` instead of the files themselves, since editors
+/// often replace a file rather than write to it. Returns the watched paths.
+fn watch_sources(
+    watcher: &mut RecommendedWatcher,
+    watched: Vec<PathBuf>,
+    sources: &ScenarioSources,
+) -> Vec<PathBuf> {
+    for path in watched {
+        watcher.unwatch(&path).ok();
+    }
+
+    let mut watched = vec![];
+    let parents = sources.files.iter().filter_map(|file| file.parent());
+
+    for (path, mode) in parents.map(|dir| (dir, RecursiveMode::NonRecursive)).chain(
+        sources
+            .template_dirs
+            .iter()
+            .map(|dir| (dir.as_path(), RecursiveMode::Recursive)),
+    ) {
+        if watched.iter().any(|watched: &PathBuf| watched == path) {
+            continue;
+        }
+        if let Err(e) = watcher.watch(path, mode) {
+            eprintln!("Failed to watch `{}`: {e}", path.display());
+            continue;
         }
+        watched.push(path.to_path_buf());
+    }
+
+    watched
+}
+
+/// Runs the scenario on its own thread and sends its exit code once it finishes
+fn spawn_run(run: Arc<Run>, shutdown: Shutdown, send: Sender<WatchEvent>) {
+    if let Some(timeout) = run.global_timeout {
+        let shutdown = shutdown.clone();
+
+        std::thread::spawn(move || {
+            std::thread::sleep(timeout);
+            if !shutdown.shutdown() {
+                eprintln!("Global timeout of {timeout:?} reached, shutting down");
+            }
+        });
+    }
+
+    std::thread::spawn(move || {
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            run_scenario_with(
+                Path::new(&run.scenario),
+                run.selection.clone(),
+                run.params.clone(),
+                &run.options,
+                &shutdown,
+            )
+        }));
+
+        let code = match result {
+            Ok(Ok(summary)) => {
+                if !run.quiet {
+                    print!("{}", summary.report);
+                }
+                summary.exit_code(run.ignore_failures)
+            }
+            Ok(Err(e)) => {
+                eprintln!("{e}");
+                1
+            }
+            Err(_) => 101,
+        };
 
-        send.send(()).ok();
+        send.send(WatchEvent::Finished(code)).ok();
     });
+}
+
+/// Parses durations like `300s`, `500ms`, `5m` or `1h`, a bare number is seconds
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().ok()?;
+
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "" | "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        "h" => Some(Duration::from_secs(number.checked_mul(60 * 60)?)),
+        _ => None,
+    }
+}
+
+/// Reads a JSON object, each key is a variable and its value becomes the variable's object
+fn parse_var_file(path: &str) -> Result<Vec<(String, serde_json::Value)>, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    let vars: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&contents)?;
+
+    Ok(vars.into_iter().collect())
+}
+
+/// Reads `KEY=VALUE` lines, skipping blank lines and `#` comments. Everything after the first
+/// `=` is the value, a single pair of surrounding quotes is removed.
+fn parse_env_file(path: &str) -> std::io::Result<Vec<(String, String)>> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut env = vec![];
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
 
-    recv.recv().unwrap();
+        let Some((key, value)) = line.split_once('=') else {
+            panic!("Expected KEY=VALUE on line {} of `{path}`", number + 1);
+        };
+
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+            .unwrap_or(value);
+
+        env.push((key.trim().to_string(), value.to_string()));
+    }
+
+    Ok(env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_units() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("1d"), None);
+    }
+
+    #[test]
+    fn overflowing_duration_is_rejected() {
+        assert_eq!(parse_duration("18446744073709551615m"), None);
+        assert_eq!(parse_duration("18446744073709551615h"), None);
+        assert_eq!(
+            parse_duration("18446744073709551615s"),
+            Some(Duration::from_secs(u64::MAX))
+        );
+    }
 }
@@ -1,29 +1,121 @@
+use indexmap::IndexMap;
+
 use crate::{
-    bed::commands::Command,
-    program::{Instruction, InstructionId, Program},
+    bed::{
+        commands::Command,
+        expr::{IterTargetExpr, RangeExpr},
+    },
+    program::{Instruction, InstructionId, Program, VarNameId, VarNames},
+    Error,
 };
 
-use super::CommandExpr;
+use super::{build_outer, scope_depth, CommandExpr, CommandMacro, ForLoop, ForLoopType};
+
+/// The `def` blocks a command program can `call`
+pub struct Macros<'a> {
+    defs: &'a IndexMap<VarNameId, CommandMacro>,
+    names: &'a VarNames,
+    /// Macros currently being inlined, calling one of them again would never finish
+    calling: Vec<VarNameId>,
+}
+
+impl<'a> Macros<'a> {
+    pub fn new(defs: &'a IndexMap<VarNameId, CommandMacro>, names: &'a VarNames) -> Self {
+        Self {
+            defs,
+            names,
+            calling: vec![],
+        }
+    }
+
+    fn name(&self, id: VarNameId) -> &'a str {
+        self.names.evaluate(id).unwrap()
+    }
 
-pub fn build_commands_program(exprs: impl Iterator<Item = CommandExpr>) -> Program<Command> {
+    /// The macro a `call` inlines, an error if it doesn't exist, is already being inlined or
+    /// the arguments don't match its params
+    fn enter(
+        &mut self,
+        name: VarNameId,
+        args: &[(VarNameId, impl Sized)],
+    ) -> Result<&'a CommandMacro, Error> {
+        let Some(def) = self.defs.get(&name) else {
+            return Err(Error::Parse(format!(
+                "`call {}` has no matching `def`",
+                self.name(name)
+            )));
+        };
+
+        if self.calling.contains(&name) {
+            let cycle: Vec<_> = self
+                .calling
+                .iter()
+                .chain([&name])
+                .map(|id| self.name(*id))
+                .collect();
+            return Err(Error::Parse(format!(
+                "Recursive call: {}",
+                cycle.join(" -> ")
+            )));
+        }
+
+        for param in def.params.iter() {
+            if !args.iter().any(|(arg, _)| arg == param) {
+                return Err(Error::Parse(format!(
+                    "`call {}` is missing the param `{}`",
+                    self.name(name),
+                    self.name(*param)
+                )));
+            }
+        }
+        for (i, (arg, _)) in args.iter().enumerate() {
+            if !def.params.contains(arg) || args[..i].iter().any(|(other, _)| other == arg) {
+                return Err(Error::Parse(format!(
+                    "`call {}` has an unknown or repeated param `{}`",
+                    self.name(name),
+                    self.name(*arg)
+                )));
+            }
+        }
+
+        self.calling.push(name);
+        Ok(def)
+    }
+}
+
+/// Fails with `Error::Parse` if a `call` can't be inlined, see `Macros::enter`
+pub fn build_commands_program(
+    exprs: impl Iterator<Item = CommandExpr>,
+    mut macros: Macros,
+) -> Result<Program<Command>, Error> {
     let mut instructions = vec![];
 
     for value in exprs {
-        build_expr(value, &mut instructions);
+        build_expr(value, &mut instructions, None, &mut macros)?;
     }
 
-    Program(instructions)
+    Ok(Program(instructions))
 }
 
-pub fn build_expr(expr: CommandExpr, instructions: &mut Vec<Instruction<Command>>) {
+/// `outer` is the scope depth enclosing the innermost loop, see `CommandExpr::Outer`
+pub fn build_expr(
+    expr: CommandExpr,
+    instructions: &mut Vec<Instruction<Command>>,
+    outer: Option<usize>,
+    macros: &mut Macros,
+) -> Result<(), Error> {
     match expr {
         CommandExpr::Command(command) => instructions.push(command),
+        CommandExpr::Outer(command) => instructions.push(build_outer(command, outer)),
         CommandExpr::ForLoop { for_loop, exprs } => {
+            let outer = Some(scope_depth(instructions));
+            let mut built = Ok(());
             for_loop.build(instructions, |instructions| {
-                for expr in exprs {
-                    build_expr(expr, instructions);
-                }
+                built = exprs
+                    .into_iter()
+                    .try_for_each(|expr| build_expr(expr, instructions, outer, macros));
             });
+            built?;
         }
         CommandExpr::If { conditions, exprs } => {
             let start = instructions.len();
@@ -39,19 +131,105 @@ pub fn build_expr(expr: CommandExpr, instructions: &mut Vec<Instruction<Command>
             instructions.push(Instruction::PushScope);
 
             for expr in exprs {
-                build_expr(expr, instructions);
+                build_expr(expr, instructions, outer, macros)?;
             }
 
             instructions.push(Instruction::PopScope);
             let jump_target = instructions.len();
 
-            for i in start..end {
-                let Instruction::ConditionalJump { jump, .. } = &mut instructions[i] else { 
-                    unreachable!() 
+            for instruction in instructions[start..end].iter_mut() {
+                let Instruction::ConditionalJump { jump, .. } = instruction else {
+                    unreachable!()
                 };
 
                 jump.0 = jump_target;
             }
         }
+        CommandExpr::Group { name, exprs } => {
+            instructions.push(Instruction::Command(Command::StartGroup(name)));
+            instructions.push(Instruction::PushScope);
+
+            for expr in exprs {
+                build_expr(expr, instructions, outer, macros)?;
+            }
+
+            instructions.push(Instruction::PopScope);
+            instructions.push(Instruction::Command(Command::EndGroup));
+        }
+        CommandExpr::CwdEach { dir, exprs } => {
+            let block = instructions.len();
+            instructions.push(Instruction::Command(Command::PushDir { dir, block }));
+            instructions.push(Instruction::PushScope);
+
+            for expr in exprs {
+                build_expr(expr, instructions, outer, macros)?;
+            }
+
+            instructions.push(Instruction::PopScope);
+            instructions.push(Instruction::Command(Command::PopDir));
+        }
+        CommandExpr::Repeat { count, iter, exprs } => {
+            let for_loop = ForLoop {
+                ty: ForLoopType::Combinations,
+                iters: vec![iter],
+                targets: vec![IterTargetExpr::Range {
+                    start: RangeExpr::Integer(0),
+                    end: count,
+                }],
+                kept: vec![],
+                matrix: false,
+            };
+
+            let outer = Some(scope_depth(instructions));
+            let mut built = Ok(());
+            for_loop.build(instructions, |instructions| {
+                built = exprs
+                    .into_iter()
+                    .try_for_each(|expr| build_expr(expr, instructions, outer, macros));
+            });
+            built?;
+        }
+        CommandExpr::Timeout { millis, exprs } => {
+            let start = instructions.len();
+            instructions.push(Instruction::StartDeadline {
+                millis,
+                end: InstructionId(0),
+            });
+            instructions.push(Instruction::PushScope);
+
+            for expr in exprs {
+                build_expr(expr, instructions, outer, macros)?;
+            }
+
+            instructions.push(Instruction::PopScope);
+            instructions.push(Instruction::EndDeadline);
+            let jump_target = instructions.len();
+
+            let Instruction::StartDeadline { end, .. } = &mut instructions[start] else {
+                unreachable!()
+            };
+            end.0 = jump_target;
+        }
+        CommandExpr::Call { name, args } => {
+            let def = macros.enter(name, &args)?;
+            instructions.push(Instruction::PushScope);
+
+            // Bound in order, so an argument sees the params before it
+            for (param, value) in args {
+                instructions.push(Instruction::CreateVar {
+                    target: param,
+                    scope: None,
+                    value,
+                });
+            }
+            for expr in def.exprs.iter().cloned() {
+                build_expr(expr, instructions, outer, macros)?;
+            }
+
+            instructions.push(Instruction::PopScope);
+            macros.calling.pop();
+        }
     }
+
+    Ok(())
 }
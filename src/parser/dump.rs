@@ -0,0 +1,565 @@
+use serde_json::{json, Map, Value};
+
+use crate::{
+    bed::{
+        commands::{ArgBuilder, Command, OutputMap, Spawn},
+        expr::{
+            CompareExpr, Condition, IterTargetExpr, ObjectExpr, RangeExpr, StringExpr,
+            StringInstance,
+        },
+        templates::{BuildObjectExpr, BuildTargets, TemplateCommand, YieldExpr},
+    },
+    program::{Instruction, VarFieldId, VarNameId},
+};
+
+use super::{format::DslWriter, CommandExpr, ForLoop, ForLoopType, Parsed, TemplateExpr};
+
+/// Bumped whenever a field is removed or changes meaning, adding fields doesn't change it
+pub const DUMP_VERSION: u64 = 1;
+
+/// Describes the parsed scenario as JSON for editors and linters. Every node is an object with
+/// a `kind`, variable names are resolved and variable accesses are written the way the
+/// scenario writes them, e.g. `server.ports[0]`.
+pub fn dump_parsed(parsed: &Parsed) -> Value {
+    let dumper = Dumper {
+        writer: DslWriter::new(&parsed.names),
+        parsed,
+    };
+
+    dumper.parsed()
+}
+
+struct Dumper<'a> {
+    writer: DslWriter<'a>,
+    parsed: &'a Parsed,
+}
+
+impl Dumper<'_> {
+    fn name(&self, id: VarNameId) -> Value {
+        json!(self.parsed.names.evaluate(id).unwrap())
+    }
+
+    fn parsed(&self) -> Value {
+        let parsed = self.parsed;
+
+        let matrix: Vec<_> = parsed
+            .matrix
+            .iter()
+            .map(|axis| {
+                json!({
+                    "name": self.name(axis.name),
+                    "value": self.object(&axis.value),
+                })
+            })
+            .collect();
+
+        let templates: Vec<_> = parsed
+            .templates
+            .iter()
+            .map(|(name, exprs)| {
+                json!({
+                    "name": self.name(*name),
+                    "body": self.template_exprs(exprs),
+                })
+            })
+            .collect();
+
+        let macros: Vec<_> = parsed
+            .macros
+            .iter()
+            .map(|(name, def)| {
+                let params: Vec<_> = def.params.iter().map(|id| self.name(*id)).collect();
+
+                json!({
+                    "name": self.name(*name),
+                    "params": params,
+                    "body": self.command_exprs(&def.exprs),
+                })
+            })
+            .collect();
+
+        let commands: Vec<_> = parsed
+            .commands
+            .iter()
+            .map(|(name, exprs)| {
+                let requires: Vec<_> = parsed
+                    .requires
+                    .get(name)
+                    .into_iter()
+                    .flatten()
+                    .map(|id| self.name(*id))
+                    .collect();
+
+                json!({
+                    "name": name.map(|name| self.name(name)),
+                    "requires": requires,
+                    "body": self.command_exprs(exprs),
+                    "on_failure": parsed
+                        .on_failure
+                        .get(name)
+                        .map(|exprs| self.command_exprs(exprs)),
+                })
+            })
+            .collect();
+
+        let globals: Vec<_> = parsed
+            .globals
+            .0
+            .iter()
+            .map(|instruction| self.instruction(instruction, |_, _| unreachable!()))
+            .collect();
+
+        json!({
+            "version": DUMP_VERSION,
+            "includes": parsed.includes,
+            "output": parsed.output,
+            "globals": globals,
+            "matrix": matrix,
+            "templates": templates,
+            "macros": macros,
+            "commands": commands,
+        })
+    }
+
+    fn template_exprs(&self, exprs: &[TemplateExpr]) -> Value {
+        let exprs: Vec<_> = exprs
+            .iter()
+            .map(|expr| match expr {
+                TemplateExpr::Command(instruction) => {
+                    self.instruction(instruction, Self::template_command)
+                }
+                TemplateExpr::Outer(instruction) => {
+                    let mut value = self.instruction(instruction, Self::template_command);
+                    value["outer"] = json!(true);
+                    value
+                }
+                TemplateExpr::ForLoop { for_loop, exprs } => {
+                    let mut value = self.for_loop(for_loop);
+                    value["body"] = self.template_exprs(exprs);
+                    value
+                }
+                TemplateExpr::If { conditions, exprs } => json!({
+                    "kind": "if",
+                    "conditions": self.conditions(conditions),
+                    "body": self.template_exprs(exprs),
+                }),
+            })
+            .collect();
+
+        json!(exprs)
+    }
+
+    fn command_exprs(&self, exprs: &[CommandExpr]) -> Value {
+        let exprs: Vec<_> = exprs
+            .iter()
+            .map(|expr| match expr {
+                CommandExpr::Command(instruction) => self.instruction(instruction, Self::command),
+                CommandExpr::Outer(instruction) => {
+                    let mut value = self.instruction(instruction, Self::command);
+                    value["outer"] = json!(true);
+                    value
+                }
+                CommandExpr::ForLoop { for_loop, exprs } => {
+                    let mut value = self.for_loop(for_loop);
+                    value["body"] = self.command_exprs(exprs);
+                    value
+                }
+                CommandExpr::If { conditions, exprs } => json!({
+                    "kind": "if",
+                    "conditions": self.conditions(conditions),
+                    "body": self.command_exprs(exprs),
+                }),
+                CommandExpr::Group { name, exprs } => json!({
+                    "kind": "group",
+                    "name": self.string(name),
+                    "body": self.command_exprs(exprs),
+                }),
+                CommandExpr::CwdEach { dir, exprs } => json!({
+                    "kind": "cwd_each",
+                    "dir": self.string(dir),
+                    "body": self.command_exprs(exprs),
+                }),
+                CommandExpr::Repeat { count, exprs, .. } => json!({
+                    "kind": "repeat",
+                    "count": self.range_expr(count),
+                    "body": self.command_exprs(exprs),
+                }),
+                CommandExpr::Timeout { millis, exprs } => json!({
+                    "kind": "timeout",
+                    "millis": millis,
+                    "body": self.command_exprs(exprs),
+                }),
+                CommandExpr::Call { name, args } => {
+                    // A list rather than an object, args are bound in order
+                    let args: Vec<_> = args
+                        .iter()
+                        .map(|(param, value)| {
+                            json!({ "name": self.name(*param), "value": self.object(value) })
+                        })
+                        .collect();
+
+                    json!({ "kind": "call", "name": self.name(*name), "args": args })
+                }
+            })
+            .collect();
+
+        json!(exprs)
+    }
+
+    /// The instructions a parsed statement can produce, `command` describes `T`
+    fn instruction<T>(
+        &self,
+        instruction: &Instruction<T>,
+        command: impl Fn(&Self, &T) -> Value,
+    ) -> Value {
+        match instruction {
+            Instruction::CreateVar { target, value, .. } => json!({
+                "kind": "create",
+                "target": self.name(*target),
+                "value": self.object(value),
+            }),
+            Instruction::AssignVar { target, value, .. } => json!({
+                "kind": "assign",
+                "target": self.name(*target),
+                "value": self.object(value),
+            }),
+            Instruction::Print(access) => json!({ "kind": "print", "value": self.access(access) }),
+            Instruction::UnsetVar(target) => {
+                json!({ "kind": "unset", "target": self.name(*target) })
+            }
+            Instruction::PushList { target, object } => json!({
+                "kind": "push",
+                "target": self.name(*target),
+                "value": self.object(object),
+            }),
+            Instruction::Command(value) => command(self, value),
+            _ => unreachable!(),
+        }
+    }
+
+    fn template_command(&self, command: &TemplateCommand) -> Value {
+        match command {
+            TemplateCommand::BuildAssign { output, object } => json!({
+                "kind": "build_assign",
+                "target": self.name(*output),
+                "value": self.build_object(object),
+            }),
+            TemplateCommand::Yield { objects, .. } => {
+                let values: Vec<_> = objects
+                    .iter()
+                    .map(|object| match object {
+                        YieldExpr::Build(object) => self.build_object(object),
+                        YieldExpr::Object(object) => self.object(object),
+                    })
+                    .collect();
+
+                // A single object keeps the `value` key so existing dumps are unchanged
+                match values.len() {
+                    1 => json!({ "kind": "yield", "value": values[0] }),
+                    _ => json!({ "kind": "yield", "values": values }),
+                }
+            }
+        }
+    }
+
+    fn build_object(&self, object: &BuildObjectExpr) -> Value {
+        let properties = self.properties(object.properties.iter(), |property| {
+            json!({
+                "value": self.object(&property.value),
+                "optional": property.optional,
+            })
+        });
+
+        match &object.targets {
+            BuildTargets::One(target) => json!({
+                "kind": "build",
+                "template": self.string(&target.template),
+                "output": self.string(&target.output),
+                "properties": properties,
+            }),
+            BuildTargets::Many(targets) => {
+                let targets: Vec<_> = targets
+                    .iter()
+                    .map(|target| {
+                        json!({
+                            "template": self.string(&target.template),
+                            "output": self.string(&target.output),
+                        })
+                    })
+                    .collect();
+
+                json!({
+                    "kind": "build_many",
+                    "targets": targets,
+                    "properties": properties,
+                })
+            }
+        }
+    }
+
+    fn command(&self, command: &Command) -> Value {
+        match command {
+            Command::LimitSpawn(limit) => json!({ "kind": "limit", "value": limit }),
+            Command::Sleep(millis) => json!({ "kind": "sleep", "millis": millis }),
+            Command::WaitAll(millis) => json!({ "kind": "wait_all", "millis": millis }),
+            Command::WaitFile { path, timeout } => json!({
+                "kind": "wait_file",
+                "path": self.string(path),
+                "timeout": timeout,
+            }),
+            Command::Copy { src, dst } => json!({
+                "kind": "copy",
+                "src": self.string(src),
+                "dst": self.string(dst),
+            }),
+            Command::Symlink { src, dst } => json!({
+                "kind": "symlink",
+                "src": self.string(src),
+                "dst": self.string(dst),
+            }),
+            Command::Spawn(spawn) => self.spawn("spawn", spawn),
+            Command::Run(spawn) => self.spawn("run", spawn),
+            Command::Terminate(name) => json!({ "kind": "terminate", "name": self.string(name) }),
+            Command::Assert { cond, message } => json!({
+                "kind": "assert",
+                "condition": self.condition(cond),
+                "message": self.string(message),
+            }),
+            Command::StartGroup(_)
+            | Command::EndGroup
+            | Command::PushDir { .. }
+            | Command::PopDir => {
+                unreachable!()
+            }
+        }
+    }
+
+    fn spawn(&self, kind: &str, spawn: &Spawn) -> Value {
+        let args: Vec<_> = spawn
+            .args
+            .iter()
+            .map(|arg| match arg {
+                ArgBuilder::String(value) => {
+                    json!({ "kind": "string", "value": self.string(value) })
+                }
+                ArgBuilder::Set(access) => json!({ "kind": "set", "value": self.access(access) }),
+                ArgBuilder::Json(access) => json!({ "kind": "json", "value": self.access(access) }),
+                ArgBuilder::Combo => json!({ "kind": "combo" }),
+                ArgBuilder::File(path) => json!({ "kind": "file", "path": self.string(path) }),
+                ArgBuilder::Spread(access) => {
+                    json!({ "kind": "spread", "value": self.access(access) })
+                }
+            })
+            .collect();
+
+        json!({
+            "kind": kind,
+            "detach": spawn.detach,
+            "clear_env": spawn.clear_env,
+            "name": spawn.name.as_ref().map(|name| self.string(name)),
+            "nice": spawn.nice,
+            "dir": spawn.working_dir.as_ref().map(|dir| self.string(dir)),
+            "filter": spawn.filter.as_ref().map(|filter| self.string(filter)),
+            "progress": spawn.progress.as_ref().map(|progress| self.string(progress)),
+            "stdout": self.output_map(&spawn.stdout),
+            "stderr": self.output_map(&spawn.stderr),
+            "command": self.string(&spawn.command),
+            "args": args,
+        })
+    }
+
+    fn output_map(&self, map: &OutputMap<StringExpr>) -> Value {
+        match map {
+            OutputMap::Print => json!({ "kind": "print" }),
+            OutputMap::Create(path) => json!({ "kind": "create", "path": self.string(path) }),
+            OutputMap::Append(path) => json!({ "kind": "append", "path": self.string(path) }),
+            OutputMap::Tee(path) => json!({ "kind": "tee", "path": self.string(path) }),
+            OutputMap::Rotate {
+                path,
+                max_bytes,
+                keep,
+            } => json!({
+                "kind": "rotate",
+                "path": self.string(path),
+                "max_bytes": max_bytes,
+                "keep": keep,
+            }),
+            OutputMap::Limited { path, max_bytes } => json!({
+                "kind": "limited",
+                "path": self.string(path),
+                "max_bytes": max_bytes,
+            }),
+        }
+    }
+
+    fn for_loop(&self, for_loop: &ForLoop) -> Value {
+        let ty = match &for_loop.ty {
+            ForLoopType::Combinations => json!({ "kind": "combinations" }),
+            ForLoopType::Group => json!({ "kind": "group" }),
+            ForLoopType::Sample { count, .. } => json!({ "kind": "sample", "count": count }),
+        };
+        let iters: Vec<_> = for_loop.iters.iter().map(|id| self.name(*id)).collect();
+        let targets: Vec<_> = for_loop
+            .targets
+            .iter()
+            .map(|target| self.iter_target(target))
+            .collect();
+
+        json!({
+            "kind": "for",
+            "type": ty,
+            "keep": !for_loop.kept.is_empty(),
+            "matrix": for_loop.matrix,
+            "iters": iters,
+            "targets": targets,
+        })
+    }
+
+    fn iter_target(&self, target: &IterTargetExpr) -> Value {
+        match target {
+            IterTargetExpr::Variable(id) => json!({ "kind": "variable", "name": self.name(*id) }),
+            IterTargetExpr::Range { start, end } => json!({
+                "kind": "range",
+                "start": self.range_expr(start),
+                "end": self.range_expr(end),
+            }),
+            IterTargetExpr::Chain { parts, .. } => {
+                let parts: Vec<_> = parts.iter().map(|part| self.iter_target(part)).collect();
+                json!({ "kind": "chain", "parts": parts })
+            }
+            IterTargetExpr::Inline { values, .. } => {
+                let values: Vec<_> = values.iter().map(|value| self.object(value)).collect();
+                json!({ "kind": "list", "values": values })
+            }
+        }
+    }
+
+    fn range_expr(&self, expr: &RangeExpr) -> Value {
+        match expr {
+            RangeExpr::Integer(value) => json!({ "kind": "integer", "value": value }),
+            RangeExpr::Variable(value) => json!({ "kind": "string", "value": self.string(value) }),
+            RangeExpr::Len(access) => json!({ "kind": "len", "value": self.access(access) }),
+        }
+    }
+
+    fn conditions(&self, conditions: &[Condition]) -> Value {
+        json!(conditions
+            .iter()
+            .map(|condition| self.condition(condition))
+            .collect::<Vec<_>>())
+    }
+
+    fn condition(&self, condition: &Condition) -> Value {
+        match condition {
+            Condition::Variable(access) => {
+                json!({ "kind": "variable", "value": self.access(access) })
+            }
+            Condition::Exists(access) => json!({ "kind": "exists", "value": self.access(access) }),
+            Condition::Equals(lhs, rhs) => json!({
+                "kind": "equals",
+                "lhs": self.compared(lhs),
+                "rhs": self.compared(rhs),
+            }),
+            Condition::NotEquals(lhs, rhs) => json!({
+                "kind": "not_equals",
+                "lhs": self.compared(lhs),
+                "rhs": self.compared(rhs),
+            }),
+            Condition::In { value, list } => json!({
+                "kind": "in",
+                "value": self.access(value),
+                "list": self.access(list),
+            }),
+        }
+    }
+
+    fn compared(&self, expr: &CompareExpr) -> Value {
+        match expr {
+            CompareExpr::String(value) => self.string(value),
+            CompareExpr::Len(access) => json!({ "kind": "len", "value": self.access(access) }),
+        }
+    }
+
+    fn object(&self, object: &ObjectExpr) -> Value {
+        match object {
+            ObjectExpr::Clone(access) | ObjectExpr::Deref(access) => {
+                json!({ "kind": "clone", "value": self.access(access) })
+            }
+            ObjectExpr::Ref(access) => json!({ "kind": "ref", "value": self.access(access) }),
+            ObjectExpr::BoolLit(value) => json!({ "kind": "bool", "value": value }),
+            ObjectExpr::Condition(condition) => {
+                json!({ "kind": "condition", "condition": self.condition(condition) })
+            }
+            ObjectExpr::IntLit(value) => json!({ "kind": "int", "value": value }),
+            ObjectExpr::StrLit(value) => json!({ "kind": "string", "value": value }),
+            ObjectExpr::List(values) => {
+                let values: Vec<_> = values.iter().map(|value| self.object(value)).collect();
+                json!({ "kind": "list", "values": values })
+            }
+            ObjectExpr::Counter(start, end) => json!({
+                "kind": "range",
+                "start": self.range_expr(start),
+                "end": self.range_expr(end),
+            }),
+            ObjectExpr::Struct(value) => {
+                let properties =
+                    self.properties(value.properties.iter(), |value| self.object(value));
+
+                json!({
+                    "kind": "struct",
+                    "base": self.string(&value.base),
+                    "properties": properties,
+                })
+            }
+            ObjectExpr::Comprehension { body, iter, target } => json!({
+                "kind": "comprehension",
+                "body": self.object(body),
+                "iter": self.name(*iter),
+                "target": self.iter_target(target),
+            }),
+        }
+    }
+
+    /// Properties sorted by name so the output doesn't depend on hash order
+    fn properties<'b, T: 'b>(
+        &self,
+        properties: impl Iterator<Item = (&'b VarNameId, &'b T)>,
+        value: impl Fn(&T) -> Value,
+    ) -> Map<String, Value> {
+        let mut properties: Vec<_> = properties
+            .map(|(name, property)| {
+                let name = self.parsed.names.evaluate(*name).unwrap().to_string();
+                (name, value(property))
+            })
+            .collect();
+        properties.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        properties.into_iter().collect()
+    }
+
+    fn access(&self, access: &VarFieldId) -> Value {
+        json!(self.writer.access(access))
+    }
+
+    fn string(&self, expr: &StringExpr) -> Value {
+        let parts: Vec<_> = expr
+            .0
+            .iter()
+            .map(|value| match value {
+                StringInstance::String(value) => json!({ "kind": "string", "value": value }),
+                StringInstance::Variable(access) => {
+                    json!({ "kind": "variable", "value": self.access(access) })
+                }
+                StringInstance::Default(access, default) => json!({
+                    "kind": "default",
+                    "value": self.access(access),
+                    "default": default,
+                }),
+                StringInstance::Env(name) => json!({ "kind": "env", "name": name }),
+                StringInstance::Now(format) => json!({ "kind": "now", "format": format }),
+            })
+            .collect();
+
+        json!(parts)
+    }
+}
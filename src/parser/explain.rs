@@ -0,0 +1,301 @@
+use std::fmt::Write;
+
+use crate::{
+    bed::{
+        commands::Command,
+        expr::{IterTargetExpr, ObjectExpr, RangeExpr},
+    },
+    program::{Instruction, VarNameId},
+};
+
+use super::{format::DslWriter, CommandExpr, ForLoop, ForLoopType, Parsed};
+
+const INDENT: &str = "  ";
+
+/// A number of iterations or spawns worked out without running anything
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Estimate {
+    Exact(u64),
+    /// Inside an `if`, `when` or `timeout` block so it may be fewer
+    AtMost(u64),
+    /// Depends on a variable or `len(..)`
+    Unknown,
+}
+
+impl Estimate {
+    fn bound(self) -> Option<u64> {
+        match self {
+            Estimate::Exact(value) | Estimate::AtMost(value) => Some(value),
+            Estimate::Unknown => None,
+        }
+    }
+
+    fn at_most(self) -> Self {
+        match self {
+            Estimate::Exact(value) => Estimate::AtMost(value),
+            estimate => estimate,
+        }
+    }
+
+    fn plus(self, other: Self) -> Self {
+        match (self, other) {
+            (Estimate::Exact(a), Estimate::Exact(b)) => Estimate::Exact(a.saturating_add(b)),
+            (a, b) => match (a.bound(), b.bound()) {
+                (Some(a), Some(b)) => Estimate::AtMost(a.saturating_add(b)),
+                _ => Estimate::Unknown,
+            },
+        }
+    }
+
+    fn times(self, other: Self) -> Self {
+        match (self, other) {
+            (Estimate::Exact(0), _) | (_, Estimate::Exact(0)) => Estimate::Exact(0),
+            (Estimate::Exact(a), Estimate::Exact(b)) => Estimate::Exact(a.saturating_mul(b)),
+            (a, b) => match (a.bound(), b.bound()) {
+                (Some(a), Some(b)) => Estimate::AtMost(a.saturating_mul(b)),
+                _ => Estimate::Unknown,
+            },
+        }
+    }
+
+    /// A group loop stops with its shortest iterable, an unknown one can only make it shorter
+    fn min(self, other: Self) -> Self {
+        match (self, other) {
+            (Estimate::Exact(a), Estimate::Exact(b)) => Estimate::Exact(a.min(b)),
+            (Estimate::Unknown, Estimate::Unknown) => Estimate::Unknown,
+            (a, b) => {
+                let bound = a.bound().into_iter().chain(b.bound()).min().unwrap();
+                Estimate::AtMost(bound)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Estimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Estimate::Exact(value) => write!(f, "{value}"),
+            Estimate::AtMost(value) => write!(f, "up to {value}"),
+            Estimate::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Prints the loops, conditional blocks and spawns of a command block as a tree along with the
+/// number of iterations of each loop and the total number of times each spawn runs. Only
+/// literal bounds and `matrix` axes are counted, nothing is evaluated. Spawns in the block's
+/// `on_failure` are counted as `up to`.
+pub fn explain_program(
+    parsed: &Parsed,
+    exprs: &[CommandExpr],
+    on_failure: Option<&[CommandExpr]>,
+) -> String {
+    let mut explainer = Explainer {
+        parsed,
+        writer: DslWriter::new(&parsed.names),
+        output: String::new(),
+        depth: 0,
+        calling: vec![],
+    };
+
+    let mut spawns = explainer.exprs(exprs, Estimate::Exact(1));
+
+    if let Some(exprs) = on_failure {
+        explainer.line("on_failure: runs if the block fails");
+        spawns = spawns.plus(explainer.block(exprs, Estimate::Exact(1).at_most()));
+    }
+
+    writeln!(explainer.output, "Estimated spawns: {spawns}").unwrap();
+
+    explainer.output
+}
+
+struct Explainer<'a> {
+    parsed: &'a Parsed,
+    writer: DslWriter<'a>,
+    output: String,
+    depth: usize,
+    /// Macros being explained, a recursive `call` is shown but not followed
+    calling: Vec<VarNameId>,
+}
+
+impl Explainer<'_> {
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.depth {
+            self.output.push_str(INDENT);
+        }
+        self.output.push_str(line);
+        self.output.push('\n');
+    }
+
+    /// `runs` is how many times the block is entered, returns the spawns inside it
+    fn exprs(&mut self, exprs: &[CommandExpr], runs: Estimate) -> Estimate {
+        let mut spawns = Estimate::Exact(0);
+
+        for expr in exprs {
+            spawns = spawns.plus(self.expr(expr, runs));
+        }
+
+        spawns
+    }
+
+    fn expr(&mut self, expr: &CommandExpr, runs: Estimate) -> Estimate {
+        match expr {
+            CommandExpr::Command(Instruction::Command(command)) => {
+                let line = match command {
+                    Command::Spawn(spawn) => self.writer.spawn("spawn", spawn),
+                    Command::Run(spawn) => self.writer.spawn("run", spawn),
+                    _ => return Estimate::Exact(0),
+                };
+                self.line(&format!("{line}: {}", counted(runs, "spawn")));
+                runs
+            }
+            CommandExpr::Command(_) | CommandExpr::Outer(_) => Estimate::Exact(0),
+            CommandExpr::ForLoop { for_loop, exprs } => {
+                let (sizes, iterations) = self.for_loop(for_loop);
+                let header = self.writer.for_loop(for_loop);
+                self.line(&format!(
+                    "{header}: {sizes}{}",
+                    counted(iterations, "iteration")
+                ));
+                self.block(exprs, runs.times(iterations))
+            }
+            CommandExpr::Repeat { count, exprs, .. } => {
+                let iterations = range_len(&RangeExpr::Integer(0), count);
+                let header = format!("repeat {}", self.writer.range_expr(count));
+                self.line(&format!("{header}: {}", counted(iterations, "iteration")));
+                self.block(exprs, runs.times(iterations))
+            }
+            CommandExpr::If { conditions, exprs } => {
+                let header = self.writer.conditions(conditions);
+                self.line(&format!("{header}: may be skipped"));
+                self.block(exprs, runs.at_most())
+            }
+            CommandExpr::Timeout { millis, exprs } => {
+                self.line(&format!("timeout {millis}: may be cut short"));
+                self.block(exprs, runs.at_most())
+            }
+            CommandExpr::Group { name, exprs } => {
+                let header = format!("group {}", self.writer.string(name));
+                self.line(&header);
+                self.block(exprs, runs)
+            }
+            CommandExpr::CwdEach { dir, exprs } => {
+                let header = format!("cwd_each {}", self.writer.string(dir));
+                self.line(&header);
+                self.block(exprs, runs)
+            }
+            CommandExpr::Call { name, .. } => {
+                let header = self.writer.call(expr);
+                let header = header.trim_end_matches(';');
+                let Some(def) = self.parsed.macros.get(name) else {
+                    self.line(&format!("{header}: no matching def"));
+                    return Estimate::Exact(0);
+                };
+                if self.calling.contains(name) {
+                    self.line(&format!("{header}: recursive"));
+                    return Estimate::Unknown;
+                }
+
+                self.line(header);
+                self.calling.push(*name);
+                let spawns = self.block(&def.exprs, runs);
+                self.calling.pop();
+                spawns
+            }
+        }
+    }
+
+    fn block(&mut self, exprs: &[CommandExpr], runs: Estimate) -> Estimate {
+        self.depth += 1;
+        let spawns = self.exprs(exprs, runs);
+        self.depth -= 1;
+        spawns
+    }
+
+    /// How the iterations of one pass through the loop were worked out, such as `3 x 4 = `,
+    /// and the iterations themselves
+    fn for_loop(&self, for_loop: &ForLoop) -> (String, Estimate) {
+        let sizes: Vec<_> = for_loop
+            .targets
+            .iter()
+            .map(|target| self.target_len(target))
+            .collect();
+        let listed = |separator: &str| {
+            sizes
+                .iter()
+                .map(|size| size.to_string())
+                .collect::<Vec<_>>()
+                .join(separator)
+        };
+
+        let combinations = || {
+            sizes
+                .iter()
+                .fold(Estimate::Exact(1), |total, size| total.times(*size))
+        };
+
+        let (shown, iterations) = match &for_loop.ty {
+            ForLoopType::Combinations => (listed(" x "), combinations()),
+            ForLoopType::Group => {
+                let shortest = sizes.iter().copied().reduce(Estimate::min);
+                (
+                    format!("shortest of {}", listed(", ")),
+                    shortest.unwrap_or(Estimate::Exact(0)),
+                )
+            }
+            ForLoopType::Sample { count, .. } => {
+                let iterations = match combinations() {
+                    Estimate::Exact(total) => Estimate::Exact(total.min(*count)),
+                    total => Estimate::AtMost(total.bound().unwrap_or(*count).min(*count)),
+                };
+                (format!("{count} of {}", listed(" x ")), iterations)
+            }
+        };
+
+        match sizes.len() {
+            1 if !matches!(for_loop.ty, ForLoopType::Sample { .. }) => (String::new(), iterations),
+            _ => (format!("{shown} = "), iterations),
+        }
+    }
+
+    fn target_len(&self, target: &IterTargetExpr) -> Estimate {
+        match target {
+            IterTargetExpr::Variable(id) => self.matrix_len(*id),
+            IterTargetExpr::Range { start, end } => range_len(start, end),
+            IterTargetExpr::Chain { parts, .. } => {
+                parts.iter().fold(Estimate::Exact(0), |total, part| {
+                    total.plus(self.target_len(part))
+                })
+            }
+            IterTargetExpr::Inline { values, .. } => Estimate::Exact(values.len() as u64),
+        }
+    }
+
+    /// Variables are only known if they are the hidden list of a `matrix` axis
+    fn matrix_len(&self, id: VarNameId) -> Estimate {
+        let axis = self.parsed.matrix.iter().find(|axis| axis.list == id);
+
+        match axis.map(|axis| &axis.value) {
+            Some(ObjectExpr::List(values)) => Estimate::Exact(values.len() as u64),
+            Some(ObjectExpr::Counter(start, end)) => range_len(start, end),
+            _ => Estimate::Unknown,
+        }
+    }
+}
+
+fn counted(estimate: Estimate, noun: &str) -> String {
+    match estimate {
+        Estimate::Exact(1) => format!("1 {noun}"),
+        estimate => format!("{estimate} {noun}s"),
+    }
+}
+
+fn range_len(start: &RangeExpr, end: &RangeExpr) -> Estimate {
+    match (start, end) {
+        (RangeExpr::Integer(start), RangeExpr::Integer(end)) => {
+            Estimate::Exact(end.saturating_sub(*start).max(0) as u64)
+        }
+        _ => Estimate::Unknown,
+    }
+}
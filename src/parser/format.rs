@@ -0,0 +1,828 @@
+use std::fmt::Write;
+
+use crate::{
+    bed::{
+        commands::{ArgBuilder, Command, OutputMap, Spawn},
+        expr::{
+            CompareExpr, Condition, IterTargetExpr, ObjectExpr, RangeExpr, StringExpr,
+            StringInstance,
+        },
+        templates::{
+            BuildObjectExpr, BuildStringExpr, BuildTargets, PropertyExpr, TemplateCommand,
+            YieldExpr,
+        },
+    },
+    program::{Instruction, VarFieldId, VarNameId, VarNames, VariableIdx},
+};
+
+use super::{CommandExpr, ForLoop, ForLoopType, Parsed, TemplateExpr};
+
+const INDENT: &str = "    ";
+
+/// Bare strings the grammar would read as something else in one of the places strings appear
+const KEYWORDS: [&str; 6] = ["clear_env", "detach", "group", "keep", "print", "ref"];
+
+/// Writes a canonical scenario from the parsed AST, comments are not kept
+pub fn format_parsed(parsed: &Parsed) -> String {
+    let mut writer = DslWriter::new(&parsed.names);
+
+    writer.write_parsed(parsed);
+    writer.output
+}
+
+pub(super) struct DslWriter<'a> {
+    names: &'a VarNames,
+    output: String,
+    depth: usize,
+}
+
+impl<'a> DslWriter<'a> {
+    /// Writer for formatting single headers and commands, see `explain_program`
+    pub(super) fn new(names: &'a VarNames) -> Self {
+        Self {
+            names,
+            output: String::new(),
+            depth: 0,
+        }
+    }
+
+    fn name(&self, id: VarNameId) -> &str {
+        self.names.evaluate(id).unwrap()
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.depth {
+            self.output.push_str(INDENT);
+        }
+        self.output.push_str(line);
+        self.output.push('\n');
+    }
+
+    fn section(&mut self, header: &str) {
+        if !self.output.is_empty() {
+            self.output.push('\n');
+        }
+        self.line(header);
+    }
+
+    fn write_parsed(&mut self, parsed: &Parsed) {
+        for value in parsed.scenario_includes.iter() {
+            self.line(&format!("include \"{}\"", value.display()));
+        }
+
+        if !parsed.includes.is_empty() {
+            self.section("[includes]");
+            for value in parsed.includes.iter() {
+                self.line(&format!("\"{}\"", value.display()));
+            }
+        }
+
+        if !parsed.output.as_os_str().is_empty() {
+            self.section("[output]");
+            self.line(&format!("\"{}\"", parsed.output.display()));
+        }
+
+        if !parsed.globals.0.is_empty() {
+            self.section("[globals]");
+            for value in parsed.globals.0.iter() {
+                let line = self.instruction(value, |_, _| unreachable!());
+                self.line(&line);
+            }
+        }
+
+        if !parsed.matrix.is_empty() {
+            self.section("matrix {");
+            self.depth += 1;
+            for axis in parsed.matrix.iter() {
+                let line = format!("{} = {};", self.name(axis.name), self.object(&axis.value));
+                self.line(&line);
+            }
+            self.depth -= 1;
+            self.line("}");
+        }
+
+        for (name, exprs) in parsed.templates.iter() {
+            self.section(&format!("[template.{}]", self.name(*name)));
+            self.template_exprs(exprs);
+        }
+
+        for (name, def) in parsed.macros.iter() {
+            let params: Vec<_> = def.params.iter().map(|id| self.name(*id)).collect();
+            let header = format!("def {}({})", self.name(*name), params.join(", "));
+            self.section(&format!("{header} {{"));
+            self.depth += 1;
+            self.command_exprs(&def.exprs);
+            self.depth -= 1;
+            self.line("}");
+        }
+
+        for (name, exprs) in parsed.commands.iter() {
+            match name {
+                Some(name) => self.section(&format!("[commands.{}]", self.name(*name))),
+                None => self.section("[commands]"),
+            }
+            if let Some(requires) = parsed.requires.get(name) {
+                let requires: Vec<_> = requires.iter().map(|id| self.name(*id)).collect();
+                self.line(&format!("requires {};", requires.join(", ")));
+            }
+            self.command_exprs(exprs);
+
+            if let Some(exprs) = parsed.on_failure.get(name) {
+                self.block("on_failure".into(), |writer| writer.command_exprs(exprs));
+            }
+        }
+    }
+
+    fn block(&mut self, header: String, f: impl FnOnce(&mut Self)) {
+        self.line(&format!("{header} {{"));
+        self.depth += 1;
+        f(self);
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn template_exprs(&mut self, exprs: &[TemplateExpr]) {
+        for expr in exprs {
+            match expr {
+                TemplateExpr::Command(instruction) => {
+                    let line = self.instruction(instruction, Self::template_command);
+                    self.line(&line);
+                }
+                TemplateExpr::Outer(instruction) => {
+                    let line = self.instruction(instruction, Self::template_command);
+                    self.line(&format!("outer {line}"));
+                }
+                TemplateExpr::ForLoop { for_loop, exprs } => {
+                    let header = self.for_loop(for_loop);
+                    self.block(header, |writer| writer.template_exprs(exprs));
+                }
+                TemplateExpr::If { conditions, exprs } => {
+                    let header = self.conditions(conditions);
+                    self.block(header, |writer| writer.template_exprs(exprs));
+                }
+            }
+        }
+    }
+
+    fn command_exprs(&mut self, exprs: &[CommandExpr]) {
+        for expr in exprs {
+            match expr {
+                CommandExpr::Command(instruction) => {
+                    let line = self.instruction(instruction, Self::command);
+                    self.line(&line);
+                }
+                CommandExpr::Outer(instruction) => {
+                    let line = self.instruction(instruction, Self::command);
+                    self.line(&format!("outer {line}"));
+                }
+                CommandExpr::ForLoop { for_loop, exprs } => {
+                    let header = self.for_loop(for_loop);
+                    self.block(header, |writer| writer.command_exprs(exprs));
+                }
+                CommandExpr::If { conditions, exprs } => {
+                    let header = self.conditions(conditions);
+                    self.block(header, |writer| writer.command_exprs(exprs));
+                }
+                CommandExpr::Group { name, exprs } => {
+                    let header = format!("group {}", self.string(name));
+                    self.block(header, |writer| writer.command_exprs(exprs));
+                }
+                CommandExpr::CwdEach { dir, exprs } => {
+                    let header = format!("cwd_each {}", self.string(dir));
+                    self.block(header, |writer| writer.command_exprs(exprs));
+                }
+                CommandExpr::Repeat { count, exprs, .. } => {
+                    let header = format!("repeat {}", self.range_expr(count));
+                    self.block(header, |writer| writer.command_exprs(exprs));
+                }
+                CommandExpr::Timeout { millis, exprs } => {
+                    let header = format!("timeout {millis}");
+                    self.block(header, |writer| writer.command_exprs(exprs));
+                }
+                CommandExpr::Call { .. } => {
+                    let line = self.call(expr);
+                    self.line(&line);
+                }
+            }
+        }
+    }
+
+    pub(super) fn call(&self, expr: &CommandExpr) -> String {
+        let CommandExpr::Call { name, args } = expr else {
+            unreachable!()
+        };
+        let args: Vec<_> = args
+            .iter()
+            .map(|(param, value)| format!("{} = {}", self.name(*param), self.object(value)))
+            .collect();
+
+        format!("call {}({});", self.name(*name), args.join(", "))
+    }
+
+    /// Formats the instructions a parsed statement can produce, `command` formats `T`
+    fn instruction<T>(
+        &self,
+        instruction: &Instruction<T>,
+        command: impl Fn(&Self, &T) -> String,
+    ) -> String {
+        match instruction {
+            Instruction::CreateVar { target, value, .. } => {
+                format!("{} = {};", self.name(*target), self.object(value))
+            }
+            Instruction::AssignVar { target, value, .. } => {
+                format!("{} := {};", self.name(*target), self.object(value))
+            }
+            Instruction::Print(access) => format!("print({});", self.access(access)),
+            Instruction::UnsetVar(target) => format!("unset {};", self.name(*target)),
+            Instruction::PushList { target, object } => {
+                format!("{}.push({});", self.name(*target), self.object(object))
+            }
+            Instruction::Command(value) => format!("{};", command(self, value)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn template_command(&self, command: &TemplateCommand) -> String {
+        match command {
+            TemplateCommand::BuildAssign { output, object } => {
+                format!("{} = {}", self.name(*output), self.build_object(object))
+            }
+            TemplateCommand::Yield { objects, .. } => {
+                let objects: Vec<_> = objects
+                    .iter()
+                    .map(|object| match object {
+                        YieldExpr::Build(object) => self.build_object(object),
+                        YieldExpr::Object(object) => self.object(object),
+                    })
+                    .collect();
+                format!("yield {}", objects.join(", "))
+            }
+        }
+    }
+
+    fn build_object(&self, object: &BuildObjectExpr) -> String {
+        let base = match &object.targets {
+            BuildTargets::One(BuildStringExpr { template, output }) => {
+                format!("build({}, {})", self.string(template), self.string(output))
+            }
+            BuildTargets::Many(targets) => {
+                let targets: Vec<_> = targets
+                    .iter()
+                    .map(|BuildStringExpr { template, output }| {
+                        format!("{} -> {}", self.string(template), self.string(output))
+                    })
+                    .collect();
+                format!("build({})", targets.join(", "))
+            }
+        };
+
+        if object.properties.is_empty() {
+            return base;
+        }
+
+        let mut properties: Vec<_> = object
+            .properties
+            .iter()
+            .map(|(name, PropertyExpr { value, optional })| {
+                let op = if *optional { "?=" } else { "=" };
+                format!("{} {op} {}", self.name(*name), self.object(value))
+            })
+            .collect();
+        properties.sort();
+
+        format!("({base}, {})", properties.join(", "))
+    }
+
+    fn command(&self, command: &Command) -> String {
+        match command {
+            Command::LimitSpawn(limit) => format!("limit {limit}"),
+            Command::Sleep(millis) => format!("sleep {millis}"),
+            Command::WaitAll(Some(millis)) => format!("wait_all {millis}"),
+            Command::WaitAll(None) => "wait_all".into(),
+            Command::WaitFile { path, timeout } => match timeout {
+                Some(millis) => format!("wait_file {} timeout {millis}", self.string(path)),
+                None => format!("wait_file {}", self.string(path)),
+            },
+            Command::Copy { src, dst } => {
+                format!("copy {} {}", self.string(src), self.string(dst))
+            }
+            Command::Symlink { src, dst } => {
+                format!("symlink {} {}", self.string(src), self.string(dst))
+            }
+            Command::Spawn(spawn) => self.spawn("spawn", spawn),
+            Command::Run(spawn) => self.spawn("run", spawn),
+            Command::Terminate(name) => format!("terminate {}", self.string(name)),
+            Command::Assert { cond, message } => {
+                let cond = match cond {
+                    Condition::Variable(access) => format!("({})", self.access(access)),
+                    Condition::Exists(access) => format!("(exists({}))", self.access(access)),
+                    Condition::In { value, list } => {
+                        format!("({} in {})", self.access(value), self.access(list))
+                    }
+                    Condition::Equals(lhs, rhs) => {
+                        format!("({} == {})", self.compared(lhs), self.operand(rhs))
+                    }
+                    Condition::NotEquals(lhs, rhs) => {
+                        format!("({} != {})", self.compared(lhs), self.operand(rhs))
+                    }
+                };
+                format!("assert {cond} {}", self.string(message))
+            }
+            Command::StartGroup(_)
+            | Command::EndGroup
+            | Command::PushDir { .. }
+            | Command::PopDir => {
+                unreachable!()
+            }
+        }
+    }
+
+    pub(super) fn spawn(&self, keyword: &str, spawn: &Spawn) -> String {
+        let mut output = String::from(keyword);
+
+        if spawn.detach {
+            output.push_str(" detach");
+        }
+        if spawn.clear_env {
+            output.push_str(" clear_env");
+        }
+        if let Some(name) = &spawn.name {
+            write!(output, " name({})", self.string(name)).unwrap();
+        }
+        if let Some(nice) = spawn.nice {
+            write!(output, " nice({nice})").unwrap();
+        }
+        if let Some(dir) = &spawn.working_dir {
+            write!(output, " dir({})", self.string(dir)).unwrap();
+        }
+        if let Some(filter) = &spawn.filter {
+            write!(output, " filter({})", self.string(filter)).unwrap();
+        }
+        if let Some(progress) = &spawn.progress {
+            write!(output, " progress({})", self.string(progress)).unwrap();
+        }
+        if !matches!(spawn.stdout, OutputMap::Print) {
+            write!(output, " stdout({})", self.output_map(&spawn.stdout)).unwrap();
+        }
+        if !matches!(spawn.stderr, OutputMap::Print) {
+            write!(output, " stderr({})", self.output_map(&spawn.stderr)).unwrap();
+        }
+
+        write!(output, " {}", self.string(&spawn.command)).unwrap();
+
+        for arg in spawn.args.iter() {
+            let arg = match arg {
+                ArgBuilder::String(value) => self.string(value),
+                ArgBuilder::Set(access) => format!("{{{}}}", self.access(access)),
+                ArgBuilder::Json(access) => format!("json({})", self.access(access)),
+                ArgBuilder::Combo => "combo()".to_string(),
+                ArgBuilder::File(path) => format!("@{}", self.string(path)),
+                ArgBuilder::Spread(access) => format!("...{}", self.access(access)),
+            };
+            write!(output, " {arg}").unwrap();
+        }
+
+        output
+    }
+
+    fn output_map(&self, map: &OutputMap<StringExpr>) -> String {
+        match map {
+            OutputMap::Print => "print".into(),
+            OutputMap::Create(path) => self.string(path),
+            OutputMap::Append(path) => format!("append({})", self.string(path)),
+            OutputMap::Tee(path) => format!("tee({})", self.string(path)),
+            OutputMap::Rotate {
+                path,
+                max_bytes,
+                keep,
+            } => match keep {
+                Some(keep) => format!("rotate({}, {max_bytes}, {keep})", self.string(path)),
+                None => format!("rotate({}, {max_bytes})", self.string(path)),
+            },
+            OutputMap::Limited { path, max_bytes } => {
+                format!("limited({}, {max_bytes})", self.string(path))
+            }
+        }
+    }
+
+    pub(super) fn for_loop(&self, for_loop: &ForLoop) -> String {
+        let mut output = String::from("for");
+
+        match &for_loop.ty {
+            ForLoopType::Group => output.push_str(" group"),
+            ForLoopType::Sample { count, .. } => write!(output, " sample {count}").unwrap(),
+            ForLoopType::Combinations => {}
+        }
+        if !for_loop.kept.is_empty() {
+            output.push_str(" keep");
+        }
+        if for_loop.matrix {
+            output.push_str(" matrix");
+            return output;
+        }
+
+        let iters: Vec<_> = for_loop.iters.iter().map(|id| self.name(*id)).collect();
+        let targets: Vec<_> = for_loop
+            .targets
+            .iter()
+            .map(|target| self.iter_target(target))
+            .collect();
+
+        match iters.len() {
+            1 => write!(output, " {} in {}", iters[0], targets[0]).unwrap(),
+            _ => write!(
+                output,
+                " ({}) in ({})",
+                iters.join(", "),
+                targets.join(", ")
+            )
+            .unwrap(),
+        }
+
+        output
+    }
+
+    fn iter_target(&self, target: &IterTargetExpr) -> String {
+        match target {
+            IterTargetExpr::Variable(id) => self.name(*id).into(),
+            IterTargetExpr::Range { start, end } => {
+                format!("{}..{}", self.range_expr(start), self.range_expr(end))
+            }
+            IterTargetExpr::Chain { parts, .. } => parts
+                .iter()
+                .map(|part| self.iter_target(part))
+                .collect::<Vec<_>>()
+                .join(" ++ "),
+            IterTargetExpr::Inline { values, .. } => {
+                let values: Vec<_> = values.iter().map(|value| self.object(value)).collect();
+                format!("[{}]", values.join(", "))
+            }
+        }
+    }
+
+    pub(super) fn range_expr(&self, expr: &RangeExpr) -> String {
+        match expr {
+            RangeExpr::Integer(value) => value.to_string(),
+            RangeExpr::Variable(value) => self.string(value),
+            RangeExpr::Len(access) => format!("len({})", self.access(access)),
+        }
+    }
+
+    /// The variable or `len(..)` on the left of a `==` or `!=` comparison
+    fn compared(&self, lhs: &CompareExpr) -> String {
+        match lhs {
+            CompareExpr::String(value) => match value.0.as_slice() {
+                [StringInstance::Variable(access)] => self.access(access),
+                _ => unreachable!(),
+            },
+            CompareExpr::Len(access) => format!("len({})", self.access(access)),
+        }
+    }
+
+    /// The value on the right of a `==` or `!=` comparison
+    fn operand(&self, rhs: &CompareExpr) -> String {
+        match rhs {
+            CompareExpr::String(value) => self.string(value),
+            CompareExpr::Len(access) => format!("len({})", self.access(access)),
+        }
+    }
+
+    pub(super) fn conditions(&self, conditions: &[Condition]) -> String {
+        match conditions {
+            [Condition::Equals(lhs, rhs)] => {
+                format!("when {} == {}", self.compared(lhs), self.operand(rhs))
+            }
+            [Condition::NotEquals(lhs, rhs)] => {
+                format!("when {} != {}", self.compared(lhs), self.operand(rhs))
+            }
+            _ => {
+                let mut output = String::from("if");
+
+                for condition in conditions {
+                    match condition {
+                        Condition::Variable(access) => {
+                            write!(output, " {}", self.access(access)).unwrap()
+                        }
+                        Condition::Exists(access) => {
+                            write!(output, " exists({})", self.access(access)).unwrap()
+                        }
+                        Condition::In { value, list } => {
+                            write!(output, " ({} in {})", self.access(value), self.access(list))
+                                .unwrap()
+                        }
+                        Condition::Equals(lhs, rhs) => {
+                            write!(output, " ({} == {})", self.compared(lhs), self.operand(rhs))
+                                .unwrap()
+                        }
+                        Condition::NotEquals(lhs, rhs) => {
+                            write!(output, " ({} != {})", self.compared(lhs), self.operand(rhs))
+                                .unwrap()
+                        }
+                    }
+                }
+
+                output
+            }
+        }
+    }
+
+    fn object(&self, object: &ObjectExpr) -> String {
+        match object {
+            ObjectExpr::Clone(access) | ObjectExpr::Deref(access) => {
+                format!("*{}", self.access(access))
+            }
+            ObjectExpr::Ref(access) => format!("ref {}", self.access(access)),
+            ObjectExpr::BoolLit(value) => value.to_string(),
+            ObjectExpr::Condition(condition) => match condition {
+                Condition::Exists(access) => format!("exists({})", self.access(access)),
+                Condition::In { value, list } => {
+                    format!("({} in {})", self.access(value), self.access(list))
+                }
+                Condition::Equals(lhs, rhs) => {
+                    format!("({} == {})", self.compared(lhs), self.operand(rhs))
+                }
+                Condition::NotEquals(lhs, rhs) => {
+                    format!("({} != {})", self.compared(lhs), self.operand(rhs))
+                }
+                // Only `if` and `assert` take a bare variable
+                Condition::Variable(_) => unreachable!(),
+            },
+            ObjectExpr::IntLit(value) => value.to_string(),
+            // Quoted so it isn't read back as a bool or integer literal
+            ObjectExpr::StrLit(value) if is_literal(value) => format!("\"{value}\""),
+            ObjectExpr::StrLit(value) => string_literal(value),
+            ObjectExpr::List(values) => {
+                let values: Vec<_> = values.iter().map(|value| self.object(value)).collect();
+                format!("[{}]", values.join(", "))
+            }
+            ObjectExpr::Counter(start, end) => {
+                format!("{}..{}", self.range_expr(start), self.range_expr(end))
+            }
+            ObjectExpr::Comprehension { body, iter, target } => format!(
+                "[{} for {} in {}]",
+                self.object(body),
+                self.name(*iter),
+                self.iter_target(target)
+            ),
+            ObjectExpr::Struct(value) if value.properties.is_empty() => self.string(&value.base),
+            ObjectExpr::Struct(value) => {
+                let mut properties: Vec<_> = value
+                    .properties
+                    .iter()
+                    .map(|(name, value)| format!("{} = {}", self.name(*name), self.object(value)))
+                    .collect();
+                properties.sort();
+
+                format!("({}, {})", self.string(&value.base), properties.join(", "))
+            }
+        }
+    }
+
+    pub(super) fn access(&self, access: &VarFieldId) -> String {
+        let mut output = self.name(access.var).to_string();
+
+        if let Some(idx) = &access.idx {
+            match idx.as_ref() {
+                VariableIdx::Integer(idx) => write!(output, "[{idx}]").unwrap(),
+                VariableIdx::String(idx) => write!(output, "[{}]", quoted(idx)).unwrap(),
+                VariableIdx::Variable(idx) => write!(output, "[{}]", self.access(idx)).unwrap(),
+                VariableIdx::Slice { start, end } => {
+                    output.push('[');
+                    if let Some(start) = start {
+                        write!(output, "{start}").unwrap();
+                    }
+                    output.push_str("..");
+                    if let Some(end) = end {
+                        write!(output, "{end}").unwrap();
+                    }
+                    output.push(']');
+                }
+            }
+        }
+        if let Some(field) = &access.field {
+            write!(output, ".{}", self.access(field)).unwrap();
+        }
+
+        output
+    }
+
+    pub(super) fn string(&self, expr: &StringExpr) -> String {
+        if let Some(multiline) = self.multiline(expr) {
+            return multiline;
+        }
+
+        let parts: Vec<_> = expr
+            .0
+            .iter()
+            .map(|value| match value {
+                StringInstance::String(value) => string_literal(value),
+                StringInstance::Variable(access) => format!("[{}]", self.access(access)),
+                StringInstance::Default(access, default) => {
+                    format!("[{}:-{default}]", self.access(access))
+                }
+                StringInstance::Env(name) => format!("${{{name}}}"),
+                StringInstance::Now(Some(format)) => format!("{{now:{format}}}"),
+                StringInstance::Now(None) => "{now}".to_string(),
+            })
+            .collect();
+
+        parts.join(" + ")
+    }
+
+    /// Writes a string with a line break in it as a single `"""` string with the variables
+    /// inside, `None` if it has no line break or can't be written back that way
+    fn multiline(&self, expr: &StringExpr) -> Option<String> {
+        let has_newline = expr
+            .0
+            .iter()
+            .any(|value| matches!(value, StringInstance::String(value) if value.contains('\n')));
+
+        if !has_newline {
+            return None;
+        }
+
+        let mut body = String::new();
+
+        for value in &expr.0 {
+            // A `\` right before a variable would escape its `{`
+            if !matches!(value, StringInstance::String(_)) && body.ends_with('\\') {
+                return None;
+            }
+
+            match value {
+                StringInstance::String(value) => body.push_str(&value.replace('{', "\\{")),
+                // `{now}` is the time, not a variable called `now`
+                StringInstance::Variable(access) if self.access(access) == "now" => return None,
+                StringInstance::Variable(access) => {
+                    write!(body, "{{{}}}", self.access(access)).unwrap()
+                }
+                StringInstance::Env(name) => write!(body, "${{{name}}}").unwrap(),
+                StringInstance::Now(Some(format)) => write!(body, "{{now:{format}}}").unwrap(),
+                StringInstance::Now(None) => body.push_str("{now}"),
+                StringInstance::Default(..) => return None,
+            }
+        }
+
+        if body.contains("\"\"\"") || body.ends_with('"') {
+            return None;
+        }
+
+        Some(format!("\"\"\"{body}\"\"\""))
+    }
+}
+
+/// Whether the bare word would be parsed as a bool or integer literal
+fn is_literal(value: &str) -> bool {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    matches!(value, "true" | "false")
+        || (!digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+/// Leaves plain words such as paths and flags bare, anything else is quoted
+fn string_literal(value: &str) -> String {
+    let bare = !value.is_empty()
+        && !value.contains("..")
+        && !KEYWORDS.contains(&value)
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_./:=-".contains(c));
+
+    if bare {
+        return value.into();
+    }
+
+    // Quoted strings unescape `\"`, multi-line strings only unescape `\{`
+    if value.contains('\n') && !value.contains("\"\"\"") && !value.ends_with('"') {
+        return format!("\"\"\"{}\"\"\"", value.replace('{', "\\{"));
+    }
+
+    quoted(value)
+}
+
+fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_parsed;
+    use crate::parser::parse_source;
+
+    /// Touches most of the grammar, sections, blocks and spawn options
+    const SCENARIO: &str = r#"
+include "common.bed"
+
+[includes]
+"templates"
+
+[output]
+"out"
+
+[globals]
+files = ["a", "b"];
+base_args = ["--config", base.toml, --verbose];
+count = 3;
+banner = """files: {files[0]}, {files[1]}
+home ${HOME} at {now:%H} and {now}
+kept: \{count} {} { count } $5 "quoted"
+""" + [count];
+
+matrix {
+    os = [linux, "mac os"];
+    opt = [0, 1];
+}
+
+[template.services]
+for file in files {
+    yield (build("service.j2", [file] + .yaml), port = [count], replicas ?= [overrides.replicas]);
+}
+
+def launch(name, port) {
+    spawn name([name]) nice(-2) dir(run/ + [name]) ./server [name] --port [port] {now};
+}
+
+[commands]
+requires count, files;
+limit 2;
+for keep i in 0..5 {
+    if (i == 3) {
+        print(i);
+    }
+}
+for (os, opt) in (files, 0..2) {
+    spawn filter("grep -v DEBUG") progress("(\d+)%") stderr(print) stdout(tee(build.log)) ./build [os] -O[opt] ...base_args json(files) combo() @args.txt;
+}
+for sample 2 (a, b) in (files, files) {
+    spawn stdout(append(pairs.log)) ./pair [a] [b];
+}
+when profile == "nightly" {
+    spawn ./soak;
+}
+if exists(flag) (os in files) {
+    spawn ./flagged;
+}
+assert (count != 0) "need at least " + [count];
+assert (len(files) == [count]) "count is off";
+when len(files) != 0 {
+    spawn ./nonempty;
+}
+if exists(flag) (count == len(files)) {
+    spawn ./counted;
+}
+timeout 3000 {
+    group "servers" {
+        call launch(name = alpha, port = 8080);
+    }
+    wait_file out/ready timeout 500;
+    wait_all 1000;
+}
+cwd_each "work" + [count] {
+    copy ../input.db input.db;
+    symlink ../shared shared;
+    run clear_env stdout(limited(setup.log, 4096)) ./setup.sh;
+}
+repeat 2 {
+    spawn clear_env stdout(rotate(logs/server.log, 1024, 5)) ./server;
+    sleep 10;
+}
+r = ref files[1];
+release = (profile != debug);
+listed = (os in files);
+has_flag = exists(flag);
+files.push("c");
+unset r;
+terminate alpha;
+wait_all;
+on_failure {
+    terminate alpha;
+}
+
+[commands.smoke]
+spawn detach ./database;
+"#;
+
+    fn assert_idempotent(source: &str) {
+        let formatted = format_parsed(&parse_source(source).unwrap());
+        let reformatted = format_parsed(&parse_source(&formatted).unwrap());
+
+        assert_eq!(formatted, reformatted);
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        assert_idempotent(SCENARIO);
+    }
+
+    #[test]
+    fn examples_format_idempotently() {
+        for source in [
+            include_str!("../../example/example.bed"),
+            include_str!("../../example/assign_example.bed"),
+            include_str!("../../example/object_example.bed"),
+            include_str!("../../example/range_example.bed"),
+            include_str!("../../example/template_bench.bed"),
+        ] {
+            assert_idempotent(source);
+        }
+    }
+}
@@ -1,22 +1,37 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::HashMap,
     path::{Path, PathBuf},
 };
 
+use chrono::format::{Item, StrftimeItems};
+use indexmap::IndexMap;
 use pest::{iterators::Pair, Parser};
 
 use crate::{
     bed::{
         commands::{ArgBuilder, Command, OutputMap, Spawn},
-        expr::{IterTargetExpr, ObjectExpr, RangeExpr, StringExpr, StringInstance, StructExpr},
-        templates::{BuildObjectExpr, BuildStringExpr, TemplateCommand, YieldExpr},
+        expr::{
+            CompareExpr, Condition, IterTargetExpr, ObjectExpr, RangeExpr, StringExpr,
+            StringInstance, StructExpr,
+        },
+        templates::{
+            BuildObjectExpr, BuildStringExpr, BuildTargets, PropertyExpr, TemplateCommand,
+            YieldExpr,
+        },
     },
     program::{Instruction, InstructionId, Program, VarFieldId, VarNameId, VarNames, VariableIdx},
+    Error,
 };
 
-use self::{commands::build_commands_program, templates::build_templates_program};
+use self::{
+    commands::{build_commands_program, Macros},
+    templates::build_templates_program,
+};
 
 pub mod commands;
+pub mod dump;
+pub mod explain;
+pub mod format;
 pub mod templates;
 
 #[derive(Parser)]
@@ -25,15 +40,73 @@ pub struct TestBedParser;
 
 pub struct Parsed {
     pub names: VarNames,
+    /// Scenario files named by `include` directives, only kept by `parse_source`
+    pub scenario_includes: Vec<PathBuf>,
+    /// Canonical path of every scenario file `parse_test_bed` read, the scenario itself first
+    pub files: Vec<PathBuf>,
+    /// Template include directories
     pub includes: Vec<PathBuf>,
     pub output: PathBuf,
+    /// Run top to bottom before any template or command, so a global can use the ones above
+    /// it. Included globals come first.
     pub globals: Program<TemplateCommand>,
+    /// Source file and line of each global, used to point at the one that failed. The file is
+    /// `None` for `parse_source`.
+    pub globals_lines: Vec<(Option<PathBuf>, usize)>,
+    /// Axes of the `matrix` block in declaration order, see `MatrixAxis`
+    pub matrix: Vec<MatrixAxis>,
     pub templates: Vec<(VarNameId, Vec<TemplateExpr>)>,
-    pub commands: BTreeMap<Option<VarNameId>, Vec<CommandExpr>>,
+    /// Command blocks in source order
+    pub commands: IndexMap<Option<VarNameId>, Vec<CommandExpr>>,
+    /// Variables a command block declares with `requires`, checked once the globals have run
+    pub requires: HashMap<Option<VarNameId>, Vec<VarNameId>>,
+    /// The `on_failure` block of a command block, run if the block fails with an error
+    pub on_failure: HashMap<Option<VarNameId>, Vec<CommandExpr>>,
+    /// `def` blocks in source order, a later one with the same name replaces it
+    pub macros: IndexMap<VarNameId, CommandMacro>,
     // pub commands: Vec<CommandExpr>,
 }
 
+impl Default for Parsed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Parsed {
+    pub fn new() -> Self {
+        Self {
+            names: VarNames::default(),
+            scenario_includes: vec![],
+            files: vec![],
+            includes: vec![],
+            output: PathBuf::new(),
+            globals: Program(vec![]),
+            globals_lines: vec![],
+            matrix: vec![],
+            templates: vec![],
+            commands: IndexMap::new(),
+            requires: HashMap::new(),
+            on_failure: HashMap::new(),
+            macros: IndexMap::new(),
+        }
+    }
+
+    /// The globals followed by the list of every matrix axis, so axes can use globals
+    pub fn globals_program(&self) -> Program<TemplateCommand> {
+        let mut program = self.globals.clone();
+
+        for axis in self.matrix.iter() {
+            program.0.push(Instruction::CreateVar {
+                target: axis.list,
+                scope: None,
+                value: axis.value.clone(),
+            });
+        }
+
+        program
+    }
+
     pub fn template_program(&self) -> Vec<(String, Program<TemplateCommand>)> {
         self.templates
             .clone()
@@ -46,33 +119,76 @@ impl Parsed {
             .collect()
     }
 
+    /// `None` if there is no such block, an error if one of its `call`s can't be inlined
     pub fn commands_program(
         &self,
         name: Option<VarNameId>,
-    ) -> Option<(Option<String>, Program<Command>)> {
-        let commands = self.commands.get(&name)?.clone();
+    ) -> Result<Option<CommandProgram>, Error> {
+        let Some(commands) = self.commands.get(&name) else {
+            return Ok(None);
+        };
+        let program = build_commands_program(commands.clone().into_iter(), self.macros())?;
         let name = name.map(|value| self.names.evaluate(value).unwrap().to_string());
-        Some((name, build_commands_program(commands.into_iter())))
+        Ok(Some((name, program)))
+    }
+
+    pub fn on_failure_program(
+        &self,
+        name: Option<VarNameId>,
+    ) -> Result<Option<Program<Command>>, Error> {
+        let Some(commands) = self.on_failure.get(&name) else {
+            return Ok(None);
+        };
+        build_commands_program(commands.clone().into_iter(), self.macros()).map(Some)
     }
 
-    pub fn all_programs(&self) -> Vec<(Option<String>, Program<Command>)> {
+    pub fn all_programs(&self) -> Result<Vec<CommandProgram>, Error> {
         self.commands
             .clone()
             .into_iter()
             .map(|(id, program)| {
                 let name = id.map(|value| self.names.evaluate(value).unwrap().to_string());
-                let program = build_commands_program(program.into_iter());
-                (name, program)
+                let program = build_commands_program(program.into_iter(), self.macros())?;
+                Ok((name, program))
             })
             .collect()
     }
+
+    fn macros(&self) -> Macros<'_> {
+        Macros::new(&self.macros, &self.names)
+    }
 }
 
-#[derive(Clone)]
+/// Name of a command block, `None` for the unnamed one, and its compiled program
+pub type CommandProgram = (Option<String>, Program<Command>);
+
+/// A `def name(params) { ... }` block, see `CommandExpr::Call`
+#[derive(Clone, Debug)]
+pub struct CommandMacro {
+    pub params: Vec<VarNameId>,
+    pub exprs: Vec<CommandExpr>,
+}
+
+/// An axis of the `matrix` block, `for matrix` binds `name` to each element of `value`
+#[derive(Clone, Debug)]
+pub struct MatrixAxis {
+    pub name: VarNameId,
+    /// Hidden global holding `value`, named `@matrix.name`
+    pub list: VarNameId,
+    pub value: ObjectExpr,
+}
+
+const MATRIX_PREFIX: &str = "@matrix.";
+
+#[derive(Clone, Debug)]
 pub struct ForLoop {
     pub ty: ForLoopType,
     pub iters: Vec<VarNameId>,
     pub targets: Vec<IterTargetExpr>,
+    /// Set by `for keep ...`, each iter paired with the hidden variable holding its last value
+    pub kept: Vec<(VarNameId, VarNameId)>,
+    /// Written as `for matrix`, the iters and targets are the axes of the `matrix` block
+    pub matrix: bool,
 }
 
 pub fn build_group_loop<T>(
@@ -112,14 +228,14 @@ pub fn build_group_loop<T>(
     let end_idx = instructions.len();
     instructions.push(Instruction::PopScope);
 
-    for i in iter_start..goto {
-        if let Instruction::StartIter { jump, .. } = &mut instructions[i] {
+    for instruction in instructions[iter_start..goto].iter_mut() {
+        if let Instruction::StartIter { jump, .. } = instruction {
             *jump = InstructionId(end_idx)
         }
     }
 
-    for i in increment_start..end_idx {
-        if let Instruction::Increment { jump, .. } = &mut instructions[i] {
+    for instruction in instructions[increment_start..end_idx].iter_mut() {
+        if let Instruction::Increment { jump, .. } = instruction {
             *jump = InstructionId(end_idx)
         }
     }
@@ -175,16 +291,72 @@ pub fn build_combination_loop<T>(
 }
 
 impl ForLoop {
+    /// Kept iters are copied out at the end of every iteration and recreated in the enclosing
+    /// scope once the loop ends. If the loop never runs they are left as empty lists. Iters over
+    /// a list held in a loop temporary keep the element itself, a ref would outlive the list.
     pub fn build<T>(
         &self,
         instructions: &mut Vec<Instruction<T>>,
         f: impl FnOnce(&mut Vec<Instruction<T>>),
     ) {
-        match self.ty {
+        for (_, last) in self.kept.iter() {
+            instructions.push(Instruction::CreateVar {
+                target: *last,
+                scope: None,
+                value: ObjectExpr::List(vec![]),
+            });
+        }
+
+        let f = |instructions: &mut Vec<Instruction<T>>| {
+            f(instructions);
+
+            for ((iter, last), target) in self.kept.iter().zip(self.targets.iter()) {
+                let temporary = matches!(self.ty, ForLoopType::Sample { .. })
+                    || matches!(
+                        target,
+                        IterTargetExpr::Chain { .. } | IterTargetExpr::Inline { .. }
+                    );
+                let value = match temporary {
+                    true => ObjectExpr::Deref(VarFieldId::new(*iter)),
+                    false => ObjectExpr::Clone(VarFieldId::new(*iter)),
+                };
+
+                instructions.push(Instruction::AssignVar {
+                    target: *last,
+                    scope: None,
+                    value,
+                });
+            }
+        };
+
+        match &self.ty {
             ForLoopType::Group => build_group_loop(&self.iters, &self.targets, instructions, f),
             ForLoopType::Combinations => {
                 build_combination_loop(&self.iters, &self.targets, instructions, f)
             }
+            ForLoopType::Sample { count, lists } => {
+                let targets: Vec<_> = lists
+                    .iter()
+                    .map(|list| IterTargetExpr::Variable(*list))
+                    .collect();
+
+                instructions.push(Instruction::PushScope);
+                instructions.push(Instruction::Sample {
+                    targets: self.targets.clone(),
+                    count: *count,
+                    lists: lists.clone(),
+                });
+                build_group_loop(&self.iters, &targets, instructions, f);
+                instructions.push(Instruction::PopScope);
+            }
+        }
+
+        for (iter, last) in self.kept.iter() {
+            instructions.push(Instruction::CreateVar {
+                target: *iter,
+                scope: None,
+                value: ObjectExpr::Clone(VarFieldId::new(*last)),
+            });
         }
     }
 }
@@ -193,25 +365,112 @@ impl ForLoop {
 pub enum ForLoopType {
     Group,
     Combinations,
+    /// Visits `count` random combinations, `lists` are the hidden variables holding the
+    /// sampled elements of each iterable
+    Sample {
+        count: u64,
+        lists: Vec<VarNameId>,
+    },
 }
 
-pub fn parse_test_bed(file: impl AsRef<Path>) -> Parsed {
-    let file = std::fs::read_to_string(file).unwrap();
-    let ast = TestBedParser::parse(Rule::main, &file).unwrap();
-    let mut variables = VarNames::default();
-    let mut globals = Program(vec![]);
-    let mut templates = vec![];
-    let mut commands = BTreeMap::new();
-    let mut includes = vec![];
-    let mut output = PathBuf::new();
+/// The scenario path that reads the scenario from stdin instead of a file. Its `include`
+/// directives are resolved against the working directory.
+pub const STDIN_PATH: &str = "-";
+
+/// Reads the scenario at `file`, or stdin if it is `STDIN_PATH`
+pub fn read_scenario(file: &Path) -> std::io::Result<String> {
+    match file == Path::new(STDIN_PATH) {
+        true => std::io::read_to_string(std::io::stdin()),
+        false => std::fs::read_to_string(file),
+    }
+}
+
+/// Parses the scenario at `file` along with every scenario it includes, see `STDIN_PATH`
+pub fn parse_test_bed(file: impl AsRef<Path>) -> Result<Parsed, Error> {
+    let mut parsed = Parsed::new();
+    parse_file(file.as_ref(), &mut parsed, &mut vec![])?;
+    Ok(parsed)
+}
+
+/// Parses `source` without resolving its `include` directives, they are only recorded in
+/// `Parsed::scenario_includes`
+pub fn parse_source(source: &str) -> Result<Parsed, Error> {
+    let mut parsed = Parsed::new();
+    parse_into(source, None, &mut parsed, &mut |path, parsed| {
+        parsed.scenario_includes.push(PathBuf::from(path));
+        Ok(())
+    })?;
+    Ok(parsed)
+}
+
+/// `stack` holds the canonical paths of the files currently being parsed to catch cycles
+fn parse_file(file: &Path, parsed: &mut Parsed, stack: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let path = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+
+    if stack.contains(&path) {
+        let cycle: Vec<_> = stack
+            .iter()
+            .chain([&path])
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(Error::Parse(format!(
+            "Include cycle: {}",
+            cycle.join(" -> ")
+        )));
+    }
+
+    let source = read_scenario(file).map_err(|e| Error::Io(file.to_path_buf(), e))?;
+    // An empty parent, as for stdin, resolves includes against the working directory
+    let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    if file != Path::new(STDIN_PATH) {
+        parsed.files.push(path.clone());
+    }
+    stack.push(path);
+    parse_into(&source, Some(file), parsed, &mut |include, parsed| {
+        parse_file(&dir.join(include), parsed, stack)
+    })?;
+    stack.pop();
+    Ok(())
+}
+
+/// Merges `source` into `parsed`. Includes come first in a file so anything the file defines
+/// itself wins: its globals run after the included ones and its templates and command blocks
+/// replace included ones with the same name, keeping their position. `file` is only used to
+/// point syntax errors at it.
+fn parse_into(
+    source: &str,
+    file: Option<&Path>,
+    parsed: &mut Parsed,
+    include: &mut dyn FnMut(&str, &mut Parsed) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let ast = TestBedParser::parse(Rule::main, source).map_err(|e| {
+        let e = match file {
+            Some(file) => e.with_path(&file.display().to_string()),
+            None => e,
+        };
+        Error::Parse(e.to_string())
+    })?;
 
     for value in ast {
+        let variables = &mut parsed.names;
+
         match value.as_rule() {
+            Rule::scenario_include => {
+                let path = value
+                    .into_inner()
+                    .next()
+                    .unwrap()
+                    .into_inner()
+                    .next()
+                    .unwrap();
+                include(path.as_str(), parsed)?;
+            }
             Rule::includes => {
                 let inner = value.into_inner();
                 for value in inner {
                     let inner = value.into_inner().next().unwrap();
-                    includes.push(PathBuf::from(inner.as_str()));
+                    parsed.includes.push(PathBuf::from(inner.as_str()));
                 }
             }
             Rule::template_output => {
@@ -222,40 +481,95 @@ pub fn parse_test_bed(file: impl AsRef<Path>) -> Parsed {
                     .into_inner()
                     .next()
                     .unwrap();
-                output = PathBuf::from(inner.as_str());
+                parsed.output = PathBuf::from(inner.as_str());
             }
             Rule::globals => {
                 let inner = value.into_inner().next().unwrap();
-                globals = parse_globals_program(&mut variables, inner);
+                let lines = inner
+                    .clone()
+                    .into_inner()
+                    .map(|value| (file.map(Path::to_path_buf), value.line_col().0));
+                parsed.globals_lines.extend(lines);
+                let globals: Program<TemplateCommand> = parse_globals_program(variables, inner);
+                parsed.globals.0.extend(globals.0);
+            }
+            Rule::matrix => {
+                for axis in value.into_inner() {
+                    let axis = parse_matrix_axis(variables, axis);
+
+                    match parsed
+                        .matrix
+                        .iter_mut()
+                        .find(|value| value.name == axis.name)
+                    {
+                        Some(value) => *value = axis,
+                        None => parsed.matrix.push(axis),
+                    }
+                }
             }
             Rule::templates => {
                 let mut inner = value.into_inner();
                 let ident = inner.next().unwrap();
-                let ident = parse_ident(&mut variables, ident);
+                let ident = parse_ident(variables, ident);
                 let program = inner.next().unwrap();
-                let program = parse_template_program(ident, &mut variables, program);
+                let program = parse_template_program(ident, variables, program);
 
-                templates.push((ident, program))
+                match parsed.templates.iter_mut().find(|(id, _)| *id == ident) {
+                    Some(template) => template.1 = program,
+                    None => parsed.templates.push((ident, program)),
+                }
             }
             Rule::commands => {
-                let mut inner = value.into_inner();
-                let next = inner.next().unwrap();
-
-                let (ident, program) = match next.as_rule() {
-                    Rule::ident => {
-                        let ident = parse_ident(&mut variables, next);
-                        let program = inner.next().unwrap();
-                        let program = parse_command_program(&mut variables, program);
-                        (Some(ident), program)
-                    }
-                    Rule::command_program => {
-                        let program = parse_command_program(&mut variables, next);
-                        (None, program)
+                let mut ident = None;
+                let mut requires = vec![];
+                let mut program = vec![];
+                let mut on_failure = None;
+
+                for value in value.into_inner() {
+                    match value.as_rule() {
+                        Rule::ident => ident = Some(parse_ident(variables, value)),
+                        Rule::requires => {
+                            for value in value.into_inner().skip(1) {
+                                requires.push(parse_ident(variables, value));
+                            }
+                        }
+                        Rule::command_program => {
+                            program = parse_command_program(variables, value);
+                        }
+                        Rule::on_failure => {
+                            let value = value.into_inner().nth(1).unwrap();
+                            on_failure = Some(parse_command_program(variables, value));
+                        }
+                        _ => unreachable!(),
                     }
-                    _ => unreachable!(),
+                }
+
+                // A block redefined by a later file replaces its requirements too
+                match requires.is_empty() {
+                    true => parsed.requires.remove(&ident),
+                    false => parsed.requires.insert(ident, requires),
+                };
+                match on_failure {
+                    Some(on_failure) => parsed.on_failure.insert(ident, on_failure),
+                    None => parsed.on_failure.remove(&ident),
                 };
+                parsed.commands.insert(ident, program);
+            }
+            Rule::command_def => {
+                let mut inner = value.into_inner().skip(1);
+                let name = parse_ident(variables, inner.next().unwrap());
+                let mut params = vec![];
+                let mut exprs = vec![];
+
+                for value in inner {
+                    match value.as_rule() {
+                        Rule::ident => params.push(parse_ident(variables, value)),
+                        Rule::command_program => exprs = parse_command_program(variables, value),
+                        _ => unreachable!(),
+                    }
+                }
 
-                commands.insert(ident, program);
+                parsed.macros.insert(name, CommandMacro { params, exprs });
             }
             Rule::EOI => break,
             _ => {
@@ -264,14 +578,7 @@ pub fn parse_test_bed(file: impl AsRef<Path>) -> Parsed {
         }
     }
 
-    Parsed {
-        names: variables,
-        globals,
-        templates,
-        commands,
-        includes,
-        output,
-    }
+    Ok(())
 }
 
 // ======================= Globals ===========================
@@ -281,6 +588,10 @@ pub fn parse_globals_program<T>(variables: &mut VarNames, pair: Pair<Rule>) -> P
     let mut exprs = vec![];
 
     for value in inner {
+        assert!(
+            !is_outer(&value),
+            "`outer` assignments can only be used inside a loop"
+        );
         let instruction = parse_variable_assignment(variables, value);
         exprs.push(instruction);
     }
@@ -288,19 +599,33 @@ pub fn parse_globals_program<T>(variables: &mut VarNames, pair: Pair<Rule>) -> P
     Program(exprs)
 }
 
+pub fn parse_matrix_axis(variables: &mut VarNames, pair: Pair<Rule>) -> MatrixAxis {
+    let mut inner = pair.into_inner();
+    let ident = inner.next().unwrap();
+    let name = ident.as_str();
+
+    MatrixAxis {
+        name: variables.replace(name),
+        list: variables.replace(&format!("{MATRIX_PREFIX}{name}")),
+        value: parse_object_expr(variables, inner.next().unwrap()),
+    }
+}
+
 // ======================= Globals ===========================
 
 // ======================= Templates ===========================
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum TemplateExpr {
     Command(Instruction<TemplateCommand>),
+    /// An `outer` assignment, see `CommandExpr::Outer`
+    Outer(Instruction<TemplateCommand>),
     ForLoop {
         for_loop: ForLoop,
         exprs: Vec<TemplateExpr>,
     },
     If {
-        conditions: Vec<VarFieldId>,
+        conditions: Vec<Condition>,
         exprs: Vec<TemplateExpr>,
     },
 }
@@ -329,8 +654,13 @@ pub fn parse_template_expr(
 
     match inner.as_rule() {
         Rule::template => {
+            let outer = is_outer_statement(&inner);
             let command = parse_template(template_target, variables, inner);
-            TemplateExpr::Command(command)
+
+            match outer {
+                true => TemplateExpr::Outer(command),
+                false => TemplateExpr::Command(command),
+            }
         }
         Rule::template_for_loop => {
             let mut inner = inner.into_inner();
@@ -379,17 +709,18 @@ pub fn parse_template(
             Instruction::Command(TemplateCommand::BuildAssign { output, object })
         }
         Rule::variable_assignment => parse_variable_assignment(variables, inner),
+        Rule::unset => parse_unset(variables, inner),
         Rule::print_var => parse_print(variables, inner),
         Rule::push => {
             let (target, object) = parse_push(variables, inner);
             Instruction::PushList { target, object }
         }
         Rule::yield_template => {
-            let yield_object = parse_yield_template(variables, inner);
+            let objects = parse_yield_template(variables, inner);
 
             Instruction::Command(TemplateCommand::Yield {
                 output: template_target,
-                object: yield_object,
+                objects,
             })
         }
         _ => unreachable!(),
@@ -407,9 +738,10 @@ pub fn parse_build_assignment(
     (ident, object)
 }
 
-pub fn parse_yield_template(variables: &mut VarNames, pair: Pair<Rule>) -> YieldExpr {
-    let inner = pair.into_inner().next().unwrap();
-    parse_yield_object(variables, inner)
+pub fn parse_yield_template(variables: &mut VarNames, pair: Pair<Rule>) -> Vec<YieldExpr> {
+    pair.into_inner()
+        .map(|inner| parse_yield_object(variables, inner))
+        .collect()
 }
 
 pub fn parse_yield_object(variables: &mut VarNames, pair: Pair<Rule>) -> YieldExpr {
@@ -424,19 +756,34 @@ pub fn parse_yield_object(variables: &mut VarNames, pair: Pair<Rule>) -> YieldEx
 
 pub fn parse_build_object(variables: &mut VarNames, pair: Pair<Rule>) -> BuildObjectExpr {
     let mut inner = pair.into_inner();
-    let base = parse_build_fn(variables, inner.next().unwrap());
-    let mut object = BuildObjectExpr::new(base);
+    let targets = parse_build_fn(variables, inner.next().unwrap());
+    let mut object = BuildObjectExpr::new(targets);
 
     for value in inner {
-        let (id, expr) = parse_property_assignment(variables, value);
-        object.properties.insert(id, expr);
+        let (id, property) = parse_property_assignment(variables, value);
+        object.properties.insert(id, property);
     }
 
     object
 }
 
-pub fn parse_build_fn(variables: &mut VarNames, pair: Pair<Rule>) -> BuildStringExpr {
-    let mut inner = pair.into_inner();
+pub fn parse_build_fn(variables: &mut VarNames, pair: Pair<Rule>) -> BuildTargets {
+    let mut inner = pair.into_inner().peekable();
+
+    if inner.peek().unwrap().as_rule() == Rule::build_target {
+        let targets = inner
+            .map(|target| parse_build_target(variables, target.into_inner()))
+            .collect();
+        return BuildTargets::Many(targets);
+    }
+
+    BuildTargets::One(parse_build_target(variables, inner))
+}
+
+fn parse_build_target<'a>(
+    variables: &mut VarNames,
+    mut inner: impl Iterator<Item = Pair<'a, Rule>>,
+) -> BuildStringExpr {
     let template = inner.next().unwrap();
     let template = parse_string_builder(variables, template);
 
@@ -453,17 +800,48 @@ pub fn parse_build_fn(variables: &mut VarNames, pair: Pair<Rule>) -> BuildString
 
 // ======================= Commands ===========================
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum CommandExpr {
     Command(Instruction<Command>),
+    /// An `outer x = value` assignment, its scope is set to the one enclosing the innermost
+    /// loop when the program is built
+    Outer(Instruction<Command>),
     ForLoop {
         for_loop: ForLoop,
         exprs: Vec<CommandExpr>,
     },
     If {
-        conditions: Vec<VarFieldId>,
+        conditions: Vec<Condition>,
+        exprs: Vec<CommandExpr>,
+    },
+    Group {
+        name: StringExpr,
+        exprs: Vec<CommandExpr>,
+    },
+    /// Spawns inside `exprs` without their own `dir(..)` run in `dir`, evaluated and created
+    /// every time the block is entered
+    CwdEach {
+        dir: StringExpr,
+        exprs: Vec<CommandExpr>,
+    },
+    /// Runs `exprs` `count` times, `iter` is an anonymous counter the body can't name
+    Repeat {
+        count: RangeExpr,
+        iter: VarNameId,
+        exprs: Vec<CommandExpr>,
+    },
+    /// Skips the rest of `exprs` once `millis` have passed, processes spawned inside are timed
+    /// out by the next poll after the deadline
+    Timeout {
+        millis: u64,
         exprs: Vec<CommandExpr>,
     },
+    /// Written as `call name(param = value, ...)`, the body of the `def` is inlined when the
+    /// program is built with each param bound in a scope of its own
+    Call {
+        name: VarNameId,
+        args: Vec<(VarNameId, ObjectExpr)>,
+    },
 }
 
 pub fn parse_command_program(variables: &mut VarNames, pair: Pair<Rule>) -> Vec<CommandExpr> {
@@ -482,8 +860,29 @@ pub fn parse_command_expr(variables: &mut VarNames, pair: Pair<Rule>) -> Command
 
     match inner.as_rule() {
         Rule::command => {
+            let outer = is_outer_statement(&inner);
             let command = parse_command(variables, inner);
-            CommandExpr::Command(command)
+
+            match outer {
+                true => CommandExpr::Outer(command),
+                false => CommandExpr::Command(command),
+            }
+        }
+        Rule::command_call => {
+            let mut inner = inner.into_inner().skip(1);
+            let name = parse_ident(variables, inner.next().unwrap());
+            let mut args = vec![];
+
+            for value in inner {
+                let (param, property) = parse_property_assignment(variables, value);
+                assert!(
+                    !property.optional,
+                    "`?=` is only supported on the properties of `build(..)` objects"
+                );
+                args.push((param, property.value));
+            }
+
+            CommandExpr::Call { name, args }
         }
         Rule::command_for_loop => {
             let mut inner = inner.into_inner();
@@ -513,6 +912,75 @@ pub fn parse_command_expr(variables: &mut VarNames, pair: Pair<Rule>) -> Command
 
             CommandExpr::If { conditions, exprs }
         }
+        Rule::command_group => {
+            let mut inner = inner.into_inner();
+            let name = parse_string_builder(variables, inner.next().unwrap());
+
+            let mut exprs = vec![];
+
+            for value in inner {
+                let expr = parse_command_expr(variables, value);
+                exprs.push(expr);
+            }
+
+            CommandExpr::Group { name, exprs }
+        }
+        Rule::command_cwd_each => {
+            let mut inner = inner.into_inner();
+            let dir = parse_string_builder(variables, inner.next().unwrap());
+
+            let mut exprs = vec![];
+
+            for value in inner {
+                let expr = parse_command_expr(variables, value);
+                exprs.push(expr);
+            }
+
+            CommandExpr::CwdEach { dir, exprs }
+        }
+        Rule::command_repeat => {
+            let mut inner = inner.into_inner();
+            let count = parse_range_expr(variables, inner.next().unwrap());
+            let iter = variables.replace(&format!("@repeat{}", variables.0.len()));
+
+            let mut exprs = vec![];
+
+            for value in inner {
+                let expr = parse_command_expr(variables, value);
+                exprs.push(expr);
+            }
+
+            CommandExpr::Repeat { count, iter, exprs }
+        }
+        Rule::command_timeout => {
+            let mut inner = inner.into_inner();
+            let millis = inner.next().unwrap().as_str().parse().unwrap();
+
+            let mut exprs = vec![];
+
+            for value in inner {
+                let expr = parse_command_expr(variables, value);
+                exprs.push(expr);
+            }
+
+            CommandExpr::Timeout { millis, exprs }
+        }
+        Rule::command_when => {
+            let mut inner = inner.into_inner();
+            let condition = parse_when_statement(variables, inner.next().unwrap());
+
+            let mut exprs = vec![];
+
+            for value in inner {
+                let expr = parse_command_expr(variables, value);
+                exprs.push(expr);
+            }
+
+            CommandExpr::If {
+                conditions: vec![condition],
+                exprs,
+            }
+        }
         _ => unreachable!(),
     }
 }
@@ -522,6 +990,7 @@ pub fn parse_command(variables: &mut VarNames, pair: Pair<Rule>) -> Instruction<
 
     match inner.as_rule() {
         Rule::variable_assignment => parse_variable_assignment(variables, inner),
+        Rule::unset => parse_unset(variables, inner),
         Rule::print_var => parse_print(variables, inner),
         Rule::push => {
             let (target, object) = parse_push(variables, inner);
@@ -543,6 +1012,38 @@ pub fn parse_command(variables: &mut VarNames, pair: Pair<Rule>) -> Instruction<
             let spawn = parse_spawn(variables, inner);
             Instruction::Command(Command::Spawn(spawn))
         }
+        Rule::run => {
+            let spawn = parse_spawn(variables, inner);
+            Instruction::Command(Command::Run(spawn))
+        }
+        Rule::wait_file => {
+            let mut inner = inner.into_inner();
+            let path = parse_string_builder(variables, inner.next().unwrap());
+            let timeout = inner.next().map(|value| value.as_str().parse().unwrap());
+            Instruction::Command(Command::WaitFile { path, timeout })
+        }
+        Rule::copy_file | Rule::symlink => {
+            let rule = inner.as_rule();
+            let mut inner = inner.into_inner();
+            let src = parse_string_builder(variables, inner.next().unwrap());
+            let dst = parse_string_builder(variables, inner.next().unwrap());
+
+            match rule {
+                Rule::copy_file => Instruction::Command(Command::Copy { src, dst }),
+                _ => Instruction::Command(Command::Symlink { src, dst }),
+            }
+        }
+        Rule::terminate => {
+            let inner = inner.into_inner().next().unwrap();
+            let name = parse_string_builder(variables, inner);
+            Instruction::Command(Command::Terminate(name))
+        }
+        Rule::assert => {
+            let mut inner = inner.into_inner();
+            let cond = parse_condition(variables, inner.next().unwrap());
+            let message = parse_string_builder(variables, inner.next().unwrap());
+            Instruction::Command(Command::Assert { cond, message })
+        }
         _ => unreachable!(),
     }
 }
@@ -571,7 +1072,13 @@ pub fn parse_wait_all(pair: Pair<Rule>) -> Option<u64> {
 pub fn parse_spawn(variables: &mut VarNames, pair: Pair<Rule>) -> Spawn {
     let mut inner = pair.into_inner();
 
+    let mut detach = false;
+    let mut clear_env = false;
+    let mut name = None;
     let mut working_dir = None;
+    let mut filter = None;
+    let mut progress = None;
+    let mut nice = None;
     let mut out = OutputMap::Print;
     let mut err = OutputMap::Print;
 
@@ -579,9 +1086,31 @@ pub fn parse_spawn(variables: &mut VarNames, pair: Pair<Rule>) -> Spawn {
 
     while next.as_rule() != Rule::string_builder {
         match next.as_rule() {
+            Rule::detach => detach = true,
+            Rule::clear_env => clear_env = true,
+            Rule::process_name => {
+                let inner = next.into_inner().next().unwrap();
+                name = Some(parse_string_builder(variables, inner));
+            }
             Rule::working_dir => {
                 working_dir = Some(parse_working_dir(variables, next));
             }
+            Rule::output_filter => {
+                let inner = next.into_inner().next().unwrap();
+                filter = Some(parse_string_builder(variables, inner));
+            }
+            Rule::progress_regex => {
+                let inner = next.into_inner().next().unwrap();
+                progress = Some(parse_string_builder(variables, inner));
+            }
+            Rule::process_nice => {
+                let value = next.into_inner().next().unwrap().as_str();
+                nice = Some(
+                    value
+                        .parse()
+                        .expect("Expected a niceness that fits in an i32"),
+                );
+            }
             Rule::std_map => {
                 (out, err) = parse_stdmap(variables, next);
             }
@@ -599,8 +1128,14 @@ pub fn parse_spawn(variables: &mut VarNames, pair: Pair<Rule>) -> Spawn {
     }
 
     Spawn {
+        detach,
+        clear_env,
+        name,
         command,
         working_dir,
+        filter,
+        progress,
+        nice,
         args,
         stdout: out,
         stderr: err,
@@ -612,6 +1147,19 @@ pub fn parse_arg_builder(variables: &mut VarNames, pair: Pair<Rule>) -> ArgBuild
     match inner.as_rule() {
         Rule::string_builder => ArgBuilder::String(parse_string_builder(variables, inner)),
         Rule::variable_access => ArgBuilder::Set(parse_variable_access(variables, inner)),
+        Rule::json_arg => {
+            let inner = inner.into_inner().next().unwrap();
+            ArgBuilder::Json(parse_variable_access(variables, inner))
+        }
+        Rule::combo_arg => ArgBuilder::Combo,
+        Rule::arg_spread => {
+            let inner = inner.into_inner().next().unwrap();
+            ArgBuilder::Spread(parse_variable_access(variables, inner))
+        }
+        Rule::arg_file => {
+            let inner = inner.into_inner().next().unwrap();
+            ArgBuilder::File(parse_string_builder(variables, inner))
+        }
         _ => unreachable!(),
     }
 }
@@ -672,6 +1220,33 @@ pub fn parse_output_map(variables: &mut VarNames, pair: Pair<Rule>) -> OutputMap
 
             OutputMap::Append(expr)
         }
+        Rule::tee => {
+            let inner = inner.into_inner().next().unwrap();
+            let expr = parse_string_builder(variables, inner);
+
+            OutputMap::Tee(expr)
+        }
+        Rule::rotate => {
+            let mut inner = inner.into_inner();
+            let path = parse_string_builder(variables, inner.next().unwrap());
+            let max_bytes: u64 = inner.next().unwrap().as_str().parse().unwrap();
+            let keep = inner.next().map(|keep| keep.as_str().parse().unwrap());
+
+            assert!(max_bytes > 0, "rotate(..) needs a size above 0 bytes");
+
+            OutputMap::Rotate {
+                path,
+                max_bytes,
+                keep,
+            }
+        }
+        Rule::limited => {
+            let mut inner = inner.into_inner();
+            let path = parse_string_builder(variables, inner.next().unwrap());
+            let max_bytes = inner.next().unwrap().as_str().parse().unwrap();
+
+            OutputMap::Limited { path, max_bytes }
+        }
         Rule::string_builder => {
             let expr = parse_string_builder(variables, inner);
             OutputMap::Create(expr)
@@ -685,45 +1260,122 @@ pub fn parse_output_map(variables: &mut VarNames, pair: Pair<Rule>) -> OutputMap
 
 // ======================= Commands ===========================
 
-pub fn parse_if_statement(variables: &mut VarNames, pair: Pair<Rule>) -> Vec<VarFieldId> {
+pub fn parse_if_statement(variables: &mut VarNames, pair: Pair<Rule>) -> Vec<Condition> {
     let mut conditions = vec![];
     let inner = pair.into_inner();
 
     for value in inner {
-        let access = parse_variable_access(variables, value);
-        conditions.push(access);
+        conditions.push(parse_condition(variables, value));
     }
 
     conditions
 }
 
+/// Parses one condition of an `if`, an `assert` or a `condition_expr`, anything else is read
+/// as a variable access
+pub fn parse_condition(variables: &mut VarNames, pair: Pair<Rule>) -> Condition {
+    match pair.as_rule() {
+        Rule::exists_condition => {
+            let inner = pair.into_inner().next().unwrap();
+            Condition::Exists(parse_variable_access(variables, inner))
+        }
+        Rule::in_condition => {
+            let mut inner = pair.into_inner();
+            let value = parse_variable_access(variables, inner.next().unwrap());
+            let list = parse_variable_access(variables, inner.next().unwrap());
+            Condition::In { value, list }
+        }
+        Rule::comparison => parse_comparison(variables, pair),
+        _ => Condition::Variable(parse_variable_access(variables, pair)),
+    }
+}
+
+pub fn parse_when_statement(variables: &mut VarNames, pair: Pair<Rule>) -> Condition {
+    parse_comparison(variables, pair.into_inner().next().unwrap())
+}
+
+/// Parses `var == value` or `var != value`, either side can be a `len(list)`
+pub fn parse_comparison(variables: &mut VarNames, pair: Pair<Rule>) -> Condition {
+    let mut inner = pair.into_inner();
+    let lhs = parse_compare_expr(variables, inner.next().unwrap());
+    let op = inner.next().unwrap();
+    let rhs = parse_compare_expr(variables, inner.next().unwrap());
+
+    match op.as_str() {
+        "==" => Condition::Equals(lhs, rhs),
+        "!=" => Condition::NotEquals(lhs, rhs),
+        _ => unreachable!(),
+    }
+}
+
+pub fn parse_compare_expr(variables: &mut VarNames, pair: Pair<Rule>) -> CompareExpr {
+    match pair.as_rule() {
+        Rule::len_expr => {
+            let inner = pair.into_inner().next().unwrap();
+            CompareExpr::Len(parse_variable_access(variables, inner))
+        }
+        Rule::variable_access => {
+            let access = parse_variable_access(variables, pair);
+            CompareExpr::String(StringExpr(vec![StringInstance::Variable(access)]))
+        }
+        _ => CompareExpr::String(parse_string_builder(variables, pair)),
+    }
+}
+
 pub fn parse_for_loop(variables: &mut VarNames, pair: Pair<Rule>) -> ForLoop {
     let inner = pair.into_inner().next().unwrap();
     let (line, col) = inner.line_col();
 
-    let ty = match inner.as_rule() {
+    let rule = inner.as_rule();
+    let mut inner = inner.into_inner().peekable();
+
+    let mut ty = match rule {
         Rule::for_loop_combinations => ForLoopType::Combinations,
         Rule::for_loop_groups => ForLoopType::Group,
+        Rule::for_loop_sample => {
+            inner.next(); // sample
+            ForLoopType::Sample {
+                count: inner.next().unwrap().as_str().parse().unwrap(),
+                lists: vec![],
+            }
+        }
+        Rule::for_loop_matrix => ForLoopType::Combinations,
         _ => unreachable!(),
     };
 
-    let mut inner = inner.into_inner();
+    let keep = inner.next_if(|pair| pair.as_rule() == Rule::keep).is_some();
+    let matrix = rule == Rule::for_loop_matrix;
 
-    let iters_pairs = inner.next().unwrap();
-    let targets_pairs = inner.next().unwrap();
     let iters;
     let targets;
 
-    match iters_pairs.as_rule() {
-        Rule::ident => {
-            iters = vec![parse_ident(variables, iters_pairs)];
-            targets = vec![parse_iterable(variables, targets_pairs)];
+    match matrix {
+        true => {
+            (iters, targets) = matrix_axes(variables);
+
+            if iters.is_empty() {
+                panic!(
+                    "`for matrix` without a `matrix` block: [Line {}, Column {}]",
+                    line, col
+                );
+            }
         }
-        Rule::ident_group => {
-            iters = parse_ident_group(variables, iters_pairs);
-            targets = parse_iterable_group_group(variables, targets_pairs);
+        false => {
+            let iters_pairs = inner.next().unwrap();
+            let targets_pairs = inner.next().unwrap();
+
+            match iters_pairs.as_rule() {
+                Rule::ident => {
+                    iters = vec![parse_ident(variables, iters_pairs)];
+                    targets = vec![parse_iterable(variables, targets_pairs)];
+                }
+                Rule::ident_group => {
+                    iters = parse_ident_group(variables, iters_pairs);
+                    targets = parse_iterable_group_group(variables, targets_pairs);
+                }
+                _ => unreachable!(),
+            }
         }
-        _ => unreachable!(),
     }
 
     if iters.len() != targets.len() {
@@ -733,7 +1385,53 @@ pub fn parse_for_loop(variables: &mut VarNames, pair: Pair<Rule>) -> ForLoop {
         );
     }
 
-    ForLoop { ty, iters, targets }
+    if let ForLoopType::Sample { lists, .. } = &mut ty {
+        *lists = iters
+            .iter()
+            .map(|_| variables.replace(&format!("@sample{}", variables.0.len())))
+            .collect();
+    }
+
+    let kept = match keep {
+        true => iters
+            .iter()
+            .map(|iter| {
+                (
+                    *iter,
+                    variables.replace(&format!("@last{}", variables.0.len())),
+                )
+            })
+            .collect(),
+        false => vec![],
+    };
+
+    ForLoop {
+        ty,
+        iters,
+        targets,
+        kept,
+        matrix,
+    }
+}
+
+/// Axes of the `matrix` blocks parsed so far, found through the hidden lists they define since
+/// they always come before the templates and commands
+fn matrix_axes(variables: &mut VarNames) -> (Vec<VarNameId>, Vec<IterTargetExpr>) {
+    let axes: Vec<_> = variables
+        .0
+        .iter()
+        .enumerate()
+        .filter_map(|(id, name)| Some((name.strip_prefix(MATRIX_PREFIX)?.to_string(), id)))
+        .collect();
+
+    axes.into_iter()
+        .map(|(name, list)| {
+            (
+                variables.replace(&name),
+                IterTargetExpr::Variable(VarNameId(list)),
+            )
+        })
+        .unzip()
 }
 
 pub fn parse_ident_group(variables: &mut VarNames, pair: Pair<Rule>) -> Vec<VarNameId> {
@@ -765,6 +1463,22 @@ pub fn parse_iterable(variables: &mut VarNames, pair: Pair<Rule>) -> IterTargetE
     let inner = pair.into_inner().next().unwrap();
 
     match inner.as_rule() {
+        Rule::chain => {
+            let mut parts = vec![];
+
+            for value in inner.into_inner() {
+                parts.push(parse_iterable(variables, value));
+            }
+
+            // `@` can't appear in an ident so the temporary can never shadow a user variable
+            let temp = variables.replace(&format!("@chain{}", variables.0.len()));
+            IterTargetExpr::Chain { parts, temp }
+        }
+        Rule::list_expression => {
+            let values = parse_list_expression(variables, inner);
+            let temp = variables.replace(&format!("@inline{}", variables.0.len()));
+            IterTargetExpr::Inline { values, temp }
+        }
         Rule::ident => {
             let ident = parse_ident(variables, inner);
 
@@ -801,6 +1515,10 @@ pub fn parse_range_expr(variables: &mut VarNames, pair: Pair<Rule>) -> RangeExpr
             let expr = StringExpr(vec![var]);
             RangeExpr::Variable(expr)
         }
+        Rule::len_expr => {
+            let inner = inner.into_inner().next().unwrap();
+            RangeExpr::Len(parse_variable_access(variables, inner))
+        }
         Rule::signed_integer => {
             let value = parse_signed_integer(inner);
             RangeExpr::Integer(value)
@@ -827,8 +1545,58 @@ pub fn parse_signed_integer(pair: Pair<Rule>) -> i64 {
     value
 }
 
+/// Whether a `variable_assignment` starts with `outer`
+pub fn is_outer(pair: &Pair<Rule>) -> bool {
+    pair.clone()
+        .into_inner()
+        .next()
+        .is_some_and(|value| value.as_rule() == Rule::outer_keyword)
+}
+
+/// Whether a `command` or `template` statement is an `outer` assignment
+pub fn is_outer_statement(pair: &Pair<Rule>) -> bool {
+    pair.clone()
+        .into_inner()
+        .next()
+        .is_some_and(|value| value.as_rule() == Rule::variable_assignment && is_outer(&value))
+}
+
+/// Number of scopes pushed by `instructions` that are still open at their end, the scope an
+/// instruction appended next runs in relative to the program's first scope
+pub fn scope_depth<T>(instructions: &[Instruction<T>]) -> usize {
+    let mut depth = 0;
+
+    for instruction in instructions {
+        match instruction {
+            Instruction::PushScope => depth += 1,
+            Instruction::PopScope => depth -= 1,
+            _ => {}
+        }
+    }
+
+    depth
+}
+
+/// Points an `outer` assignment at `outer`, the depth of the scope enclosing the innermost loop
+pub fn build_outer<T>(mut instruction: Instruction<T>, outer: Option<usize>) -> Instruction<T> {
+    let Some(outer) = outer else {
+        panic!("`outer` assignments can only be used inside a loop");
+    };
+
+    match &mut instruction {
+        Instruction::CreateVar { scope, .. } | Instruction::AssignVar { scope, .. } => {
+            *scope = Some(outer)
+        }
+        _ => unreachable!(),
+    }
+
+    instruction
+}
+
 pub fn parse_variable_assignment<T>(variables: &mut VarNames, pair: Pair<Rule>) -> Instruction<T> {
-    let mut inner = pair.into_inner();
+    let mut inner = pair
+        .into_inner()
+        .skip_while(|value| value.as_rule() == Rule::outer_keyword);
     let ident = parse_ident(variables, inner.next().unwrap());
     let create = parse_variable_assign_op(inner.next().unwrap());
     let expr = parse_object_expr(variables, inner.next().unwrap());
@@ -886,8 +1654,45 @@ pub fn parse_object_expr(variables: &mut VarNames, pair: Pair<Rule>) -> ObjectEx
 
     let object = match inner.as_rule() {
         Rule::variable_clone => ObjectExpr::Clone(parse_variable_clone(variables, inner)),
+        Rule::variable_ref => ObjectExpr::Ref(parse_variable_ref(variables, inner)),
         Rule::list_expression => ObjectExpr::List(parse_list_expression(variables, inner)),
-        Rule::struct_expr => ObjectExpr::Struct(parse_struct_expression(variables, inner)),
+        Rule::list_comprehension => {
+            let mut inner = inner.into_inner();
+            let body = parse_object_expr(variables, inner.next().unwrap());
+            let iter = parse_ident(variables, inner.next().unwrap());
+            let target = parse_iterable(variables, inner.next().unwrap());
+
+            ObjectExpr::Comprehension {
+                body: Box::new(body),
+                iter,
+                target,
+            }
+        }
+        Rule::literal => {
+            let inner = inner.into_inner().next().unwrap();
+
+            match inner.as_rule() {
+                Rule::bool_literal => ObjectExpr::BoolLit(inner.as_str() == "true"),
+                // `007`, `-0` and integers too big for an i64 keep their text like before
+                Rule::int_literal => match inner.as_str().parse::<i64>() {
+                    Ok(value) if value.to_string() == inner.as_str() => ObjectExpr::IntLit(value),
+                    _ => ObjectExpr::StrLit(inner.as_str().to_string()),
+                },
+                x => unreachable!("{x:?}"),
+            }
+        }
+        Rule::condition_expr => {
+            let inner = inner.into_inner().next().unwrap();
+            ObjectExpr::Condition(parse_condition(variables, inner))
+        }
+        Rule::struct_expr => {
+            let value = parse_struct_expression(variables, inner);
+
+            match (value.properties.is_empty(), value.base.0.as_slice()) {
+                (true, [StringInstance::String(base)]) => ObjectExpr::StrLit(base.clone()),
+                _ => ObjectExpr::Struct(value),
+            }
+        }
         Rule::range => {
             let (min, max) = parse_range(variables, inner);
             ObjectExpr::Counter(min, max)
@@ -905,8 +1710,12 @@ pub fn parse_struct_expression(variables: &mut VarNames, pair: Pair<Rule>) -> St
     let mut properties = HashMap::new();
 
     for value in inner {
-        let (name, expr) = parse_property_assignment(variables, value);
-        properties.insert(name, expr);
+        let (name, property) = parse_property_assignment(variables, value);
+        assert!(
+            !property.optional,
+            "`?=` is only supported on the properties of `build(..)` objects"
+        );
+        properties.insert(name, property.value);
     }
 
     StructExpr { base, properties }
@@ -920,32 +1729,50 @@ pub fn parse_print<T>(variables: &mut VarNames, pair: Pair<Rule>) -> Instruction
     Instruction::Print(id)
 }
 
+pub fn parse_unset<T>(variables: &mut VarNames, pair: Pair<Rule>) -> Instruction<T> {
+    let mut inner = pair.into_inner();
+    inner.next(); // unset
+    let id = parse_ident(variables, inner.next().unwrap());
+
+    Instruction::UnsetVar(id)
+}
+
 pub fn parse_variable_clone(variables: &mut VarNames, pair: Pair<Rule>) -> VarFieldId {
     let mut inner = pair.into_inner();
     let base = inner.next().unwrap();
     parse_variable_access(variables, base)
 }
 
+pub fn parse_variable_ref(variables: &mut VarNames, pair: Pair<Rule>) -> VarFieldId {
+    let mut inner = pair.into_inner();
+    inner.next(); // ref_keyword
+    let base = inner.next().unwrap();
+    parse_variable_access(variables, base)
+}
+
 pub fn parse_property_assignment(
     variables: &mut VarNames,
     pair: Pair<Rule>,
-) -> (VarNameId, ObjectExpr) {
+) -> (VarNameId, PropertyExpr) {
     let mut inner = pair.into_inner();
 
     let ident = inner.next().unwrap();
     let ident = parse_ident(variables, ident);
 
-    let object = inner.next().unwrap();
-    let expr = parse_object_expr(variables, object);
+    let mut object = inner.next().unwrap();
+    let optional = object.as_rule() == Rule::property_optional;
+    if optional {
+        object = inner.next().unwrap();
+    }
+    let value = parse_object_expr(variables, object);
 
-    (ident, expr)
+    (ident, PropertyExpr { value, optional })
 }
 
 pub fn parse_ident(variables: &mut VarNames, pair: Pair<Rule>) -> VarNameId {
     let ident = pair.as_str();
-    let ident = variables.replace(ident);
 
-    ident
+    variables.replace(ident)
 }
 
 pub fn parse_string_builder(variables: &mut VarNames, pair: Pair<Rule>) -> StringExpr {
@@ -953,16 +1780,53 @@ pub fn parse_string_builder(variables: &mut VarNames, pair: Pair<Rule>) -> Strin
     let mut expr = StringExpr::default();
 
     for value in inner {
-        let instance = parse_string_instance(variables, value);
-        expr.0.push(instance);
+        let multiline = value
+            .clone()
+            .into_inner()
+            .next()
+            .filter(|inner| inner.as_rule() == Rule::string_multiline);
+
+        match multiline {
+            Some(multiline) => parse_multiline(variables, multiline, &mut expr),
+            None => expr.0.push(parse_string_instance(variables, value)),
+        }
     }
 
     expr
 }
 
+/// Splits a `"""` string into its text and the variables, env variables and times in it.
+/// Neighbouring text is joined into one `StringInstance::String`.
+fn parse_multiline(variables: &mut VarNames, pair: Pair<Rule>, expr: &mut StringExpr) {
+    for part in pair.into_inner() {
+        let instance = match part.as_rule() {
+            Rule::multiline_text => {
+                let text = part.as_str().replace("\\{", "{");
+
+                if let Some(StringInstance::String(last)) = expr.0.last_mut() {
+                    last.push_str(&text);
+                    continue;
+                }
+
+                StringInstance::String(text)
+            }
+            Rule::multiline_variable => {
+                let inner = part.into_inner().next().unwrap();
+                StringInstance::Variable(parse_variable_access(variables, inner))
+            }
+            // Same rules as a string builder, parsed the same way
+            _ => parse_string_instance_inner(variables, part),
+        };
+
+        expr.0.push(instance);
+    }
+}
+
 pub fn parse_string_instance(variables: &mut VarNames, pair: Pair<Rule>) -> StringInstance {
-    let inner = pair.into_inner().next().unwrap();
+    parse_string_instance_inner(variables, pair.into_inner().next().unwrap())
+}
 
+fn parse_string_instance_inner(variables: &mut VarNames, inner: Pair<Rule>) -> StringInstance {
     match inner.as_rule() {
         Rule::string_no_whitespace => StringInstance::String(inner.as_str().replace("\\\"", "\"")),
         Rule::string_whitespace => {
@@ -973,6 +1837,32 @@ pub fn parse_string_instance(variables: &mut VarNames, pair: Pair<Rule>) -> Stri
             let field_id = parse_variable_access(variables, inner);
             StringInstance::Variable(field_id)
         }
+        Rule::variable_default => {
+            let mut inner = inner.into_inner();
+            let field_id = parse_variable_access(variables, inner.next().unwrap());
+            let default = inner.next().unwrap().as_str().trim_end().to_string();
+            StringInstance::Default(field_id, default)
+        }
+        Rule::env_variable => {
+            let name = inner.into_inner().next().unwrap();
+            StringInstance::Env(name.as_str().to_string())
+        }
+        Rule::now_time => {
+            let (line, col) = inner.line_col();
+            let format = inner.into_inner().next().map(|format| format.as_str());
+
+            if let Some(format) = format {
+                let invalid = StrftimeItems::new(format).any(|item| item == Item::Error);
+                if invalid {
+                    panic!(
+                        "Invalid time format `{}`: [Line {}, Column {}]",
+                        format, line, col
+                    );
+                }
+            }
+
+            StringInstance::Now(format.map(str::to_string))
+        }
         _ => unreachable!(),
     }
 }
@@ -1011,10 +1901,50 @@ pub fn parse_variable_idx(variables: &mut VarNames, pair: Pair<Rule>) -> Variabl
             let idx = idx.as_str().parse().unwrap();
             VariableIdx::Integer(idx)
         }
+        Rule::string_whitespace => {
+            let inner = idx.into_inner().next().unwrap();
+            VariableIdx::String(inner.as_str().replace("\\\"", "\""))
+        }
         Rule::variable_access => {
             let access = parse_variable_access(variables, idx);
             VariableIdx::Variable(access)
         }
+        Rule::slice => {
+            let mut start = None;
+            let mut end = None;
+
+            for bound in idx.into_inner() {
+                let value = bound.as_str().trim().parse().unwrap();
+
+                match bound.as_rule() {
+                    Rule::slice_start => start = Some(value),
+                    Rule::slice_end => end = Some(value),
+                    _ => unreachable!(),
+                }
+            }
+
+            VariableIdx::Slice { start, end }
+        }
         _ => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_blocks_keep_source_order() {
+        let source =
+            "[commands.zeta]\nwait_all;\n\n[commands]\nwait_all;\n\n[commands.alpha]\nwait_all;\n";
+        let mut parsed = Parsed::new();
+        parse_into(source, None, &mut parsed, &mut |_, _| unreachable!()).unwrap();
+
+        let names: Vec<_> = parsed
+            .commands
+            .keys()
+            .map(|name| name.map(|id| parsed.names.evaluate(id).unwrap()))
+            .collect();
+        assert_eq!(names, [Some("zeta"), None, Some("alpha")]);
+    }
+}
@@ -1,9 +1,9 @@
 use crate::{
     bed::templates::TemplateCommand,
-    program::{Instruction, Program, InstructionId},
+    program::{Instruction, InstructionId, Program},
 };
 
-use super::TemplateExpr;
+use super::{build_outer, scope_depth, TemplateExpr};
 
 pub fn build_templates_program(
     exprs: impl Iterator<Item = TemplateExpr>,
@@ -11,19 +11,26 @@ pub fn build_templates_program(
     let mut instructions = vec![];
 
     for value in exprs {
-        build_expr(value, &mut instructions);
+        build_expr(value, &mut instructions, None);
     }
 
     Program(instructions)
 }
 
-pub fn build_expr(expr: TemplateExpr, instructions: &mut Vec<Instruction<TemplateCommand>>) {
+/// `outer` is the scope depth enclosing the innermost loop, see `CommandExpr::Outer`
+pub fn build_expr(
+    expr: TemplateExpr,
+    instructions: &mut Vec<Instruction<TemplateCommand>>,
+    outer: Option<usize>,
+) {
     match expr {
         TemplateExpr::Command(command) => instructions.push(command),
+        TemplateExpr::Outer(command) => instructions.push(build_outer(command, outer)),
         TemplateExpr::ForLoop { for_loop, exprs } => {
+            let outer = Some(scope_depth(instructions));
             for_loop.build(instructions, |instructions| {
                 for expr in exprs {
-                    build_expr(expr, instructions);
+                    build_expr(expr, instructions, outer);
                 }
             });
         }
@@ -41,15 +48,15 @@ pub fn build_expr(expr: TemplateExpr, instructions: &mut Vec<Instruction<Templat
             instructions.push(Instruction::PushScope);
 
             for expr in exprs {
-                build_expr(expr, instructions);
+                build_expr(expr, instructions, outer);
             }
 
             instructions.push(Instruction::PopScope);
             let jump_target = instructions.len();
 
-            for i in start..end {
-                let Instruction::ConditionalJump { jump, .. } = &mut instructions[i] else { 
-                    unreachable!() 
+            for instruction in instructions[start..end].iter_mut() {
+                let Instruction::ConditionalJump { jump, .. } = instruction else {
+                    unreachable!()
                 };
 
                 jump.0 = jump_target;
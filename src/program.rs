@@ -1,8 +1,9 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     fmt::Debug,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, Range},
     sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant},
 };
 
 use indexmap::IndexSet;
@@ -11,24 +12,7 @@ use serde::{
     Serialize,
 };
 
-use crate::bed::expr::{IterTargetExpr, ObjectExpr};
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct StackId(pub usize);
-
-impl Deref for StackId {
-    type Target = usize;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl DerefMut for StackId {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
+use crate::bed::expr::{Condition, IterTargetExpr, ObjectExpr};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct InstructionId(pub usize);
@@ -47,7 +31,7 @@ impl DerefMut for InstructionId {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Shutdown(Arc<AtomicBool>);
 
 impl Shutdown {
@@ -151,6 +135,8 @@ pub enum Object {
     Ref(VariableRef),
     Struct(Struct),
     List(Vec<Object>),
+    /// Shown and interpolated as `true` or `false`, serialized as a boolean
+    Bool(bool),
 }
 
 pub struct DisplayObject<'a> {
@@ -168,35 +154,39 @@ impl<'a> std::fmt::Display for DisplayObject<'a> {
                     "Counter({}..{}): {}",
                     counter.start,
                     counter.end,
-                    counter.idx()
+                    counter.current_value()
                 )
             }
             Object::Ref(variable_ref) => {
-                let name = self.names.evaluate(variable_ref.target).unwrap();
-                let object = &self.program.scopes[variable_ref.scope]
-                    .0
-                    .get(&variable_ref.target)
-                    .unwrap();
+                let name = self.names.evaluate(variable_ref.target).unwrap_or("?");
+                let object = self
+                    .program
+                    .scopes
+                    .get(variable_ref.scope)
+                    .and_then(|scope| scope.0.get(&variable_ref.target));
 
                 match object {
-                    Object::List(vec) => {
+                    Some(Object::List(vec)) => {
                         write!(f, "&{}[{}]: ", name, variable_ref.offset)?;
-                        write!(
-                            f,
-                            "{}",
-                            vec[variable_ref.offset].to_display(self.program, self.names)
-                        )
+                        match vec.get(variable_ref.offset) {
+                            Some(value) => {
+                                write!(f, "{}", value.to_display(self.program, self.names))
+                            }
+                            None => write!(f, "<dangling>"),
+                        }
                     }
-                    _ => {
+                    Some(object) => {
                         write!(f, "&{}: ", name)?;
                         write!(f, "{}", object.to_display(self.program, self.names))
                     }
+                    None => write!(f, "&{}: <dangling>", name),
                 }
             }
             Object::Struct(value) => {
                 let to_display = value.to_display(self.program, self.names);
                 write!(f, "{to_display}")
             }
+            Object::Bool(value) => write!(f, "{value}"),
             Object::List(vec) => {
                 write!(f, "[")?;
                 let mut iter = vec.iter();
@@ -223,6 +213,53 @@ impl Object {
         })
     }
 
+    /// Strings, numbers and `null` become structs with no properties, `null` with an empty
+    /// base. In a JSON object a string `"base"` sets the base and every other key is a property.
+    pub fn from_json(value: &serde_json::Value, names: &mut VarNames) -> Self {
+        match value {
+            serde_json::Value::Null => Object::new(String::new()),
+            serde_json::Value::Bool(value) => Object::Bool(*value),
+            serde_json::Value::Number(value) => Object::new(value.to_string()),
+            serde_json::Value::String(value) => Object::new(value.clone()),
+            serde_json::Value::Array(values) => Object::List(
+                values
+                    .iter()
+                    .map(|value| Object::from_json(value, names))
+                    .collect(),
+            ),
+            serde_json::Value::Object(map) => {
+                let mut base = String::new();
+                let mut properties = HashMap::new();
+
+                for (key, value) in map.iter() {
+                    match (key.as_str(), value) {
+                        ("base", serde_json::Value::String(value)) => base = value.clone(),
+                        (key, value) => {
+                            let id = names.replace(key);
+                            properties.insert(id, Object::from_json(value, names));
+                        }
+                    }
+                }
+
+                Object::Struct(Struct::new(base, properties))
+            }
+        }
+    }
+
+    /// True if it holds a ref into a scope deeper than `scope`, storing it in `scope` would
+    /// leave the ref dangling once that scope is popped
+    pub fn refs_above(&self, scope: usize) -> bool {
+        match self {
+            Object::Ref(variable_ref) => variable_ref.scope > scope,
+            Object::List(values) => values.iter().any(|value| value.refs_above(scope)),
+            Object::Struct(value) => value
+                .properties
+                .values()
+                .any(|value| value.refs_above(scope)),
+            Object::Counter(_) | Object::Bool(_) => false,
+        }
+    }
+
     pub fn to_display<'a>(
         &'a self,
         state: &'a ProgramState,
@@ -245,12 +282,12 @@ impl Object {
                 write!(into, "{}", &value.base).unwrap();
             }
             Object::Ref(variable_ref) => state
-                .evaluate_ref(*variable_ref)
-                .unwrap()
+                .evaluate_ref(*variable_ref)?
                 .write_to_string(state, into)?,
             Object::Counter(counter) => {
-                write!(into, "{}", counter.idx()).unwrap();
+                write!(into, "{}", counter.current_value()).unwrap();
             }
+            Object::Bool(value) => write!(into, "{value}").unwrap(),
             Object::List(_) => return Err(VariableAccessError::NotAStruct(self.clone())),
         }
 
@@ -263,7 +300,7 @@ impl Object {
         names: &'a VarNames,
     ) -> ObjectSerialize<'a> {
         ObjectSerialize {
-            object: &self,
+            object: self,
             // base: &self.base,
             // properties: &self.properties,
             program,
@@ -286,17 +323,18 @@ impl<'a> Serialize for ObjectSerialize<'a> {
         S: serde::Serializer,
     {
         match self.object {
-            Object::Counter(counter) => return serializer.serialize_i64(counter.idx()),
+            Object::Counter(counter) => serializer.serialize_i64(counter.current_value()),
+            Object::Bool(value) => serializer.serialize_bool(*value),
             Object::Ref(variable_ref) => {
-                let Some(object) = self.program.evaluate_ref(*variable_ref) else {
+                let Ok(object) = self.program.evaluate_ref(*variable_ref) else {
                     return Err(serde::ser::Error::custom(
                         "Failed to evaluate variable reference",
                     ));
                 };
 
-                return object
-                    .to_serialize(&self.program, &self.names)
-                    .serialize(serializer);
+                object
+                    .to_serialize(self.program, self.names)
+                    .serialize(serializer)
             }
             Object::Struct(value) => {
                 if value.properties.is_empty() {
@@ -314,15 +352,15 @@ impl<'a> Serialize for ObjectSerialize<'a> {
                     },
                 )?;
 
-                return map_serialize.end();
+                map_serialize.end()
             }
             Object::List(vec) => {
                 let mut seq_serialize = serializer.serialize_seq(Some(vec.len()))?;
                 for value in vec.iter() {
                     seq_serialize
-                        .serialize_element(&value.to_serialize(&self.program, &self.names))?;
+                        .serialize_element(&value.to_serialize(self.program, self.names))?;
                 }
-                return seq_serialize.end();
+                seq_serialize.end()
             }
         }
     }
@@ -368,8 +406,18 @@ pub struct Counter {
 }
 
 impl Counter {
-    pub fn idx(&self) -> i64 {
-        self.start + self.offset as i64
+    /// The value at the current offset, used wherever a counter is shown, interpolated or
+    /// passed as an argument so they always agree
+    pub fn current_value(&self) -> i64 {
+        self.value_at(self.offset)
+    }
+
+    pub fn value_at(&self, offset: usize) -> i64 {
+        self.start + offset as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     pub fn len(&self) -> usize {
@@ -388,7 +436,31 @@ pub struct Scope(pub HashMap<VarNameId, Object>);
 #[derive(Clone, Debug)]
 pub enum VariableIdx {
     Integer(usize),
+    /// Matched against the base of each struct in the list
+    String(String),
     Variable(VarFieldId),
+    /// Written as `list[start..end]`, only valid as the last access of an argument. Negative
+    /// bounds count from the end and missing ones default to the start or end of the list.
+    Slice {
+        start: Option<i64>,
+        end: Option<i64>,
+    },
+}
+
+impl VariableIdx {
+    /// Range of a list of `len` elements selected by slice bounds, out of range bounds are
+    /// clamped and a start past the end gives an empty range
+    pub fn slice_range(start: Option<i64>, end: Option<i64>, len: usize) -> Range<usize> {
+        let resolve = |bound: i64| match bound < 0 {
+            true => len.saturating_sub(bound.unsigned_abs() as usize),
+            false => (bound as usize).min(len),
+        };
+
+        let start = start.map_or(0, resolve);
+        let end = end.map_or(len, resolve);
+
+        start..end.max(start)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -414,7 +486,7 @@ impl VarFieldId {
     ) -> Result<&'a Object, VariableAccessError> {
         let properties = match object {
             Object::Struct(value) => &value.properties,
-            Object::Ref(value) => match program.evaluate_ref(*value).unwrap() {
+            Object::Ref(value) => match program.evaluate_ref(*value)? {
                 Object::Struct(value) => &value.properties,
                 x => {
                     return Err(VariableAccessError::NotAStruct(x.clone()));
@@ -446,6 +518,7 @@ impl VarFieldId {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub enum VariableAccessError {
     NotAStruct(Object),
@@ -454,6 +527,36 @@ pub enum VariableAccessError {
     InvalidIdx,
     MissingVariable(VarNameId),
     MissingField(VarNameId),
+    MissingEnvVariable(String),
+    MissingFile(String),
+    SerializeError(String),
+    /// Only a variable or an element of a list variable can be referenced
+    InvalidRef,
+    /// The path given to `wait_file` didn't appear before its timeout
+    WaitFileTimeout(String),
+    /// A `copy` failed, holds the paths and the error
+    CopyFailed(String),
+    /// A `symlink` failed, holds the paths and the error
+    SymlinkFailed(String),
+    /// An `assert` condition didn't hold, holds its message
+    AssertionFailed(String),
+    /// The directory of a `cwd_each` block could not be created
+    CreateDirFailed(String),
+    /// The regex of a `progress(..)` failed to compile, holds it and the error
+    InvalidRegex(String),
+    /// The command of a `spawn` or `run` evaluated to an empty or blank string
+    EmptyCommand,
+    /// A `list[start..end]` slice was used where a single object is expected
+    UnexpectedSlice,
+    /// The product of a sampled loop's iterables has more than `u64::MAX` combinations
+    SampleSpaceTooLarge,
+    /// A ref whose target variable or list element no longer exists
+    DanglingRef(VarNameId),
+    /// The instruction limit was hit, `start..=end` is the last backwards jump taken
+    InstructionLimit {
+        start: usize,
+        end: usize,
+    },
 }
 
 impl std::fmt::Display for VariableAccessError {
@@ -470,20 +573,22 @@ pub enum ListIdx<'a> {
 
 impl<'a> ListIdx<'a> {
     pub fn get_object<'b>(&self, list: &'b [Object]) -> Result<&'b Object, VariableAccessError> {
+        Ok(&list[self.position(list)?])
+    }
+
+    pub fn position(&self, list: &[Object]) -> Result<usize, VariableAccessError> {
         match self {
-            ListIdx::Integer(idx) => match list.get(*idx) {
-                Some(object) => Ok(object),
-                None => Err(VariableAccessError::InvalidIdx),
-            },
+            ListIdx::Integer(idx) if *idx < list.len() => Ok(*idx),
+            ListIdx::Integer(_) => Err(VariableAccessError::InvalidIdx),
             ListIdx::String(str) => {
-                for value in list.iter() {
+                for (i, value) in list.iter().enumerate() {
                     let base = match value {
                         Object::Struct(value) => &value.base,
                         _ => continue,
                     };
 
                     if base == str {
-                        return Ok(value);
+                        return Ok(i);
                     }
                 }
 
@@ -493,16 +598,104 @@ impl<'a> ListIdx<'a> {
     }
 }
 
+/// A struct base is only used as an index if it is written the way the number would be printed,
+/// so `7` and `0` are indexes while `007`, `+7` and `7a` look up a struct with that base. Counters
+/// and other typed values never go through this.
+fn canonical_idx(base: &str) -> Option<usize> {
+    if base.starts_with('0') && base.len() > 1 {
+        return None;
+    }
+    if !base.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    base.parse().ok()
+}
+
+/// Default number of instructions a single `Program::run` may execute before
+/// it is assumed to be stuck in a malformed loop.
+pub const DEFAULT_INSTRUCTION_LIMIT: u64 = 1_000_000_000;
+
+/// SplitMix64, small and good enough to pick reproducible samples from a seed
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut value = self.0;
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        value ^ (value >> 31)
+    }
+
+    /// Uniform value in `0..bound`
+    pub fn below(&mut self, bound: u64) -> u64 {
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+
+    /// `count` distinct values from `0..len` in ascending order, every value if `count >= len`
+    pub fn sample(&mut self, len: u64, count: u64) -> Vec<u64> {
+        if count >= len {
+            return (0..len).collect();
+        }
+
+        // Floyd's algorithm, one draw per sampled value however large `len` is
+        let mut chosen = BTreeSet::new();
+
+        for bound in len - count..len {
+            let value = self.below(bound + 1);
+            if !chosen.insert(value) {
+                chosen.insert(bound);
+            }
+        }
+
+        chosen.into_iter().collect()
+    }
+}
+
+/// Pushed by `StartDeadline`, once `at` passes the rest of the block is skipped by jumping to
+/// `end` with the scopes truncated back to `depth`
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+    pub at: Instant,
+    pub end: InstructionId,
+    pub depth: usize,
+}
+
+#[derive(Clone)]
 pub struct ProgramState {
     pub scopes: Vec<Scope>,
+    /// Maximum number of instructions executed per `Program::run`, `None` disables the check
+    pub instruction_limit: Option<u64>,
+    /// Active `timeout` blocks, innermost last
+    pub deadlines: Vec<Deadline>,
+    /// Draws the combinations of `for sample` loops
+    pub rng: Rng,
+    /// Global lists that `yield` has pushed into, in the order of their first yield
+    pub yielded: Vec<VarNameId>,
 
     scope_cache: Vec<Scope>,
 }
 
+impl Default for ProgramState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ProgramState {
     pub fn new() -> Self {
         Self {
             scopes: vec![],
+            instruction_limit: Some(DEFAULT_INSTRUCTION_LIMIT),
+            deadlines: vec![],
+            rng: Rng::new(0),
+            yielded: vec![],
             scope_cache: vec![],
         }
     }
@@ -512,30 +705,36 @@ impl ProgramState {
         self.scopes.push(scope);
     }
 
-    pub fn evaluate_ref(&self, value: VariableRef) -> Option<&Object> {
-        let scope = &self.scopes[value.scope];
-        let mut variable = scope.0.get(&value.target)?;
-
-        if let Object::List(list) = variable {
-            variable = list.get(value.offset)?;
-        }
+    /// The earliest deadline of the active `timeout` blocks
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadlines.iter().map(|deadline| deadline.at).min()
+    }
 
-        while let Object::Ref(value) = variable {
-            let scope = &self.scopes[value.scope];
-            variable = scope.0.get(&value.target)?;
+    /// Follows `value` and every ref it leads to, fails if a target no longer exists
+    pub fn evaluate_ref(&self, mut value: VariableRef) -> Result<&Object, VariableAccessError> {
+        loop {
+            let dangling = VariableAccessError::DanglingRef(value.target);
+            let mut variable = self
+                .scopes
+                .get(value.scope)
+                .and_then(|scope| scope.0.get(&value.target))
+                .ok_or(dangling.clone())?;
 
             if let Object::List(list) = variable {
-                variable = list.get(value.offset)?;
+                variable = list.get(value.offset).ok_or(dangling)?;
             }
-        }
 
-        Some(variable)
+            match variable {
+                Object::Ref(next) => value = *next,
+                variable => return Ok(variable),
+            }
+        }
     }
 
     pub fn object_to_idx<'a>(&'a self, object: &'a Object) -> Option<ListIdx<'a>> {
         match object {
             Object::Counter(counter) => {
-                let idx = counter.idx();
+                let idx = counter.current_value();
 
                 if idx < 0 {
                     return None;
@@ -544,27 +743,27 @@ impl ProgramState {
                 Some(ListIdx::Integer(idx as usize))
             }
             Object::Ref(variable_ref) => {
-                let Some(object) = self.evaluate_ref(*variable_ref) else {
-                    return None;
-                };
+                let object = self.evaluate_ref(*variable_ref).ok()?;
 
                 self.object_to_idx(object)
             }
-            Object::Struct(value) => match value.base.parse() {
-                Ok(idx) => Some(ListIdx::Integer(idx)),
-                Err(_) => Some(ListIdx::String(&value.base)),
+            Object::Struct(value) => match canonical_idx(&value.base) {
+                Some(idx) => Some(ListIdx::Integer(idx)),
+                None => Some(ListIdx::String(&value.base)),
             },
-            Object::List(_) => None,
+            Object::List(_) | Object::Bool(_) => None,
         }
     }
 
     pub fn evaluate_idx<'a>(
         &'a self,
-        idx: &VariableIdx,
+        idx: &'a VariableIdx,
     ) -> Result<ListIdx<'a>, VariableAccessError> {
         let id = match idx {
             VariableIdx::Integer(idx) => return Ok(ListIdx::Integer(*idx)),
+            VariableIdx::String(value) => return Ok(ListIdx::String(value)),
             VariableIdx::Variable(id) => id,
+            VariableIdx::Slice { .. } => return Err(VariableAccessError::UnexpectedSlice),
         };
 
         let object = self.get_object(id)?;
@@ -596,6 +795,67 @@ impl ProgramState {
         Ok(object)
     }
 
+    /// Elements selected when the last access of `id` is a `list[start..end]` slice, `None` if
+    /// it isn't a slice
+    pub fn get_slice<'a>(
+        &'a self,
+        id: &VarFieldId,
+    ) -> Result<Option<&'a [Object]>, VariableAccessError> {
+        let mut last = id;
+        while let Some(field) = &last.field {
+            last = field;
+        }
+
+        let Some(VariableIdx::Slice { start, end }) = last.idx.as_deref() else {
+            return Ok(None);
+        };
+
+        // The same access without the slice resolves to the list itself
+        let mut target = id.clone();
+        let mut access = &mut target;
+        while let Some(field) = access.field.as_deref_mut() {
+            access = field;
+        }
+        access.idx = None;
+
+        let list = match self.get_object(&target)? {
+            Object::Ref(variable_ref) => self.evaluate_ref(*variable_ref)?,
+            object => object,
+        };
+        let Object::List(list) = list else {
+            return Err(VariableAccessError::NotAList);
+        };
+
+        Ok(Some(
+            &list[VariableIdx::slice_range(*start, *end, list.len())],
+        ))
+    }
+
+    /// Resolves `id` to a `VariableRef` instead of cloning the object it points at
+    pub fn get_ref(&self, id: &VarFieldId) -> Result<VariableRef, VariableAccessError> {
+        let Some((scope, object)) = self.get_value(id.var) else {
+            return Err(VariableAccessError::MissingVariable(id.var));
+        };
+
+        if id.field.is_some() {
+            return Err(VariableAccessError::InvalidRef);
+        }
+
+        let offset = match (&id.idx, object) {
+            (Some(idx), Object::List(list)) => self.evaluate_idx(idx)?.position(list)?,
+            (Some(_), _) => return Err(VariableAccessError::NotAList),
+            // `evaluate_ref` would read this back as the first element
+            (None, Object::List(_)) => return Err(VariableAccessError::InvalidRef),
+            (None, _) => 0,
+        };
+
+        Ok(VariableRef {
+            scope,
+            target: id.var,
+            offset,
+        })
+    }
+
     pub fn pop_scope(&mut self) {
         let mut scope = match self.scopes.pop() {
             Some(scope) => scope,
@@ -606,6 +866,13 @@ impl ProgramState {
         self.scope_cache.push(scope);
     }
 
+    /// Pops scopes until only `depth` remain, used to unwind after a program errors
+    pub fn truncate_scopes(&mut self, depth: usize) {
+        while self.scopes.len() > depth {
+            self.pop_scope();
+        }
+    }
+
     pub fn insert_var(&mut self, id: VarNameId, var: Object, scope: Option<usize>) -> &mut Object {
         if self.scopes.is_empty() {
             self.new_scope();
@@ -671,6 +938,17 @@ impl ProgramState {
 
         None
     }
+
+    /// Removes the variable from the nearest scope holding it, returning its value
+    pub fn remove_value(&mut self, variable: VarNameId) -> Option<Object> {
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(value) = scope.0.remove(&variable) {
+                return Some(value);
+            }
+        }
+
+        None
+    }
 }
 
 pub trait Executable<Command> {
@@ -692,6 +970,18 @@ pub trait Executable<Command> {
     }
 
     fn print(&self, program: &ProgramState, object: &Object);
+
+    /// Called when a `timeout` block starts
+    fn enter_deadline(&mut self) {}
+
+    /// Called when a `timeout` block finishes before its deadline
+    fn leave_deadline(&mut self) {}
+
+    /// Called when the innermost `count` `timeout` blocks expired and the rest of them is
+    /// skipped, so anything entered inside them can be closed
+    fn expire_deadlines(&mut self, count: usize) {
+        let _count = count;
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -709,16 +999,20 @@ pub enum Instruction<T> {
         target: VarNameId,
         object: ObjectExpr,
     },
+    /// `scope` is relative to the scope the program starts in, `None` is the current scope
     CreateVar {
         target: VarNameId,
         scope: Option<usize>,
         value: ObjectExpr,
     },
+    /// With a `scope` the nearest variable in that scope or below it is assigned
     AssignVar {
         target: VarNameId,
         scope: Option<usize>,
         value: ObjectExpr,
     },
+    /// Removes the variable from the nearest scope holding it, does nothing if there is none
+    UnsetVar(VarNameId),
     StartIter {
         /// Id of the variable to iterate over
         target: IterTargetExpr,
@@ -731,11 +1025,25 @@ pub enum Instruction<T> {
         iter: VarNameId,
         jump: InstructionId,
     },
+    /// Jumps if `cond` doesn't hold
     ConditionalJump {
-        cond: VarFieldId,
+        cond: Condition,
         jump: InstructionId,
     },
     Goto(InstructionId),
+    /// Draws `count` combinations of `targets` and stores the elements of each in the
+    /// matching hidden list of `lists`, a group loop over the lists then walks the sample
+    Sample {
+        targets: Vec<IterTargetExpr>,
+        count: u64,
+        lists: Vec<VarNameId>,
+    },
+    /// Starts a `timeout` block, `end` is the instruction after its `EndDeadline`
+    StartDeadline {
+        millis: u64,
+        end: InstructionId,
+    },
+    EndDeadline,
     Command(T),
 }
 
@@ -753,6 +1061,13 @@ impl<T: Debug> std::fmt::Display for Program<T> {
 }
 
 impl<Command> Program<Command> {
+    /// Whether running it draws from `ProgramState::rng`
+    pub fn is_random(&self) -> bool {
+        self.0
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::Sample { .. }))
+    }
+
     pub fn run(
         &self,
         executable: &mut impl Executable<Command>,
@@ -760,6 +1075,13 @@ impl<Command> Program<Command> {
         shutdown: &Shutdown,
     ) -> Result<(), (usize, VariableAccessError)> {
         let mut counter = 0;
+        let mut executed = 0;
+        let mut previous = 0;
+        let mut cycle = (0, 0);
+        state.deadlines.clear();
+        // Scope levels of `CreateVar` and `AssignVar` are relative to the scope the program
+        // starts in
+        let base = state.scopes.len().saturating_sub(1);
 
         while counter < self.0.len() {
             if shutdown.is_shutdown() {
@@ -767,6 +1089,33 @@ impl<Command> Program<Command> {
                 return Ok(());
             }
 
+            // The outermost expired block is skipped along with every block inside it
+            let now = Instant::now();
+            if let Some(idx) = state.deadlines.iter().position(|value| value.at <= now) {
+                let deadline = state.deadlines[idx];
+                executable.expire_deadlines(state.deadlines.len() - idx);
+                state.deadlines.truncate(idx);
+                state.truncate_scopes(deadline.depth);
+                counter = *deadline.end;
+                continue;
+            }
+
+            if counter < previous {
+                cycle = (counter, previous);
+            }
+            previous = counter;
+            executed += 1;
+
+            if let Some(limit) = state.instruction_limit {
+                if executed > limit {
+                    let (start, end) = cycle;
+                    return Err((
+                        counter,
+                        VariableAccessError::InstructionLimit { start, end },
+                    ));
+                }
+            }
+
             let instruction = &self.0[counter];
 
             match instruction {
@@ -778,11 +1127,25 @@ impl<Command> Program<Command> {
                 }
                 Instruction::Print(variable) => {
                     let variable = state.get_object(variable).map_err(|e| (counter, e))?;
+                    if let Object::Ref(variable_ref) = variable {
+                        state
+                            .evaluate_ref(*variable_ref)
+                            .map_err(|e| (counter, e))?;
+                    }
                     executable.print(state, variable);
                 }
+                Instruction::UnsetVar(target) => {
+                    state.remove_value(*target);
+                }
                 Instruction::PushList { target, object } => {
                     let object = object.evaluate(state).map_err(|e| (counter, e))?;
 
+                    if let Some((scope, _)) = state.get_value(*target) {
+                        if object.refs_above(scope) {
+                            return Err((counter, VariableAccessError::InvalidRef));
+                        }
+                    }
+
                     match state.get_value_mut(*target) {
                         Some(Object::List(list)) => {
                             list.push(object);
@@ -801,7 +1164,10 @@ impl<Command> Program<Command> {
                     let eval = value.evaluate(state).map_err(|e| (counter, e))?;
                     match scope {
                         Some(scope) => {
-                            if let Some(scope) = state.scopes.get_mut(*scope) {
+                            if eval.refs_above(base + *scope) {
+                                return Err((counter, VariableAccessError::InvalidRef));
+                            }
+                            if let Some(scope) = state.scopes.get_mut(base + *scope) {
                                 scope.0.insert(*target, eval);
                             }
                         }
@@ -816,26 +1182,46 @@ impl<Command> Program<Command> {
                     value,
                 } => {
                     let eval = value.evaluate(state).map_err(|e| (counter, e))?;
-                    match scope {
-                        Some(scope) => {
-                            if let Some(scope) = state.scopes.get_mut(*scope) {
-                                if let Some(variable) = scope.0.get_mut(target) {
-                                    *variable = eval;
-                                }
+                    // Shadows inside the scope are skipped, the variable must exist
+                    let found = match scope {
+                        Some(scope) => state.scopes[..=base + *scope]
+                            .iter()
+                            .rposition(|scope| scope.0.contains_key(target)),
+                        None => state.get_value(*target).map(|(idx, _)| idx),
+                    };
+
+                    match (found, scope) {
+                        (Some(idx), _) => {
+                            if eval.refs_above(idx) {
+                                return Err((counter, VariableAccessError::InvalidRef));
                             }
+                            state.scopes[idx].0.insert(*target, eval);
                         }
-                        None => {
-                            if let Some(variable) = state.get_value_mut(*target) {
-                                *variable = eval;
-                            }
+                        (None, Some(_)) => {
+                            return Err((counter, VariableAccessError::MissingVariable(*target)))
                         }
+                        (None, None) => {}
                     }
                 }
                 Instruction::StartIter {
-                    target: IterTargetExpr::Variable(target),
+                    target:
+                        target_expr @ (IterTargetExpr::Variable(_)
+                        | IterTargetExpr::Chain { .. }
+                        | IterTargetExpr::Inline { .. }),
                     iter,
                     jump,
                 } => {
+                    let target = &match target_expr {
+                        IterTargetExpr::Chain { temp, .. }
+                        | IterTargetExpr::Inline { temp, .. } => {
+                            let list = target_expr.materialize(state).map_err(|e| (counter, e))?;
+                            state.insert_var(*temp, list, None);
+                            *temp
+                        }
+                        IterTargetExpr::Variable(target) => *target,
+                        IterTargetExpr::Range { .. } => unreachable!(),
+                    };
+
                     let (scope, object) = state
                         .get_value(*target)
                         .ok_or((counter, VariableAccessError::MissingVariable(*target)))?;
@@ -930,7 +1316,7 @@ impl<Command> Program<Command> {
                     };
 
                     range_counter.offset += 1;
-                    let idx = range_counter.start + range_counter.offset as i64;
+                    let idx = range_counter.current_value();
                     let end = range_counter.end;
                     let offset = range_counter.offset;
                     executable.set_iter(*iter, offset, iter_var);
@@ -941,29 +1327,59 @@ impl<Command> Program<Command> {
                     }
                 }
                 Instruction::ConditionalJump { cond, jump } => {
-                    let object = state.get_object(cond).map_err(|e| (counter, e))?;
-
-                    let value = match object {
-                        Object::Struct(object) => object,
-                        Object::Ref(variable_ref) => {
-                            match state.evaluate_ref(*variable_ref).unwrap() {
-                                Object::Struct(object) => object,
-                                x => {
-                                    return Err((
-                                        counter,
-                                        VariableAccessError::NotAStruct(x.clone()),
-                                    ))
-                                }
-                            }
-                        }
-                        x => return Err((counter, VariableAccessError::NotAStruct(x.clone()))),
-                    };
-
-                    if value.base != "false" {
+                    if !cond.evaluate(state).map_err(|e| (counter, e))? {
                         counter = **jump;
                         continue;
                     }
                 }
+                Instruction::Sample {
+                    targets,
+                    count,
+                    lists,
+                } => {
+                    let mut materialized = Vec::with_capacity(targets.len());
+                    let mut len: u64 = 1;
+
+                    for target in targets {
+                        let Object::List(list) =
+                            target.materialize(state).map_err(|e| (counter, e))?
+                        else {
+                            unreachable!()
+                        };
+                        len = len
+                            .checked_mul(list.len() as u64)
+                            .ok_or((counter, VariableAccessError::SampleSpaceTooLarge))?;
+                        materialized.push(list);
+                    }
+
+                    let mut sampled = vec![vec![]; targets.len()];
+
+                    // Combinations are numbered in the order a full loop visits them, the last
+                    // iterable changing fastest
+                    for mut idx in state.rng.sample(len, *count) {
+                        for (list, values) in materialized.iter().zip(sampled.iter_mut()).rev() {
+                            let len = list.len() as u64;
+                            values.push(list[(idx % len) as usize].clone());
+                            idx /= len;
+                        }
+                    }
+
+                    for (list, values) in lists.iter().zip(sampled) {
+                        state.insert_var(*list, Object::List(values), None);
+                    }
+                }
+                Instruction::StartDeadline { millis, end } => {
+                    state.deadlines.push(Deadline {
+                        at: now + Duration::from_millis(*millis),
+                        end: *end,
+                        depth: state.scopes.len(),
+                    });
+                    executable.enter_deadline();
+                }
+                Instruction::EndDeadline => {
+                    state.deadlines.pop();
+                    executable.leave_deadline();
+                }
                 Instruction::Goto(target) => {
                     counter = **target;
                     continue;
@@ -982,3 +1398,98 @@ impl<Command> Program<Command> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs programs without commands
+    struct Noop;
+
+    impl Executable<()> for Noop {
+        fn shutdown(&mut self) {}
+
+        fn finish(&mut self, _state: &mut ProgramState, _shutdown: &Shutdown) {}
+
+        fn execute(
+            &mut self,
+            _command: &(),
+            _state: &mut ProgramState,
+            _shutdown: &Shutdown,
+        ) -> Result<(), VariableAccessError> {
+            Ok(())
+        }
+
+        fn print(&self, _program: &ProgramState, _object: &Object) {}
+    }
+
+    #[test]
+    fn runaway_jump_hits_instruction_limit() {
+        let program: Program<()> = Program(vec![
+            Instruction::PushScope,
+            Instruction::PopScope,
+            Instruction::Goto(InstructionId(0)),
+        ]);
+        let mut state = ProgramState::new();
+        state.instruction_limit = Some(100);
+
+        let result = program.run(&mut Noop, &mut state, &Shutdown::new());
+        assert!(matches!(
+            result,
+            Err((
+                _,
+                VariableAccessError::InstructionLimit { start: 0, end: 2 }
+            ))
+        ));
+    }
+
+    #[test]
+    fn ref_cannot_outlive_its_target() {
+        let (w, r) = (VarNameId(0), VarNameId(1));
+        let program: Program<()> = Program(vec![
+            Instruction::CreateVar {
+                target: r,
+                scope: None,
+                value: ObjectExpr::StrLit("0".to_string()),
+            },
+            Instruction::PushScope,
+            Instruction::CreateVar {
+                target: w,
+                scope: None,
+                value: ObjectExpr::StrLit("5".to_string()),
+            },
+            Instruction::AssignVar {
+                target: r,
+                scope: None,
+                value: ObjectExpr::Ref(VarFieldId::new(w)),
+            },
+        ]);
+        let mut state = ProgramState::new();
+        state.new_scope();
+
+        let result = program.run(&mut Noop, &mut state, &Shutdown::new());
+        assert!(matches!(result, Err((3, VariableAccessError::InvalidRef))));
+    }
+
+    #[test]
+    fn dangling_ref_is_an_error() {
+        let mut names = VarNames::default();
+        let (x, r) = (names.replace("x"), names.replace("r"));
+        let mut state = ProgramState::new();
+        state.new_scope();
+        state.insert_var(x, Object::new("hello".to_string()), None);
+        let target = state.get_ref(&VarFieldId::new(x)).unwrap();
+        state.insert_var(r, Object::Ref(target), None);
+        state.remove_value(x);
+
+        assert!(matches!(
+            state.evaluate_ref(target),
+            Err(VariableAccessError::DanglingRef(id)) if id == x
+        ));
+        let (_, object) = state.get_value(r).unwrap();
+        assert_eq!(
+            object.to_display(&state, &names).to_string(),
+            "&x: <dangling>"
+        );
+    }
+}